@@ -0,0 +1,582 @@
+//! Adapters for the asynchronous I/O traits of `futures-io`.
+//!
+//! The [`bufread`] types read compressed or decompressed bytes out of an
+//! underlying [`AsyncBufRead`], and the [`write`](mod@write) types write
+//! them to an underlying [`AsyncWrite`]. Each poll only processes the data
+//! that is ready, and keeps the state of the codec when the underlying I/O is
+//! pending.
+//!
+//! The underlying readers and writers must be [`Unpin`], which can be
+//! achieved by pinning them in a [`Box`].
+
+use crate::async_io::{Codec, ReadState, WriteState};
+use futures_io::{AsyncBufRead, AsyncRead, AsyncWrite};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+fn poll_fill_buf<'a, R, C>(
+    inner: &mut R,
+    state: &'a mut ReadState<C>,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<&'a [u8]>>
+where
+    R: AsyncBufRead + Unpin,
+    C: Codec,
+{
+    state.check()?;
+    while state.needs_input() {
+        let input = ready!(Pin::new(&mut *inner).poll_fill_buf(cx))?;
+        let n = state.process(input)?;
+        Pin::new(&mut *inner).consume(n);
+    }
+    Poll::Ready(Ok(state.buffered()))
+}
+
+fn poll_read<R, C>(
+    inner: &mut R,
+    state: &mut ReadState<C>,
+    cx: &mut Context<'_>,
+    buf: &mut [u8],
+) -> Poll<io::Result<usize>>
+where
+    R: AsyncBufRead + Unpin,
+    C: Codec,
+{
+    let data = ready!(poll_fill_buf(inner, state, cx))?;
+    let n = std::cmp::min(data.len(), buf.len());
+    buf[..n].copy_from_slice(&data[..n]);
+    state.consume(n);
+    Poll::Ready(Ok(n))
+}
+
+fn poll_write_pending<W, C>(
+    inner: &mut W,
+    state: &mut WriteState<C>,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<()>>
+where
+    W: AsyncWrite + Unpin,
+    C: Codec,
+{
+    while !state.pending().is_empty() {
+        let n = ready!(Pin::new(&mut *inner).poll_write(cx, state.pending()))?;
+        if n == 0 {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "failed to write the buffered data",
+            )));
+        }
+        state.advance(n);
+    }
+    Poll::Ready(Ok(()))
+}
+
+macro_rules! reader {
+    (@common $(#[$doc:meta])* $name:ident, $codec:ty) => {
+        $(#[$doc])*
+        #[cfg_attr(docsrs, doc(cfg(feature = "futures-io")))]
+        pub struct $name<R> {
+            inner: R,
+            state: ReadState<$codec>,
+        }
+
+        impl<R> $name<R> {
+            /// Get a reference to the underlying reader.
+            pub fn get_ref(&self) -> &R {
+                &self.inner
+            }
+
+            /// Get a mutable reference to the underlying reader. Reading from
+            /// it corrupts the data of this reader.
+            pub fn get_mut(&mut self) -> &mut R {
+                &mut self.inner
+            }
+
+            /// Consume this reader, returning the underlying reader.
+            pub fn into_inner(self) -> R {
+                self.inner
+            }
+        }
+
+        impl<R> std::fmt::Debug for $name<R>
+        where
+            R: std::fmt::Debug,
+        {
+            fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+                fmt.debug_struct(stringify!($name))
+                    .field("inner", &self.inner)
+                    .finish()
+            }
+        }
+
+        impl<R> AsyncRead for $name<R>
+        where
+            R: AsyncBufRead + Unpin,
+        {
+            fn poll_read(
+                self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut [u8],
+            ) -> Poll<io::Result<usize>> {
+                let this = self.get_mut();
+                poll_read(&mut this.inner, &mut this.state, cx, buf)
+            }
+        }
+
+        impl<R> AsyncBufRead for $name<R>
+        where
+            R: AsyncBufRead + Unpin,
+        {
+            fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+                let this = self.get_mut();
+                poll_fill_buf(&mut this.inner, &mut this.state, cx)
+            }
+
+            fn consume(self: Pin<&mut Self>, amt: usize) {
+                self.get_mut().state.consume(amt);
+            }
+        }
+    };
+    (decoder $(#[$doc:meta])* $name:ident, $codec:ty, $new:path) => {
+        reader!(@common $(#[$doc])* $name, $codec);
+
+        impl<R> $name<R>
+        where
+            R: AsyncBufRead + Unpin,
+        {
+            /// Create a reader decompressing the data read from `inner`.
+            pub fn new(inner: R) -> Self {
+                Self::new_with_options(inner, &crate::decompress::Options::default())
+            }
+
+            /// Create a reader decompressing the data read from `inner` with
+            /// the given `options`.
+            pub fn new_with_options(inner: R, options: &crate::decompress::Options) -> Self {
+                Self {
+                    inner,
+                    state: ReadState::new($new(options)),
+                }
+            }
+        }
+    };
+    (encoder $(#[$doc:meta])* $name:ident, $codec:ty, $options:ty, $new:path) => {
+        reader!(@common $(#[$doc])* $name, $codec);
+
+        impl<R> $name<R>
+        where
+            R: AsyncBufRead + Unpin,
+        {
+            /// Create a reader compressing the data read from `inner` with
+            /// default options.
+            pub fn new(inner: R) -> io::Result<Self> {
+                Self::new_with_options(inner, &<$options>::default())
+            }
+
+            /// Create a reader compressing the data read from `inner` with
+            /// the given `options`.
+            pub fn new_with_options(inner: R, options: &$options) -> io::Result<Self> {
+                Ok(Self {
+                    inner,
+                    state: ReadState::new($new(options)?),
+                })
+            }
+        }
+    };
+}
+
+macro_rules! writer {
+    (@common $(#[$doc:meta])* $name:ident, $codec:ty) => {
+        $(#[$doc])*
+        #[cfg_attr(docsrs, doc(cfg(feature = "futures-io")))]
+        pub struct $name<W> {
+            inner: W,
+            state: WriteState<$codec>,
+        }
+
+        impl<W> $name<W> {
+            /// Get a reference to the underlying writer.
+            pub fn get_ref(&self) -> &W {
+                &self.inner
+            }
+
+            /// Get a mutable reference to the underlying writer. Writing to it
+            /// corrupts the data of this writer.
+            pub fn get_mut(&mut self) -> &mut W {
+                &mut self.inner
+            }
+
+            /// Consume this writer, returning the underlying writer. Unless
+            /// it was closed, the data may be incomplete.
+            pub fn into_inner(self) -> W {
+                self.inner
+            }
+        }
+
+        impl<W> std::fmt::Debug for $name<W>
+        where
+            W: std::fmt::Debug,
+        {
+            fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+                fmt.debug_struct(stringify!($name))
+                    .field("inner", &self.inner)
+                    .finish()
+            }
+        }
+
+        impl<W> AsyncWrite for $name<W>
+        where
+            W: AsyncWrite + Unpin,
+        {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                let this = self.get_mut();
+                ready!(poll_write_pending(&mut this.inner, &mut this.state, cx))?;
+                Poll::Ready(this.state.process(buf))
+            }
+
+            /// Writes the bytes produced so far to the underlying writer, and
+            /// flushes it. The data buffered by the codec isn't flushed.
+            fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                let this = self.get_mut();
+                ready!(poll_write_pending(&mut this.inner, &mut this.state, cx))?;
+                Pin::new(&mut this.inner).poll_flush(cx)
+            }
+
+            /// Finishes the data, writes it to the underlying writer, and
+            /// closes it.
+            fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                let this = self.get_mut();
+                this.state.finish()?;
+                ready!(poll_write_pending(&mut this.inner, &mut this.state, cx))?;
+                Pin::new(&mut this.inner).poll_close(cx)
+            }
+        }
+    };
+    (decoder $(#[$doc:meta])* $name:ident, $codec:ty, $new:path) => {
+        writer!(@common $(#[$doc])* $name, $codec);
+
+        impl<W> $name<W>
+        where
+            W: AsyncWrite + Unpin,
+        {
+            /// Create a writer decompressing the data written to it into
+            /// `inner`.
+            pub fn new(inner: W) -> Self {
+                Self::new_with_options(inner, &crate::decompress::Options::default())
+            }
+
+            /// Create a writer decompressing the data written to it into
+            /// `inner` with the given `options`.
+            pub fn new_with_options(inner: W, options: &crate::decompress::Options) -> Self {
+                Self {
+                    inner,
+                    state: WriteState::new($new(options)),
+                }
+            }
+        }
+    };
+    (encoder $(#[$doc:meta])* $name:ident, $codec:ty, $options:ty, $new:path) => {
+        writer!(@common $(#[$doc])* $name, $codec);
+
+        impl<W> $name<W>
+        where
+            W: AsyncWrite + Unpin,
+        {
+            /// Create a writer compressing the data written to it into
+            /// `inner` with default options.
+            pub fn new(inner: W) -> io::Result<Self> {
+                Self::new_with_options(inner, &<$options>::default())
+            }
+
+            /// Create a writer compressing the data written to it into
+            /// `inner` with the given `options`.
+            pub fn new_with_options(inner: W, options: &$options) -> io::Result<Self> {
+                Ok(Self {
+                    inner,
+                    state: WriteState::new($new(options)?),
+                })
+            }
+        }
+    };
+}
+
+pub mod bufread {
+    //! Adapters reading from an underlying [`AsyncBufRead`].
+
+    use super::*;
+    use crate::async_io;
+    use crate::compress::{Options, XzOptions};
+
+    reader!(
+        decoder
+        /// LZMA decompressor implementing [`AsyncRead`], decompressing the
+        /// data of an underlying [`AsyncBufRead`] as it is read.
+        LzmaDecoder,
+        async_io::LzmaDecoder,
+        async_io::lzma_decoder
+    );
+
+    reader!(
+        decoder
+        /// Raw LZMA2 decompressor implementing [`AsyncRead`], decompressing
+        /// the data of an underlying [`AsyncBufRead`] as it is read.
+        ///
+        /// Reading stops at the end marker, leaving any trailing data in the
+        /// underlying reader.
+        Lzma2Decoder,
+        async_io::Lzma2Decoder,
+        async_io::lzma2_decoder
+    );
+
+    reader!(
+        decoder
+        /// XZ decompressor implementing [`AsyncRead`], decompressing the data
+        /// of an underlying [`AsyncBufRead`] as it is read.
+        XzDecoder,
+        async_io::XzDecoder,
+        async_io::xz_decoder
+    );
+
+    reader!(
+        encoder
+        /// LZMA compressor implementing [`AsyncRead`], compressing the data
+        /// of an underlying [`AsyncBufRead`] as it is read.
+        LzmaEncoder,
+        async_io::LzmaEncoder,
+        Options,
+        async_io::lzma_encoder
+    );
+
+    reader!(
+        encoder
+        /// Raw LZMA2 compressor implementing [`AsyncRead`], compressing the
+        /// data of an underlying [`AsyncBufRead`] as it is read.
+        Lzma2Encoder,
+        async_io::Lzma2Encoder,
+        Options,
+        async_io::lzma2_encoder
+    );
+
+    reader!(
+        encoder
+        /// XZ compressor implementing [`AsyncRead`], compressing the data of
+        /// an underlying [`AsyncBufRead`] as it is read.
+        ///
+        /// The [`threads`](XzOptions::threads) option must be 1, as waiting
+        /// for other threads would block the executor.
+        XzEncoder,
+        async_io::XzEncoder,
+        XzOptions,
+        async_io::xz_encoder
+    );
+}
+
+pub mod write {
+    //! Adapters writing to an underlying [`AsyncWrite`].
+
+    use super::*;
+    use crate::async_io;
+    use crate::compress::{Options, XzOptions};
+
+    writer!(
+        decoder
+        /// LZMA decompressor implementing [`AsyncWrite`], writing the
+        /// decompressed data to an underlying [`AsyncWrite`].
+        LzmaDecoder,
+        async_io::LzmaDecoder,
+        async_io::lzma_decoder
+    );
+
+    writer!(
+        decoder
+        /// Raw LZMA2 decompressor implementing [`AsyncWrite`], writing the
+        /// decompressed data to an underlying [`AsyncWrite`].
+        ///
+        /// Writes after the end marker consume no data.
+        Lzma2Decoder,
+        async_io::Lzma2Decoder,
+        async_io::lzma2_decoder
+    );
+
+    writer!(
+        decoder
+        /// XZ decompressor implementing [`AsyncWrite`], writing the
+        /// decompressed data to an underlying [`AsyncWrite`].
+        XzDecoder,
+        async_io::XzDecoder,
+        async_io::xz_decoder
+    );
+
+    writer!(
+        encoder
+        /// LZMA compressor implementing [`AsyncWrite`], writing the
+        /// compressed data to an underlying [`AsyncWrite`].
+        LzmaEncoder,
+        async_io::LzmaEncoder,
+        Options,
+        async_io::lzma_encoder
+    );
+
+    writer!(
+        encoder
+        /// Raw LZMA2 compressor implementing [`AsyncWrite`], writing the
+        /// compressed data to an underlying [`AsyncWrite`].
+        Lzma2Encoder,
+        async_io::Lzma2Encoder,
+        Options,
+        async_io::lzma2_encoder
+    );
+
+    writer!(
+        encoder
+        /// XZ compressor implementing [`AsyncWrite`], writing the compressed
+        /// data to an underlying [`AsyncWrite`].
+        ///
+        /// The [`threads`](XzOptions::threads) option must be 1, as waiting
+        /// for other threads would block the executor.
+        XzEncoder,
+        async_io::XzEncoder,
+        XzOptions,
+        async_io::xz_encoder
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_executor::block_on;
+    use futures_util::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Reader returning one byte at a time, after being pending once.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        ready: bool,
+    }
+
+    impl<'a> AsyncRead for Trickle<'a> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let n = {
+                let data = ready!(self.as_mut().poll_fill_buf(cx))?;
+                let n = std::cmp::min(data.len(), buf.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                n
+            };
+            self.consume(n);
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    impl<'a> AsyncBufRead for Trickle<'a> {
+        fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+            let this = self.get_mut();
+            if !this.ready {
+                this.ready = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let n = std::cmp::min(this.data.len(), 1);
+            Poll::Ready(Ok(&this.data[..n]))
+        }
+
+        fn consume(self: Pin<&mut Self>, amt: usize) {
+            let this = self.get_mut();
+            this.data = &this.data[amt..];
+            this.ready = false;
+        }
+    }
+
+    #[test]
+    fn test_bufread_pending() {
+        let input = include_bytes!("../../tests/files/small.txt");
+        let mut compressed = Vec::new();
+        crate::xz_compress(&mut &input[..], &mut compressed).unwrap();
+
+        block_on(async {
+            let mut output = Vec::new();
+            bufread::XzDecoder::new(Trickle {
+                data: &compressed,
+                ready: false,
+            })
+            .read_to_end(&mut output)
+            .await
+            .unwrap();
+            assert_eq!(&output[..], &input[..]);
+
+            let mut recompressed = Vec::new();
+            bufread::LzmaEncoder::new(Trickle {
+                data: &input[..],
+                ready: false,
+            })
+            .unwrap()
+            .read_to_end(&mut recompressed)
+            .await
+            .unwrap();
+            let mut output = Vec::new();
+            crate::lzma_decompress(&mut recompressed.as_slice(), &mut output).unwrap();
+            assert_eq!(&output[..], &input[..]);
+        });
+    }
+
+    #[test]
+    fn test_write_round_trip() {
+        let input = include_bytes!("../../tests/files/foo.txt");
+        block_on(async {
+            let mut encoder = write::XzEncoder::new(Vec::new()).unwrap();
+            for chunk in input.chunks(1000) {
+                encoder.write_all(chunk).await.unwrap();
+            }
+            encoder.close().await.unwrap();
+            let compressed = encoder.into_inner();
+
+            let mut decoder = write::XzDecoder::new(Vec::new());
+            for chunk in compressed.chunks(7) {
+                decoder.write_all(chunk).await.unwrap();
+            }
+            decoder.close().await.unwrap();
+            assert_eq!(&decoder.into_inner()[..], &input[..]);
+        });
+    }
+
+    #[test]
+    fn test_bufread_trailing_data() {
+        let mut compressed = Vec::new();
+        crate::lzma2_compress(&mut &b"Hello world"[..], &mut compressed).unwrap();
+        compressed.extend_from_slice(b"trailing data");
+
+        block_on(async {
+            let mut decoder = bufread::Lzma2Decoder::new(compressed.as_slice());
+            let mut output = Vec::new();
+            decoder.read_to_end(&mut output).await.unwrap();
+            assert_eq!(output, b"Hello world");
+            assert_eq!(decoder.into_inner(), b"trailing data");
+        });
+    }
+
+    #[test]
+    fn test_bufread_truncated() {
+        let mut compressed = Vec::new();
+        crate::xz_compress(&mut &b"Hello world"[..], &mut compressed).unwrap();
+        compressed.truncate(compressed.len() - 1);
+
+        block_on(async {
+            let mut decoder = bufread::XzDecoder::new(compressed.as_slice());
+            let mut output = Vec::new();
+            decoder.read_to_end(&mut output).await.unwrap_err();
+            // The error is returned again instead of the end of the data.
+            let err = decoder.read(&mut [0; 16]).await.unwrap_err();
+            assert!(
+                err.to_string().contains("Unexpected end of input"),
+                "error was: {}",
+                err
+            );
+        });
+    }
+}
@@ -0,0 +1,309 @@
+//! Runtime-independent state of the asynchronous adapters, built on the
+//! push-based decoders and encoders.
+
+#[cfg(feature = "futures-io")]
+pub mod futures;
+#[cfg(feature = "tokio")]
+pub mod tokio;
+
+use crate::decode::lzma2_stream::Lzma2Stream;
+use crate::decode::reader::StreamDecoder;
+use crate::decode::stream::Stream;
+use crate::decode::xz_stream::XzStream;
+use crate::encode::reader::StreamEncoder;
+use crate::encode::{lzma, lzma2, xz};
+use crate::{compress, decompress};
+use std::io;
+
+/// Resumable decoder or encoder. Each call processes the input given to it
+/// and returns, so that no call waits on the underlying I/O.
+pub(crate) trait Codec {
+    /// Process a prefix of the input, appending the bytes produced to
+    /// `output`. Returns the number of bytes consumed, which is 0 for a
+    /// non-empty input once the end of the data has been reached.
+    fn process(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<usize>;
+
+    /// Append the remaining bytes to `output`, checking that the data is
+    /// complete.
+    fn finish(&mut self, output: &mut Vec<u8>) -> io::Result<()>;
+}
+
+fn finished_error() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "the stream is already finished")
+}
+
+/// Error of a failed [`Codec`], which the adapters return again on every
+/// later call rather than reporting the end of the data.
+struct Failure {
+    kind: io::ErrorKind,
+    message: String,
+}
+
+impl Failure {
+    /// Record the error of `result`, if any.
+    fn record<T>(failure: &mut Option<Failure>, result: io::Result<T>) -> io::Result<T> {
+        if let Err(e) = &result {
+            *failure = Some(Failure {
+                kind: e.kind(),
+                message: e.to_string(),
+            });
+        }
+        result
+    }
+
+    /// Return the recorded error, if any.
+    fn check(failure: &Option<Failure>) -> io::Result<()> {
+        match failure {
+            Some(failure) => Err(io::Error::new(failure.kind, failure.message.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// [`Codec`] for a push-based decoder, or [`None`] once it is finished.
+pub(crate) struct Decoder<S>(Option<S>);
+
+impl<S> Codec for Decoder<S>
+where
+    S: StreamDecoder,
+{
+    fn process(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<usize> {
+        let stream = self.0.as_mut().ok_or_else(finished_error)?;
+        let n = stream.write(input)?;
+        if let Some(decoded) = stream.output_mut() {
+            output.append(decoded);
+        }
+        Ok(n)
+    }
+
+    fn finish(&mut self, output: &mut Vec<u8>) -> io::Result<()> {
+        let stream = self.0.take().ok_or_else(finished_error)?;
+        output.append(&mut stream.finish()?);
+        Ok(())
+    }
+}
+
+/// [`Codec`] for an encoder, or [`None`] once it is finished.
+pub(crate) struct Encoder<E>(Option<E>);
+
+impl<E> Codec for Encoder<E>
+where
+    E: StreamEncoder,
+{
+    fn process(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<usize> {
+        let encoder = self.0.as_mut().ok_or_else(finished_error)?;
+        let n = encoder.append(input)?;
+        output.append(encoder.output_mut());
+        Ok(n)
+    }
+
+    fn finish(&mut self, output: &mut Vec<u8>) -> io::Result<()> {
+        let encoder = self.0.take().ok_or_else(finished_error)?;
+        output.append(&mut encoder.finish()?);
+        Ok(())
+    }
+}
+
+/// [`Codec`] for a raw LZMA2 encoder, which writes its chunks to the given
+/// output.
+pub(crate) struct Lzma2Encoder {
+    encoder: lzma2::Encoder,
+    finished: bool,
+}
+
+impl Codec for Lzma2Encoder {
+    fn process(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<usize> {
+        if self.finished {
+            return Err(finished_error());
+        }
+        self.encoder.append(input, output)
+    }
+
+    fn finish(&mut self, output: &mut Vec<u8>) -> io::Result<()> {
+        if self.finished {
+            return Err(finished_error());
+        }
+        self.finished = true;
+        self.encoder.finish(output)
+    }
+}
+
+pub(crate) type LzmaDecoder = Decoder<Stream<Vec<u8>>>;
+pub(crate) type Lzma2Decoder = Decoder<Lzma2Stream<Vec<u8>>>;
+pub(crate) type XzDecoder = Decoder<XzStream<Vec<u8>>>;
+pub(crate) type LzmaEncoder = Encoder<lzma::Encoder<Vec<u8>>>;
+pub(crate) type XzEncoder = Encoder<xz::Encoder<Vec<u8>>>;
+
+pub(crate) fn lzma_decoder(options: &decompress::Options) -> LzmaDecoder {
+    Decoder(Some(Stream::new_with_options(options, Vec::new())))
+}
+
+pub(crate) fn lzma2_decoder(options: &decompress::Options) -> Lzma2Decoder {
+    Decoder(Some(Lzma2Stream::new_with_options(options, Vec::new())))
+}
+
+pub(crate) fn xz_decoder(options: &decompress::Options) -> XzDecoder {
+    Decoder(Some(XzStream::new_with_options(options, Vec::new())))
+}
+
+pub(crate) fn lzma_encoder(options: &compress::Options) -> io::Result<LzmaEncoder> {
+    Ok(Encoder(Some(lzma::Encoder::from_stream(
+        Vec::new(),
+        options,
+    )?)))
+}
+
+pub(crate) fn lzma2_encoder(options: &compress::Options) -> io::Result<Lzma2Encoder> {
+    Ok(Lzma2Encoder {
+        encoder: lzma2::Encoder::new(options)?,
+        finished: false,
+    })
+}
+
+pub(crate) fn xz_encoder(options: &compress::XzOptions) -> io::Result<XzEncoder> {
+    // Waiting for the blocks compressed on other threads would block the
+    // executor.
+    if options.threads != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid threads {}, the asynchronous XZ encoders only compress on 1 thread",
+                options.threads
+            ),
+        ));
+    }
+    Ok(Encoder(Some(xz::Encoder::new(Vec::new(), options)?)))
+}
+
+/// State of an adapter producing the output of a [`Codec`] for reading.
+pub(crate) struct ReadState<C> {
+    codec: C,
+    /// Bytes produced and not yet read.
+    output: Vec<u8>,
+    /// Position of the next byte to read in `output`.
+    pos: usize,
+    /// Whether the codec is finished.
+    finished: bool,
+    failure: Option<Failure>,
+}
+
+impl<C> ReadState<C>
+where
+    C: Codec,
+{
+    pub(crate) fn new(codec: C) -> Self {
+        Self {
+            codec,
+            output: Vec::new(),
+            pos: 0,
+            finished: false,
+            failure: None,
+        }
+    }
+
+    /// Return the error of the codec if it failed.
+    pub(crate) fn check(&self) -> io::Result<()> {
+        Failure::check(&self.failure)
+    }
+
+    /// Bytes produced and not yet read.
+    pub(crate) fn buffered(&self) -> &[u8] {
+        &self.output[self.pos..]
+    }
+
+    /// Whether more input is needed to produce bytes to read.
+    pub(crate) fn needs_input(&self) -> bool {
+        self.pos == self.output.len() && !self.finished
+    }
+
+    pub(crate) fn consume(&mut self, amt: usize) {
+        self.pos = std::cmp::min(self.pos + amt, self.output.len());
+    }
+
+    /// Process the input available, or finish the codec on an empty input.
+    /// Returns the number of bytes consumed.
+    pub(crate) fn process(&mut self, input: &[u8]) -> io::Result<usize> {
+        self.check()?;
+        self.output.clear();
+        self.pos = 0;
+        // The codec consumes nothing once it has reached the end of its data,
+        // leaving any trailing data in the input.
+        let n = if input.is_empty() {
+            0
+        } else {
+            Failure::record(
+                &mut self.failure,
+                self.codec.process(input, &mut self.output),
+            )?
+        };
+        if n == 0 {
+            self.finished = true;
+            Failure::record(&mut self.failure, self.codec.finish(&mut self.output))?;
+        }
+        Ok(n)
+    }
+}
+
+/// State of an adapter writing the output of a [`Codec`] to an underlying
+/// writer.
+pub(crate) struct WriteState<C> {
+    codec: C,
+    /// Bytes produced and not yet written.
+    output: Vec<u8>,
+    /// Position of the next byte to write in `output`.
+    pos: usize,
+    /// Whether the codec is finished.
+    finished: bool,
+    failure: Option<Failure>,
+}
+
+impl<C> WriteState<C>
+where
+    C: Codec,
+{
+    pub(crate) fn new(codec: C) -> Self {
+        Self {
+            codec,
+            output: Vec::new(),
+            pos: 0,
+            finished: false,
+            failure: None,
+        }
+    }
+
+    /// Bytes produced and not yet written.
+    pub(crate) fn pending(&self) -> &[u8] {
+        &self.output[self.pos..]
+    }
+
+    pub(crate) fn advance(&mut self, amt: usize) {
+        self.pos += amt;
+        if self.pos == self.output.len() {
+            self.output.clear();
+            self.pos = 0;
+        }
+    }
+
+    /// Process a prefix of the input, once the pending bytes are written.
+    /// Returns the number of bytes consumed.
+    pub(crate) fn process(&mut self, input: &[u8]) -> io::Result<usize> {
+        Failure::check(&self.failure)?;
+        if input.is_empty() {
+            return Ok(0);
+        }
+        Failure::record(
+            &mut self.failure,
+            self.codec.process(input, &mut self.output),
+        )
+    }
+
+    /// Finish the codec, unless it is already finished.
+    pub(crate) fn finish(&mut self) -> io::Result<()> {
+        Failure::check(&self.failure)?;
+        if !self.finished {
+            self.finished = true;
+            Failure::record(&mut self.failure, self.codec.finish(&mut self.output))?;
+        }
+        Ok(())
+    }
+}
@@ -35,135 +35,6 @@ where
     fn into_output(self) -> W;
 }
 
-/// An accumulating buffer for LZ sequences.
-pub struct LzAccumBuffer<W>
-where
-    W: io::Write,
-{
-    /// Output sink
-    stream: W,
-    /// Buffer
-    buf: Vec<u8>,
-    /// Buffer memory limit
-    memlimit: usize,
-    /// Total number of bytes sent through the buffer
-    len: usize,
-}
-
-impl<W> LzAccumBuffer<W>
-where
-    W: io::Write,
-{
-    pub fn from_stream(stream: W, memlimit: usize) -> Self {
-        Self {
-            stream,
-            buf: Vec::new(),
-            memlimit,
-            len: 0,
-        }
-    }
-
-    /// Append bytes.
-    pub fn append_bytes(&mut self, buf: &[u8]) {
-        self.buf.extend_from_slice(buf);
-        self.len += buf.len();
-    }
-
-    /// Reset the internal dictionary.
-    pub fn reset(&mut self) -> io::Result<()> {
-        self.stream.write_all(self.buf.as_slice())?;
-        self.buf.clear();
-        self.len = 0;
-        Ok(())
-    }
-}
-
-impl<W> LzBuffer<W> for LzAccumBuffer<W>
-where
-    W: io::Write,
-{
-    fn len(&self) -> usize {
-        self.len
-    }
-
-    fn last_or(&self, lit: u8) -> u8 {
-        let buf_len = self.buf.len();
-        if buf_len == 0 {
-            lit
-        } else {
-            self.buf[buf_len - 1]
-        }
-    }
-
-    fn last_n(&self, dist: usize) -> error::Result<u8> {
-        let buf_len = self.buf.len();
-        if dist > buf_len {
-            return Err(error::Error::LzmaError(format!(
-                "Match distance {} is beyond output size {}",
-                dist, buf_len
-            )));
-        }
-
-        Ok(self.buf[buf_len - dist])
-    }
-
-    fn append_literal(&mut self, lit: u8) -> error::Result<()> {
-        let new_len = self.len + 1;
-
-        if new_len > self.memlimit {
-            Err(error::Error::LzmaError(format!(
-                "exceeded memory limit of {}",
-                self.memlimit
-            )))
-        } else {
-            self.buf.push(lit);
-            self.len = new_len;
-            Ok(())
-        }
-    }
-
-    fn append_lz(&mut self, len: usize, dist: usize) -> error::Result<()> {
-        lzma_debug!("LZ {{ len: {}, dist: {} }}", len, dist);
-        let buf_len = self.buf.len();
-        if dist > buf_len {
-            return Err(error::Error::LzmaError(format!(
-                "LZ distance {} is beyond output size {}",
-                dist, buf_len
-            )));
-        }
-
-        let mut offset = buf_len - dist;
-        for _ in 0..len {
-            let x = self.buf[offset];
-            self.buf.push(x);
-            offset += 1;
-        }
-        self.len += len;
-        Ok(())
-    }
-
-    #[cfg(feature = "stream")]
-    fn get_output(&self) -> &W {
-        &self.stream
-    }
-
-    #[cfg(feature = "stream")]
-    fn get_output_mut(&mut self) -> &mut W {
-        &mut self.stream
-    }
-
-    fn finish(mut self) -> io::Result<W> {
-        self.stream.write_all(self.buf.as_slice())?;
-        self.stream.flush()?;
-        Ok(self.stream)
-    }
-
-    #[cfg(feature = "stream")]
-    fn into_output(self) -> W {
-        self.stream
-    }
-}
-
 /// A circular buffer for LZ sequences
 pub struct LzCircularBuffer<W>
 where
@@ -199,24 +70,53 @@ where
         }
     }
 
+    /// Append bytes.
+    pub fn append_bytes(&mut self, mut buf: &[u8]) -> error::Result<()> {
+        while !buf.is_empty() {
+            let n = std::cmp::min(buf.len(), self.dict_size - self.cursor);
+            let end = self.cursor + n;
+            self.grow(end)?;
+            self.buf[self.cursor..end].copy_from_slice(&buf[..n]);
+            self.cursor = end;
+            self.len += n;
+            buf = &buf[n..];
+
+            // Flush the circular buffer to the output
+            if self.cursor == self.dict_size {
+                self.stream.write_all(self.buf.as_slice())?;
+                self.cursor = 0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reset the internal dictionary.
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.stream.write_all(&self.buf[0..self.cursor])?;
+        self.cursor = 0;
+        self.len = 0;
+        Ok(())
+    }
+
     fn get(&self, index: usize) -> u8 {
         *self.buf.get(index).unwrap_or(&0)
     }
 
     fn set(&mut self, index: usize, value: u8) -> error::Result<()> {
-        let new_len = index + 1;
+        self.grow(index + 1)?;
+        self.buf[index] = value;
+        Ok(())
+    }
 
+    /// Grow the buffer to at least the given length, within the memory limit.
+    fn grow(&mut self, new_len: usize) -> error::Result<()> {
         if self.buf.len() < new_len {
             if new_len <= self.memlimit {
                 self.buf.resize(new_len, 0);
             } else {
-                return Err(error::Error::LzmaError(format!(
-                    "exceeded memory limit of {}",
-                    self.memlimit
-                )));
+                return Err(error::Error::MemLimitExceeded(self.memlimit));
             }
         }
-        self.buf[index] = value;
         Ok(())
     }
 }
@@ -355,4 +255,42 @@ mod test {
         let stream = b.finish().unwrap();
         assert!(stream.unflushed.is_empty());
     }
+
+    #[test]
+    fn append_bytes_wraps_around() {
+        const DICT_SIZE: usize = 8;
+        let mut b = LzCircularBuffer::from_stream(Vec::new(), DICT_SIZE, DICT_SIZE);
+        b.append_literal(0).unwrap();
+        b.append_bytes(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]).unwrap();
+        assert_eq!(b.len(), 11);
+        assert_eq!(b.last_n(1).unwrap(), 10);
+        assert_eq!(b.last_n(8).unwrap(), 3);
+        b.append_lz(4, 8).unwrap();
+        assert_eq!(
+            b.finish().unwrap(),
+            [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 3, 4, 5, 6]
+        );
+    }
+
+    #[test]
+    fn reset_flushes_and_clears_dictionary() {
+        const DICT_SIZE: usize = 8;
+        let mut b = LzCircularBuffer::from_stream(Vec::new(), DICT_SIZE, DICT_SIZE);
+        b.append_bytes(&[1, 2, 3]).unwrap();
+        b.reset().unwrap();
+        assert_eq!(b.len(), 0);
+        assert!(b.last_n(1).is_err());
+        b.append_bytes(&[4, 5]).unwrap();
+        assert_eq!(b.finish().unwrap(), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn memlimit_exceeded() {
+        let mut b = LzCircularBuffer::from_stream(Vec::new(), 16, 4);
+        b.append_bytes(&[1, 2, 3, 4]).unwrap();
+        match b.append_bytes(&[5]) {
+            Err(error::Error::MemLimitExceeded(4)) => (),
+            _ => panic!("expected a memory limit error"),
+        }
+    }
 }
@@ -1,15 +1,20 @@
-use crate::decode::lzbuffer::LzBuffer;
+use crate::decode::lzbuffer::{LzBuffer, LzCircularBuffer};
 use crate::decode::lzma::{DecoderState, LzmaProperties};
-use crate::decode::{lzbuffer, rangecoder};
+use crate::decode::rangecoder;
 use crate::error;
 use byteorder::{BigEndian, ReadBytesExt};
 use std::io;
 use std::io::Read;
 
+/// Largest dictionary size, encoded by the property value 40.
+pub const DICT_SIZE_MAX: u32 = 0xFFFF_FFFF;
+
 #[derive(Debug)]
 /// Raw decoder for LZMA2.
 pub struct Lzma2Decoder {
     lzma_state: DecoderState,
+    dict_size: u32,
+    memlimit: usize,
 }
 
 impl Default for Lzma2Decoder {
@@ -20,7 +25,19 @@ impl Default for Lzma2Decoder {
 
 impl Lzma2Decoder {
     /// Creates a new object ready for decompressing data that it's given.
+    ///
+    /// As raw LZMA2 data doesn't record its dictionary size, the largest one
+    /// is allowed.
     pub fn new() -> Lzma2Decoder {
+        Self::with_dict_size(DICT_SIZE_MAX, None)
+    }
+
+    /// Creates a new object ready for decompressing data that it's given for
+    /// the input dict size, and memory limit for the internal buffer.
+    ///
+    /// Exceeding the memory limit fails with
+    /// [`Error::MemLimitExceeded`](crate::error::Error::MemLimitExceeded).
+    pub fn with_dict_size(dict_size: u32, memlimit: Option<usize>) -> Lzma2Decoder {
         Lzma2Decoder {
             lzma_state: DecoderState::new(
                 LzmaProperties {
@@ -30,6 +47,8 @@ impl Lzma2Decoder {
                 },
                 None,
             ),
+            dict_size,
+            memlimit: memlimit.unwrap_or(usize::MAX),
         }
     }
 
@@ -54,39 +73,101 @@ impl Lzma2Decoder {
         input: &mut R,
         output: &mut W,
     ) -> error::Result<()> {
-        let mut accum = lzbuffer::LzAccumBuffer::from_stream(output, usize::MAX);
+        let mut accum = self.new_dict(output);
+        while self.decompress_chunk(&mut accum, input)? {}
+        accum.finish()?;
+        Ok(())
+    }
 
-        loop {
-            let status = input.read_u8().map_err(|e| {
-                error::Error::LzmaError(format!("LZMA2 expected new status: {}", e))
-            })?;
+    /// Creates the dictionary for the given output.
+    pub(crate) fn new_dict<W: io::Write>(&self, output: W) -> LzCircularBuffer<W> {
+        LzCircularBuffer::from_stream(output, self.dict_size as usize, self.memlimit)
+    }
 
-            lzma_info!("LZMA2 status: {}", status);
-
-            if status == 0 {
-                lzma_info!("LZMA2 end of input");
-                break;
-            } else if status == 1 {
-                // uncompressed reset dict
-                Self::parse_uncompressed(&mut accum, input, true)?;
-            } else if status == 2 {
-                // uncompressed no reset
-                Self::parse_uncompressed(&mut accum, input, false)?;
-            } else {
-                self.parse_lzma(&mut accum, input, status)?;
-            }
+    /// Decompresses the next chunk of the input into the dictionary. Returns
+    /// false once the end of the LZMA2 data is reached.
+    pub(crate) fn decompress_chunk<W: io::Write, R: io::BufRead>(
+        &mut self,
+        accum: &mut LzCircularBuffer<W>,
+        input: &mut R,
+    ) -> error::Result<bool> {
+        let status = input
+            .read_u8()
+            .map_err(|e| error::Error::LzmaError(format!("LZMA2 expected new status: {}", e)))?;
+
+        lzma_info!("LZMA2 status: {}", status);
+
+        if status == 0 {
+            lzma_info!("LZMA2 end of input");
+            return Ok(false);
+        } else if status == 1 {
+            // uncompressed reset dict
+            Self::parse_uncompressed(accum, input, true)?;
+        } else if status == 2 {
+            // uncompressed no reset
+            Self::parse_uncompressed(accum, input, false)?;
+        } else {
+            self.parse_lzma(accum, input, status)?;
         }
+        Ok(true)
+    }
 
-        accum.finish()?;
-        Ok(())
+    /// Decompresses what is available of an incomplete chunk.
+    #[cfg(feature = "stream")]
+    pub(crate) fn decompress_incomplete_chunk<W: io::Write, R: io::BufRead>(
+        &mut self,
+        accum: &mut LzCircularBuffer<W>,
+        input: &mut R,
+    ) -> error::Result<()> {
+        let status = input
+            .read_u8()
+            .map_err(|e| error::Error::LzmaError(format!("LZMA2 expected new status: {}", e)))?;
+
+        if status == 1 || status == 2 {
+            let unpacked_size = input.read_u16::<BigEndian>().map_err(|e| {
+                error::Error::LzmaError(format!("LZMA2 expected unpacked size: {}", e))
+            })?;
+            if status == 1 {
+                accum.reset()?;
+            }
+            let mut buf = Vec::new();
+            input
+                .take((unpacked_size as u64) + 1)
+                .read_to_end(&mut buf)?;
+            accum.append_bytes(buf.as_slice())
+        } else {
+            let packed_size = self.parse_lzma_header(accum, input, status)?;
+            let mut taken = input.take(packed_size);
+            let mut rangecoder = rangecoder::RangeDecoder::new(&mut taken)
+                .map_err(|e| error::Error::LzmaError(format!("LZMA input too short: {}", e)))?;
+            self.lzma_state.process_stream(accum, &mut rangecoder)
+        }
     }
 
     fn parse_lzma<R, W>(
         &mut self,
-        accum: &mut lzbuffer::LzAccumBuffer<W>,
+        accum: &mut LzCircularBuffer<W>,
         input: &mut R,
         status: u8,
     ) -> error::Result<()>
+    where
+        R: io::BufRead,
+        W: io::Write,
+    {
+        let packed_size = self.parse_lzma_header(accum, input, status)?;
+        let mut taken = input.take(packed_size);
+        let mut rangecoder = rangecoder::RangeDecoder::new(&mut taken)
+            .map_err(|e| error::Error::LzmaError(format!("LZMA input too short: {}", e)))?;
+        self.lzma_state.process(accum, &mut rangecoder)
+    }
+
+    /// Parses the header of an LZMA chunk, and returns its packed size.
+    fn parse_lzma_header<R, W>(
+        &mut self,
+        accum: &mut LzCircularBuffer<W>,
+        input: &mut R,
+        status: u8,
+    ) -> error::Result<u64>
     where
         R: io::BufRead,
         W: io::Write,
@@ -185,15 +266,11 @@ impl Lzma2Decoder {
 
         self.lzma_state
             .set_unpacked_size(Some(unpacked_size + accum.len() as u64));
-
-        let mut taken = input.take(packed_size);
-        let mut rangecoder = rangecoder::RangeDecoder::new(&mut taken)
-            .map_err(|e| error::Error::LzmaError(format!("LZMA input too short: {}", e)))?;
-        self.lzma_state.process(accum, &mut rangecoder)
+        Ok(packed_size)
     }
 
     fn parse_uncompressed<R, W>(
-        accum: &mut lzbuffer::LzAccumBuffer<W>,
+        accum: &mut LzCircularBuffer<W>,
         input: &mut R,
         reset_dict: bool,
     ) -> error::Result<()>
@@ -223,8 +300,76 @@ impl Lzma2Decoder {
                 unpacked_size, e
             ))
         })?;
-        accum.append_bytes(buf.as_slice());
+        accum.append_bytes(buf.as_slice())
+    }
+}
 
-        Ok(())
+/// Size of the header of a chunk with the given status, including the status.
+#[cfg(feature = "stream")]
+pub(crate) fn chunk_header_size(status: u8) -> usize {
+    match status {
+        1 | 2 => 3,
+        0x80..=0xBF => 5,
+        0xC0..=0xFF => 6,
+        // The end marker, or an invalid status which fails to decode.
+        _ => 1,
+    }
+}
+
+/// Size of the chunk starting with the given bytes, or [`None`] if more bytes
+/// are needed to know it.
+#[cfg(feature = "stream")]
+pub(crate) fn chunk_size(buf: &[u8]) -> Option<usize> {
+    let status = *buf.first()?;
+    let data_size = match status {
+        1 | 2 => buf.get(1..3)?,
+        0x80..=0xFF => buf.get(3..5)?,
+        _ => return Some(1),
+    };
+    Some(chunk_header_size(status) + (((data_size[0] as usize) << 8) | data_size[1] as usize) + 1)
+}
+
+/// Decode the dictionary size from the LZMA2 property byte used by container
+/// formats, see sect. 5.3.1 of the XZ specification.
+pub fn dict_size_from_property(prop: u8) -> error::Result<u32> {
+    match prop {
+        0..=39 => Ok((2 | (prop as u32 & 1)) << (prop / 2 + 11)),
+        40 => Ok(DICT_SIZE_MAX),
+        _ => Err(error::Error::LzmaError(format!(
+            "LZMA2 invalid dictionary size property: {} must be <= 40",
+            prop
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(feature = "stream")]
+    #[test]
+    fn test_chunk_size() {
+        assert_eq!(chunk_size(&[]), None);
+        assert_eq!(chunk_size(&[0x00]), Some(1));
+        assert_eq!(chunk_size(&[0x01, 0x00]), None);
+        assert_eq!(chunk_size(&[0x01, 0x00, 0x00]), Some(4));
+        assert_eq!(chunk_size(&[0x02, 0xFF, 0xFF]), Some(0x10003));
+        assert_eq!(chunk_size(&[0x03]), Some(1));
+        assert_eq!(chunk_size(&[0x80, 0x00, 0x00, 0x00]), None);
+        assert_eq!(chunk_size(&[0x80, 0x00, 0x00, 0x00, 0x10]), Some(0x16));
+        assert_eq!(chunk_size(&[0xBF, 0xFF, 0xFF, 0x01, 0x00]), Some(0x106));
+        assert_eq!(chunk_size(&[0xE0, 0x00, 0x00, 0xFF, 0xFF]), Some(0x10006));
+    }
+
+    #[test]
+    fn test_dict_size_from_property() {
+        assert_eq!(dict_size_from_property(0).unwrap(), 0x1000);
+        assert_eq!(dict_size_from_property(1).unwrap(), 0x1800);
+        assert_eq!(dict_size_from_property(2).unwrap(), 0x2000);
+        assert_eq!(dict_size_from_property(22).unwrap(), 0x80_0000);
+        assert_eq!(dict_size_from_property(23).unwrap(), 0xC0_0000);
+        assert_eq!(dict_size_from_property(39).unwrap(), 0xC000_0000);
+        assert_eq!(dict_size_from_property(40).unwrap(), 0xFFFF_FFFF);
+        assert!(dict_size_from_property(41).is_err());
     }
 }
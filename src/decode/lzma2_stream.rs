@@ -0,0 +1,376 @@
+use crate::decode::lzbuffer::{LzBuffer, LzCircularBuffer};
+use crate::decode::lzma2::{self, Lzma2Decoder};
+use crate::decompress::Options;
+use crate::error::{self, Error};
+use std::fmt::Debug;
+use std::io::{self, Write};
+
+/// Decoder of LZMA2 chunks provided in arbitrary slices of input.
+///
+/// Each chunk is buffered until it is complete, so that at most one chunk
+/// (64 KiB) is buffered besides the dictionary.
+pub(crate) struct ChunkDecoder<W>
+where
+    W: Write,
+{
+    decoder: Lzma2Decoder,
+    output: LzCircularBuffer<W>,
+    /// Temporary buffer to hold the chunk being read.
+    buf: Vec<u8>,
+    /// Size of the chunks decoded so far.
+    packed_size: usize,
+}
+
+impl<W> ChunkDecoder<W>
+where
+    W: Write,
+{
+    pub(crate) fn new(decoder: Lzma2Decoder, output: W) -> Self {
+        let output = decoder.new_dict(output);
+        Self {
+            decoder,
+            output,
+            buf: Vec::new(),
+            packed_size: 0,
+        }
+    }
+
+    pub(crate) fn get_output(&self) -> &W {
+        self.output.get_output()
+    }
+
+    pub(crate) fn get_output_mut(&mut self) -> &mut W {
+        self.output.get_output_mut()
+    }
+
+    /// Size of the chunks decoded so far, including the end marker.
+    pub(crate) fn packed_size(&self) -> usize {
+        self.packed_size
+    }
+
+    /// Number of bytes of an incomplete chunk.
+    pub(crate) fn pending_len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Decode the chunks of the input. Returns true once the end marker is
+    /// decoded, leaving the remaining input.
+    pub(crate) fn process(&mut self, input: &mut &[u8]) -> error::Result<bool> {
+        loop {
+            // Buffer a whole chunk, whose size is given by its header.
+            let len = loop {
+                match lzma2::chunk_size(&self.buf) {
+                    Some(len) if self.buf.len() == len => break len,
+                    _ if input.is_empty() => return Ok(false),
+                    len => {
+                        let len = len.unwrap_or(self.buf.len() + 1);
+                        let n = std::cmp::min(len - self.buf.len(), input.len());
+                        self.buf.extend_from_slice(&input[..n]);
+                        *input = &input[n..];
+                    }
+                }
+            };
+
+            self.packed_size += len;
+            let more = self
+                .decoder
+                .decompress_chunk(&mut self.output, &mut self.buf.as_slice())?;
+            self.buf.clear();
+            if !more {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Flush the dictionary and return the output sink.
+    pub(crate) fn finish(self) -> io::Result<W> {
+        self.output.finish()
+    }
+
+    /// Decode as much as possible of an incomplete chunk, then flush the
+    /// dictionary and return the output sink.
+    pub(crate) fn finish_incomplete(mut self) -> error::Result<W> {
+        if let Some(&status) = self.buf.first() {
+            // The range decoder needs 5 bytes to start.
+            let min_len = lzma2::chunk_header_size(status) + if status >= 0x80 { 5 } else { 0 };
+            if self.buf.len() >= min_len {
+                self.decoder
+                    .decompress_incomplete_chunk(&mut self.output, &mut self.buf.as_slice())?;
+            }
+        }
+        Ok(self.output.finish()?)
+    }
+}
+
+/// Internal state of this streaming decoder.
+enum State<W>
+where
+    W: Write,
+{
+    /// Decoding chunks.
+    Chunks(Box<ChunkDecoder<W>>),
+    /// The end marker has been decoded.
+    Finished(W),
+}
+
+/// Raw LZMA2 decompressor that can process multiple chunks of data using the
+/// [`io::Write`] interface.
+///
+/// As raw LZMA2 data doesn't record its dictionary size, the dictionary grows
+/// up to the largest one, within the memory limit given in the options.
+#[cfg_attr(docsrs, doc(cfg(stream)))]
+pub struct Lzma2Stream<W>
+where
+    W: Write,
+{
+    /// Internal state, or [`None`] after an error.
+    state: Option<State<W>>,
+    /// Options given when a stream is created.
+    options: Options,
+}
+
+impl<W> Lzma2Stream<W>
+where
+    W: Write,
+{
+    /// Initialize the stream. This will consume the `output` which is the sink
+    /// implementing [`io::Write`] that will receive decompressed bytes.
+    pub fn new(output: W) -> Self {
+        Self::new_with_options(&Options::default(), output)
+    }
+
+    /// Initialize the stream with the given `options`. This will consume the
+    /// `output` which is the sink implementing [`io::Write`] that will
+    /// receive decompressed bytes.
+    pub fn new_with_options(options: &Options, output: W) -> Self {
+        let decoder = Lzma2Decoder::with_dict_size(lzma2::DICT_SIZE_MAX, options.memlimit);
+        Self {
+            state: Some(State::Chunks(Box::new(ChunkDecoder::new(decoder, output)))),
+            options: *options,
+        }
+    }
+
+    /// Get a reference to the output sink.
+    pub fn get_output(&self) -> Option<&W> {
+        self.state.as_ref().map(|state| match state {
+            State::Chunks(chunks) => chunks.get_output(),
+            State::Finished(output) => output,
+        })
+    }
+
+    /// Get a mutable reference to the output sink.
+    pub fn get_output_mut(&mut self) -> Option<&mut W> {
+        self.state.as_mut().map(|state| match state {
+            State::Chunks(chunks) => chunks.get_output_mut(),
+            State::Finished(output) => output,
+        })
+    }
+
+    /// Consumes the stream and returns the output sink. This also makes sure
+    /// we have properly reached the end of the stream.
+    pub fn finish(mut self) -> error::Result<W> {
+        match self.state.take() {
+            Some(State::Finished(mut output)) => {
+                output.flush()?;
+                Ok(output)
+            }
+            Some(State::Chunks(chunks)) => {
+                if self.options.allow_incomplete {
+                    chunks.finish_incomplete()
+                } else {
+                    Err(Error::LzmaError(format!(
+                        "LZMA2 unexpected end of input, {} byte(s) of an incomplete chunk",
+                        chunks.pending_len()
+                    )))
+                }
+            }
+            // this will occur if a call to `write()` fails
+            None => Err(Error::LzmaError(
+                "can't finish stream because of previous write error".to_string(),
+            )),
+        }
+    }
+}
+
+impl<W> Debug for Lzma2Stream<W>
+where
+    W: Write + Debug,
+{
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("Lzma2Stream")
+            .field("output", &self.get_output())
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+impl<W> Write for Lzma2Stream<W>
+where
+    W: Write,
+{
+    /// Decodes the data, until the end marker. Any data after the end marker
+    /// is left unprocessed, and the number of bytes processed is returned.
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let state = self.state.take().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                "can't write to stream because of previous write error",
+            )
+        })?;
+        let mut input = data;
+        let state = match state {
+            State::Chunks(mut chunks) => {
+                if chunks.process(&mut input)? {
+                    State::Finished(chunks.finish()?)
+                } else {
+                    State::Chunks(chunks)
+                }
+            }
+            State::Finished(output) => State::Finished(output),
+        };
+        self.state = Some(state);
+        Ok(data.len() - input.len())
+    }
+
+    /// Flushes the output sink. The internal buffer isn't flushed to avoid
+    /// corrupting the internal state. Instead, call [`Self::finish()`] to
+    /// finalize the stream and flush all remaining internal data.
+    fn flush(&mut self) -> io::Result<()> {
+        match self.get_output_mut() {
+            Some(output) => output.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lzma2_compress(x: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        crate::lzma2_compress(&mut io::Cursor::new(x), &mut compressed).unwrap();
+        compressed
+    }
+
+    /// Test an empty stream
+    #[test]
+    fn test_stream_noop() {
+        let stream = Lzma2Stream::new(Vec::new());
+        assert!(stream.get_output().unwrap().is_empty());
+        stream.finish().unwrap_err();
+
+        let mut stream = Lzma2Stream::new(Vec::new());
+        stream.write_all(&[0x00]).unwrap();
+        assert!(stream.finish().unwrap().is_empty());
+    }
+
+    /// Test processing all chunk sizes
+    #[test]
+    fn test_stream_chunked() {
+        let small_input = include_bytes!("../../tests/files/small.txt");
+        let input = lzma2_compress(small_input);
+
+        for chunk in 1..input.len() {
+            let mut stream = Lzma2Stream::new(Vec::new());
+            for data in input.chunks(chunk) {
+                stream.write_all(data).unwrap();
+            }
+            let output = stream.finish().unwrap();
+            assert_eq!(&output[..], &small_input[..]);
+        }
+    }
+
+    #[test]
+    fn test_stream_uncompressed_chunks() {
+        // An uncompressed chunk resetting the dictionary, followed by one that
+        // doesn't.
+        let input = b"\x01\x00\x04Hello\x02\x00\x05 world\x00";
+        for chunk in 1..input.len() {
+            let mut stream = Lzma2Stream::new(Vec::new());
+            for data in input.chunks(chunk) {
+                stream.write_all(data).unwrap();
+            }
+            assert_eq!(stream.finish().unwrap(), b"Hello world");
+        }
+    }
+
+    #[test]
+    fn test_stream_incomplete() {
+        let small_input = include_bytes!("../../tests/files/small.txt");
+        let input = lzma2_compress(small_input);
+
+        for end in 0..input.len() {
+            let mut stream = Lzma2Stream::new(Vec::new());
+            stream.write_all(&input[..end]).unwrap();
+            let err = stream.finish().unwrap_err();
+            assert!(
+                err.to_string().contains("unexpected end of input"),
+                "error was: {}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_stream_corrupted() {
+        let mut stream = Lzma2Stream::new(Vec::new());
+        let err = stream
+            .write_all(b"corrupted bytes here corrupted bytes here")
+            .unwrap_err();
+        assert!(err.to_string().contains("LZMA2 invalid status"), "{}", err);
+        let err = stream.finish().unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("can\'t finish stream because of previous write error"));
+    }
+
+    #[test]
+    fn test_trailing_data() {
+        let mut input = lzma2_compress(b"Hello world");
+        let stream_size = input.len();
+        input.extend_from_slice(b"trailing data");
+
+        let mut stream = Lzma2Stream::new(Vec::new());
+        assert_eq!(stream.write(&input).unwrap(), stream_size);
+        assert_eq!(stream.write(&input[stream_size..]).unwrap(), 0);
+        assert_eq!(stream.finish().unwrap(), b"Hello world");
+    }
+
+    #[test]
+    fn test_allow_incomplete() {
+        let input = include_bytes!("../../tests/files/small.txt");
+        let compressed = lzma2_compress(input);
+        let compressed = &compressed[..compressed.len() / 2];
+
+        // Should fail to finish() without the allow_incomplete option.
+        let mut stream = Lzma2Stream::new(Vec::new());
+        stream.write_all(compressed).unwrap();
+        stream.finish().unwrap_err();
+
+        // Should succeed with the allow_incomplete option, decoding what is
+        // available of the chunk.
+        let mut stream = Lzma2Stream::new_with_options(
+            &Options {
+                allow_incomplete: true,
+                ..Default::default()
+            },
+            Vec::new(),
+        );
+        stream.write_all(compressed).unwrap();
+        let output = stream.finish().unwrap();
+        assert!(!output.is_empty());
+        assert_eq!(output, &input[..output.len()]);
+
+        // An incomplete uncompressed chunk.
+        let mut stream = Lzma2Stream::new_with_options(
+            &Options {
+                allow_incomplete: true,
+                ..Default::default()
+            },
+            Vec::new(),
+        );
+        stream.write_all(b"\x01\x00\x0AHello").unwrap();
+        assert_eq!(stream.finish().unwrap(), b"Hello");
+    }
+}
@@ -7,6 +7,16 @@ pub mod options;
 pub mod rangecoder;
 pub mod util;
 pub mod xz;
+pub mod xz_index;
+pub mod xz_parallel;
 
+#[cfg(feature = "stream")]
+pub mod lzma2_stream;
+#[cfg(feature = "stream")]
+pub mod reader;
 #[cfg(feature = "stream")]
 pub mod stream;
+#[cfg(feature = "stream")]
+pub mod xz_seekable;
+#[cfg(feature = "stream")]
+pub mod xz_stream;
@@ -1,13 +1,18 @@
 /// Options to tweak decompression behavior.
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Options {
     /// Defines whether the unpacked size should be read from the header or
     /// provided.
     ///
+    /// This option only applies to the LZMA format.
+    ///
     /// The default is [`UnpackedSize::ReadFromHeader`].
     pub unpacked_size: UnpackedSize,
     /// Defines whether the dictionary's dynamic size should be limited during
-    /// decompression.
+    /// decompression. Exceeding the limit fails with
+    /// [`Error::MemLimitExceeded`](crate::error::Error::MemLimitExceeded).
+    ///
+    /// This option applies to the LZMA, LZMA2 and XZ formats.
     ///
     /// The default is unlimited.
     pub memlimit: Option<usize>,
@@ -17,6 +22,50 @@ pub struct Options {
     ///
     /// The default is false (always do completion check).
     pub allow_incomplete: bool,
+    /// Determines whether to stop decoding XZ data after the first stream,
+    /// leaving the input positioned right after its footer. Otherwise, all
+    /// the concatenated streams are decoded, and the input must end after
+    /// them and their Stream Padding.
+    ///
+    /// This option only applies to the XZ format.
+    ///
+    /// The default is false (decode all the streams).
+    pub single_stream: bool,
+    /// Number of threads decoding XZ blocks in parallel, or 0 for the number
+    /// of available CPUs.
+    ///
+    /// This option only applies to [`xz_decompress_parallel`], for inputs
+    /// made of several blocks. Each thread holds a whole block, both
+    /// compressed and decompressed, and its own dictionary, while the calling
+    /// thread reads another compressed block. Inputs whose blocks don't fit
+    /// within `memlimit` this way are decoded on the calling thread.
+    ///
+    /// The default is 1.
+    ///
+    /// [`xz_decompress_parallel`]: crate::xz_decompress_parallel
+    pub threads: u32,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            unpacked_size: UnpackedSize::default(),
+            memlimit: None,
+            allow_incomplete: false,
+            single_stream: false,
+            threads: 1,
+        }
+    }
+}
+
+impl Options {
+    /// Number of threads to decode with, resolving 0.
+    pub(crate) fn thread_count(&self) -> usize {
+        match self.threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            threads => threads as usize,
+        }
+    }
 }
 
 /// Alternatives for defining the unpacked size of the decoded data.
@@ -53,6 +102,8 @@ mod test {
                 unpacked_size: UnpackedSize::ReadFromHeader,
                 memlimit: None,
                 allow_incomplete: false,
+                single_stream: false,
+                threads: 1,
             },
             Options::default()
         );
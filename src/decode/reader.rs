@@ -0,0 +1,365 @@
+use crate::decode::lzma2_stream::Lzma2Stream;
+use crate::decode::stream::Stream;
+use crate::decode::xz_stream::XzStream;
+use crate::decompress::Options;
+use crate::error;
+use std::fmt::Debug;
+use std::io::{self, BufRead, Read, Write};
+
+/// Push-based decoder writing its decompressed bytes into a [`Vec`], which
+/// readers drive.
+pub(crate) trait StreamDecoder: Write {
+    fn output_mut(&mut self) -> Option<&mut Vec<u8>>;
+    fn finish(self) -> error::Result<Vec<u8>>;
+}
+
+impl StreamDecoder for Stream<Vec<u8>> {
+    fn output_mut(&mut self) -> Option<&mut Vec<u8>> {
+        self.get_output_mut()
+    }
+
+    fn finish(self) -> error::Result<Vec<u8>> {
+        Stream::finish(self)
+    }
+}
+
+impl StreamDecoder for Lzma2Stream<Vec<u8>> {
+    fn output_mut(&mut self) -> Option<&mut Vec<u8>> {
+        self.get_output_mut()
+    }
+
+    fn finish(self) -> error::Result<Vec<u8>> {
+        Lzma2Stream::finish(self)
+    }
+}
+
+impl StreamDecoder for XzStream<Vec<u8>> {
+    fn output_mut(&mut self) -> Option<&mut Vec<u8>> {
+        self.get_output_mut()
+    }
+
+    fn finish(self) -> error::Result<Vec<u8>> {
+        XzStream::finish(self)
+    }
+}
+
+/// Adapter feeding the input of a [`BufRead`] to a push-based decoder as the
+/// decompressed bytes are read.
+struct DecoderReader<R, S> {
+    input: R,
+    /// Decoder, or [`None`] once it is finished or failed.
+    stream: Option<S>,
+    /// Whether decoding failed, in which case reading keeps failing instead
+    /// of reporting the end of the data.
+    failed: bool,
+    /// Decompressed bytes not yet read.
+    output: Vec<u8>,
+    /// Position of the next byte to read in `output`.
+    pos: usize,
+}
+
+impl<R, S> DecoderReader<R, S>
+where
+    R: BufRead,
+    S: StreamDecoder,
+{
+    fn new(input: R, stream: S) -> Self {
+        Self {
+            input,
+            stream: Some(stream),
+            failed: false,
+            output: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Feed the decoder until it produces some output or is finished.
+    fn fill_output(&mut self) -> io::Result<()> {
+        self.output.clear();
+        self.pos = 0;
+        while self.output.is_empty() {
+            if self.failed {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "can't read from stream because of previous decoding error",
+                ));
+            }
+            let stream = match self.stream.as_mut() {
+                Some(stream) => stream,
+                None => return Ok(()),
+            };
+            let data = self.input.fill_buf()?;
+            // The decoder consumes nothing once it has reached the end of its
+            // data, leaving any trailing data in the input.
+            let n = if data.is_empty() {
+                0
+            } else {
+                match stream.write(data) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        self.failed = true;
+                        return Err(e);
+                    }
+                }
+            };
+            if n == 0 {
+                let stream = self.stream.take().unwrap();
+                match stream.finish() {
+                    Ok(output) => self.output = output,
+                    Err(e) => {
+                        self.failed = true;
+                        return Err(e.into());
+                    }
+                }
+            } else {
+                self.input.consume(n);
+                if let Some(output) = stream.output_mut() {
+                    std::mem::swap(&mut self.output, output);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R, S> Read for DecoderReader<R, S>
+where
+    R: BufRead,
+    S: StreamDecoder,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos == self.output.len() {
+            self.fill_output()?;
+        }
+        let n = (&self.output[self.pos..]).read(buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl<R, S> Debug for DecoderReader<R, S>
+where
+    R: Debug,
+{
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("DecoderReader")
+            .field("input", &self.input)
+            .field("finished", &self.stream.is_none())
+            .field("failed", &self.failed)
+            .field("pending", &(self.output.len() - self.pos))
+            .finish()
+    }
+}
+
+macro_rules! decoder_reader {
+    ($(#[$doc:meta])* $name:ident, $stream:ident) => {
+        $(#[$doc])*
+        #[cfg_attr(docsrs, doc(cfg(stream)))]
+        #[derive(Debug)]
+        pub struct $name<R> {
+            inner: DecoderReader<R, $stream<Vec<u8>>>,
+        }
+
+        impl<R> $name<R>
+        where
+            R: BufRead,
+        {
+            /// Create a reader decompressing the data read from `input`.
+            pub fn new(input: R) -> Self {
+                Self::new_with_options(input, &Options::default())
+            }
+
+            /// Create a reader decompressing the data read from `input` with
+            /// the given `options`.
+            pub fn new_with_options(input: R, options: &Options) -> Self {
+                Self {
+                    inner: DecoderReader::new(input, $stream::new_with_options(options, Vec::new())),
+                }
+            }
+
+            /// Get a reference to the underlying reader.
+            pub fn get_ref(&self) -> &R {
+                &self.inner.input
+            }
+
+            /// Get a mutable reference to the underlying reader. Reading from
+            /// it may corrupt the decompressed data.
+            pub fn get_mut(&mut self) -> &mut R {
+                &mut self.inner.input
+            }
+
+            /// Consume this decoder, returning the underlying reader.
+            pub fn into_inner(self) -> R {
+                self.inner.input
+            }
+        }
+
+        impl<R> Read for $name<R>
+        where
+            R: BufRead,
+        {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.inner.read(buf)
+            }
+        }
+    };
+}
+
+decoder_reader!(
+    /// LZMA decompressor implementing [`io::Read`], decompressing the data of
+    /// an underlying [`BufRead`] as it is read.
+    LzmaReader,
+    Stream
+);
+
+decoder_reader!(
+    /// Raw LZMA2 decompressor implementing [`io::Read`], decompressing the
+    /// data of an underlying [`BufRead`] as it is read.
+    ///
+    /// Reading stops at the end marker, leaving any trailing data in the
+    /// underlying reader.
+    Lzma2Reader,
+    Lzma2Stream
+);
+
+decoder_reader!(
+    /// XZ decompressor implementing [`io::Read`], decompressing the data of
+    /// an underlying [`BufRead`] as it is read.
+    ///
+    /// With the [`single_stream`](Options::single_stream) option, reading
+    /// stops after the first stream, leaving any trailing data in the
+    /// underlying reader.
+    XzReader,
+    XzStream
+);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Reader returning at most `chunk` bytes at a time.
+    struct ChunkedReader<'a> {
+        data: &'a [u8],
+        chunk: usize,
+    }
+
+    impl<'a> Read for ChunkedReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = std::cmp::min(self.chunk, buf.len());
+            (&mut self.data).read(&mut buf[..n])
+        }
+    }
+
+    fn read_chunked<T: Read>(mut reader: T, chunk: usize) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        let mut buf = vec![0; chunk];
+        loop {
+            match reader.read(&mut buf)? {
+                0 => return Ok(output),
+                n => output.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    #[test]
+    fn test_lzma_reader() {
+        let input = include_bytes!("../../tests/files/foo.txt");
+        let mut compressed = Vec::new();
+        crate::lzma_compress(&mut &input[..], &mut compressed).unwrap();
+
+        for &chunk in &[1, 7, 4096] {
+            let reader = io::BufReader::with_capacity(
+                chunk,
+                ChunkedReader {
+                    data: &compressed,
+                    chunk,
+                },
+            );
+            let output = read_chunked(LzmaReader::new(reader), chunk).unwrap();
+            assert_eq!(&output[..], &input[..]);
+        }
+    }
+
+    #[test]
+    fn test_lzma2_reader_trailing_data() {
+        let mut compressed = Vec::new();
+        crate::lzma2_compress(&mut &b"Hello world"[..], &mut compressed).unwrap();
+        compressed.extend_from_slice(b"trailing data");
+
+        let mut reader = Lzma2Reader::new(&compressed[..]);
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, b"Hello world");
+        assert_eq!(reader.into_inner(), b"trailing data");
+    }
+
+    #[test]
+    fn test_xz_reader() {
+        let input = include_bytes!("../../tests/files/foo.txt");
+        let mut compressed = Vec::new();
+        crate::xz_compress(&mut &input[..], &mut compressed).unwrap();
+
+        for &chunk in &[1, 7, 4096] {
+            let reader = io::BufReader::with_capacity(chunk, &compressed[..]);
+            let output = read_chunked(XzReader::new(reader), chunk).unwrap();
+            assert_eq!(&output[..], &input[..]);
+        }
+    }
+
+    #[test]
+    fn test_xz_reader_single_stream() {
+        let mut compressed = Vec::new();
+        crate::xz_compress(&mut &b"Hello world"[..], &mut compressed).unwrap();
+        compressed.extend_from_slice(b"trailing data");
+
+        let options = Options {
+            single_stream: true,
+            ..Default::default()
+        };
+        let mut reader = XzReader::new_with_options(&compressed[..], &options);
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, b"Hello world");
+        assert_eq!(reader.into_inner(), b"trailing data");
+    }
+
+    #[test]
+    fn test_xz_reader_corrupted() {
+        let mut compressed = Vec::new();
+        crate::xz_compress(&mut &b"Hello world"[..], &mut compressed).unwrap();
+        // Truncate the stream.
+        compressed.truncate(compressed.len() - 1);
+
+        let mut reader = XzReader::new(&compressed[..]);
+        let mut output = Vec::new();
+        let err = reader.read_to_end(&mut output).unwrap_err();
+        assert!(
+            err.to_string().contains("Unexpected end of input"),
+            "error was: {}",
+            err
+        );
+        // The error is reported again instead of the end of the data.
+        let err = reader.read(&mut [0; 16]).unwrap_err();
+        assert!(
+            err.to_string().contains("previous decoding error"),
+            "error was: {}",
+            err
+        );
+        reader.read(&mut [0; 16]).unwrap_err();
+    }
+
+    #[test]
+    fn test_lzma2_reader_corrupted() {
+        let mut reader = Lzma2Reader::new(&b"corrupted bytes here"[..]);
+        let err = reader.read(&mut [0; 16]).unwrap_err();
+        assert!(
+            err.to_string().contains("LZMA2 invalid status"),
+            "error was: {}",
+            err
+        );
+        reader.read(&mut [0; 16]).unwrap_err();
+    }
+}
@@ -236,7 +236,7 @@ where
                         let position = self.tmp.position();
                         let bytes_read =
                             input.read(&mut self.tmp.get_mut()[position as usize..])?;
-                        let bytes_read = if bytes_read < std::u64::MAX as usize {
+                        let bytes_read = if bytes_read < u64::MAX as usize {
                             bytes_read as u64
                         } else {
                             return Err(io::Error::new(
@@ -277,7 +277,7 @@ where
                                 // reset the cursor because we may have partial reads
                                 input.set_position(0);
                                 let bytes_read = input.read(&mut self.tmp.get_mut()[..])?;
-                                let bytes_read = if bytes_read < std::u64::MAX as usize {
+                                let bytes_read = if bytes_read < u64::MAX as usize {
                                     bytes_read as u64
                                 } else {
                                     return Err(io::Error::new(
@@ -302,6 +302,9 @@ where
                                 Error::LzmaError(e) | Error::XzError(e) => {
                                     io::Error::new(io::ErrorKind::Other, e)
                                 }
+                                e @ Error::MemLimitExceeded(_) => {
+                                    io::Error::new(io::ErrorKind::Other, e.to_string())
+                                }
                             });
                         }
                     }
@@ -339,12 +342,6 @@ where
     }
 }
 
-impl From<Error> for io::Error {
-    fn from(error: Error) -> io::Error {
-        io::Error::new(io::ErrorKind::Other, format!("{:?}", error))
-    }
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
@@ -495,6 +492,6 @@ mod test {
         );
         stream.write_all(compressed).unwrap();
         let output = stream.finish().unwrap();
-        assert_eq!(output, &input[..26]);
+        assert_eq!(output, &input[..25]);
     }
 }
@@ -1,3 +1,5 @@
+use crate::xz::filter::FilterChain;
+use crate::xz::Check;
 use std::io;
 
 pub fn read_tag<R: io::BufRead>(input: &mut R, tag: &[u8]) -> io::Result<bool> {
@@ -33,6 +35,24 @@ pub fn flush_zero_padding<R: io::BufRead>(input: &mut R) -> io::Result<bool> {
     }
 }
 
+/// Skip null bytes, and return how many were skipped.
+pub fn skip_zero_padding<R: io::BufRead>(input: &mut R) -> io::Result<usize> {
+    let mut count = 0;
+    loop {
+        let len = {
+            let buf = input.fill_buf()?;
+            let len = buf.iter().take_while(|&&x| x == 0).count();
+            if len == 0 {
+                return Ok(count);
+            }
+            len
+        };
+
+        input.consume(len);
+        count += len;
+    }
+}
+
 /// An [`io::Read`] computing a digest on the bytes read.
 pub struct CrcDigestRead<'a, 'b, R, S>
 where
@@ -114,3 +134,214 @@ where
         self.count += amt;
     }
 }
+
+/// An [`io::Write`] computing the Block Check of the bytes written, and
+/// counting them.
+pub struct CheckCountWrite<W>
+where
+    W: io::Write,
+{
+    /// Underlying writer
+    write: W,
+    /// Block Check
+    check: Check,
+    /// Number of bytes written
+    count: usize,
+}
+
+impl<W> CheckCountWrite<W>
+where
+    W: io::Write,
+{
+    pub fn new(write: W, check: Check) -> Self {
+        Self {
+            write,
+            check,
+            count: 0,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    #[cfg(feature = "stream")]
+    pub fn get_ref(&self) -> &W {
+        &self.write
+    }
+
+    #[cfg(feature = "stream")]
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.write
+    }
+
+    /// Unwrap the underlying writer and the Block Check.
+    pub fn into_inner(self) -> (W, Check) {
+        (self.write, self.check)
+    }
+}
+
+impl<W> io::Write for CheckCountWrite<W>
+where
+    W: io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.write.write(buf)?;
+        self.check.update(&buf[..result]);
+        self.count += result;
+        Ok(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write.flush()
+    }
+}
+
+/// An [`io::Write`] failing once more bytes than a limit are written to it,
+/// to stop decoding data larger than its expected size.
+#[derive(Debug)]
+pub struct LimitWrite<W>
+where
+    W: io::Write,
+{
+    /// Underlying writer
+    write: W,
+    /// Number of bytes that can still be written
+    remaining: u64,
+    /// Whether a write went over the limit
+    exceeded: bool,
+}
+
+impl<W> LimitWrite<W>
+where
+    W: io::Write,
+{
+    pub fn new(write: W, limit: u64) -> Self {
+        Self {
+            write,
+            remaining: limit,
+            exceeded: false,
+        }
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+
+    #[cfg(feature = "stream")]
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.write
+    }
+
+    pub fn into_inner(self) -> W {
+        self.write
+    }
+}
+
+impl<W> io::Write for LimitWrite<W>
+where
+    W: io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.remaining {
+            self.exceeded = true;
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "data larger than its size limit",
+            ));
+        }
+        let result = self.write.write(buf)?;
+        self.remaining -= result as u64;
+        Ok(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write.flush()
+    }
+}
+
+/// An [`io::Write`] running the bytes written through the non-last filters of
+/// a block, before writing them to the underlying writer.
+pub struct FilterWrite<W>
+where
+    W: io::Write,
+{
+    /// Underlying writer
+    write: W,
+    filters: FilterChain,
+    /// Bytes that went through the filters, reused across writes
+    buf: Vec<u8>,
+}
+
+impl<W> FilterWrite<W>
+where
+    W: io::Write,
+{
+    pub fn new(write: W, filters: FilterChain) -> Self {
+        Self {
+            write,
+            filters,
+            buf: Vec::new(),
+        }
+    }
+
+    #[cfg(feature = "stream")]
+    pub fn get_ref(&self) -> &W {
+        &self.write
+    }
+
+    #[cfg(feature = "stream")]
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.write
+    }
+
+    /// Write the bytes still held by the filters at the end of the block, and
+    /// unwrap the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.buf.clear();
+        self.filters.finish(&mut self.buf);
+        self.write.write_all(&self.buf)?;
+        Ok(self.write)
+    }
+}
+
+impl<W> io::Write for FilterWrite<W>
+where
+    W: io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.filters.is_empty() {
+            return self.write.write(buf);
+        }
+        self.buf.clear();
+        self.filters.process(buf, &mut self.buf);
+        self.write.write_all(&self.buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write.flush()
+    }
+}
+
+/// Pseudo-random numbers from a linear congruential generator, to generate
+/// test data.
+#[cfg(test)]
+pub(crate) fn lcg(mut seed: u32) -> impl FnMut() -> u32 {
+    move || {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        seed
+    }
+}
+
+/// Compress `input` to XZ, in blocks of `block_size` bytes.
+#[cfg(test)]
+pub(crate) fn compress_blocks(input: &[u8], block_size: u64) -> Vec<u8> {
+    let options = crate::compress::XzOptions {
+        block_size: Some(block_size),
+        ..Default::default()
+    };
+    let mut compressed = Vec::new();
+    crate::xz_compress_with_options(&mut &input[..], &mut compressed, &options).unwrap();
+    compressed
+}
@@ -1,21 +1,54 @@
 //! Decoder for the `.xz` file format.
 
-use crate::decode::lzma2::Lzma2Decoder;
+use crate::compress;
+use crate::decode::lzma2::{self, Lzma2Decoder};
+use crate::decode::options::Options;
 use crate::decode::util;
 use crate::error;
-use crate::xz::crc::{CRC32, CRC64};
-use crate::xz::{footer, header, CheckMethod, StreamFlags};
-use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+use crate::xz::bcj::BcjArch;
+use crate::xz::crc::CRC32;
+use crate::xz::filter::FilterChain;
+use crate::xz::sha256;
+use crate::xz::{footer, header, Check, CheckMethod, StreamFlags};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
 use std::io;
 use std::io::Read;
 
-#[derive(Debug)]
-struct Record {
-    unpadded_size: u64,
-    unpacked_size: u64,
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Record {
+    pub(crate) unpadded_size: u64,
+    pub(crate) unpacked_size: u64,
+}
+
+/// Decode the XZ streams of the input, which may be separated by Stream
+/// Padding, see sect. 2.2.
+pub fn decode_stream<R, W>(input: &mut R, output: &mut W, options: &Options) -> error::Result<()>
+where
+    R: io::BufRead,
+    W: io::Write,
+{
+    decode_single_stream(input, output, options)?;
+    if options.single_stream {
+        return Ok(());
+    }
+
+    loop {
+        let padding_size = util::skip_zero_padding(input)?;
+        if padding_size % 4 != 0 {
+            return Err(error::Error::XzError(format!(
+                "Invalid stream padding of {} byte(s), must be a multiple of 4",
+                padding_size
+            )));
+        }
+        if util::is_eof(input)? {
+            return Ok(());
+        }
+        lzma_info!("XZ stream padding: {} byte(s)", padding_size);
+        decode_single_stream(input, output, options)?;
+    }
 }
 
-pub fn decode_stream<R, W>(input: &mut R, output: &mut W) -> error::Result<()>
+fn decode_single_stream<R, W>(input: &mut R, output: &mut W, options: &Options) -> error::Result<()>
 where
     R: io::BufRead,
     W: io::Write,
@@ -41,59 +74,39 @@ where
             header.stream_flags.check_method,
             &mut records,
             header_size,
+            options,
         )?;
     };
 
-    let crc32 = input.read_u32::<LittleEndian>()?;
-    let mut digest = CRC32.digest();
-    {
-        let mut digested = util::CrcDigestRead::new(input, &mut digest);
-        let backward_size = digested.read_u32::<LittleEndian>()?;
-        if index_size as u32 != (backward_size + 1) << 2 {
-            return Err(error::Error::XzError(format!(
-                "Invalid index size: expected {} but got {}",
-                (backward_size + 1) << 2,
-                index_size
-            )));
-        }
-
-        let stream_flags = {
-            let field = digested.read_u16::<BigEndian>()?;
-            StreamFlags::parse(field)?
-        };
-
-        if header.stream_flags != stream_flags {
-            return Err(error::Error::XzError(format!(
-                "Flags in header ({:?}) does not match footer ({:?})",
-                header.stream_flags, stream_flags
-            )));
-        }
-    }
+    check_footer(input, header.stream_flags, index_size)
+}
 
-    let digest_crc32 = digest.finalize();
-    if crc32 != digest_crc32 {
+pub(crate) fn check_footer<R>(
+    input: &mut R,
+    header_flags: StreamFlags,
+    index_size: usize,
+) -> error::Result<()>
+where
+    R: io::BufRead,
+{
+    let footer = footer::StreamFooter::parse(input)?;
+    if index_size as u64 != footer.index_size {
         return Err(error::Error::XzError(format!(
-            "Invalid footer CRC32: expected 0x{:08x} but got 0x{:08x}",
-            crc32, digest_crc32
+            "Invalid index size: expected {} but got {}",
+            footer.index_size, index_size
         )));
     }
 
-    if !util::read_tag(input, footer::XZ_MAGIC_FOOTER)? {
+    if header_flags != footer.stream_flags {
         return Err(error::Error::XzError(format!(
-            "Invalid footer magic, expected {:?}",
-            footer::XZ_MAGIC_FOOTER
+            "Flags in header ({:?}) does not match footer ({:?})",
+            header_flags, footer.stream_flags
         )));
     }
-
-    if !util::is_eof(input)? {
-        return Err(error::Error::XzError(
-            "Unexpected data after last XZ block".to_string(),
-        ));
-    }
     Ok(())
 }
 
-fn check_index<R>(
+pub(crate) fn check_index<R>(
     count_input: &mut util::CountBufRead<'_, R>,
     records: &[Record],
 ) -> error::Result<()>
@@ -137,7 +150,7 @@ where
     };
     // TODO: create padding parser function
     let count = count_input.count();
-    let padding_size = ((count ^ 0x03) + 1) & 0x03;
+    let padding_size = padding_size(count);
     lzma_info!(
         "XZ index: {} byte(s) read, {} byte(s) of padding",
         count,
@@ -172,49 +185,185 @@ where
 
 #[derive(Debug)]
 enum FilterId {
+    Delta,
+    Bcj(BcjArch),
     Lzma2,
 }
 
 fn get_filter_id(id: u64) -> error::Result<FilterId> {
     match id {
+        0x03 => Ok(FilterId::Delta),
+        0x04 => Ok(FilterId::Bcj(BcjArch::X86)),
+        0x05 => Ok(FilterId::Bcj(BcjArch::PowerPc)),
+        0x06 => Ok(FilterId::Bcj(BcjArch::Ia64)),
+        0x07 => Ok(FilterId::Bcj(BcjArch::Arm)),
+        0x08 => Ok(FilterId::Bcj(BcjArch::ArmThumb)),
+        0x09 => Ok(FilterId::Bcj(BcjArch::Sparc)),
+        0x0A => Ok(FilterId::Bcj(BcjArch::Arm64)),
+        0x0B => Ok(FilterId::Bcj(BcjArch::RiscV)),
         0x21 => Ok(FilterId::Lzma2),
         _ => Err(error::Error::XzError(format!("Unknown filter id {}", id))),
     }
 }
 
-struct Filter {
+pub(crate) struct Filter {
     filter_id: FilterId,
     props: Vec<u8>,
 }
 
-struct BlockHeader {
+pub(crate) struct BlockHeader {
     filters: Vec<Filter>,
     packed_size: Option<u64>,
     unpacked_size: Option<u64>,
 }
 
+/// Size of the null padding after a block or index of the given size, to
+/// align it on 4 bytes.
+pub(crate) fn padding_size(count: usize) -> usize {
+    ((count ^ 0x03) + 1) & 0x03
+}
+
 fn read_block<R, W>(
     count_input: &mut util::CountBufRead<'_, R>,
     output: &mut W,
     check_method: CheckMethod,
     records: &mut Vec<Record>,
     header_size: u8,
+    options: &Options,
 ) -> error::Result<bool>
 where
     R: io::BufRead,
     W: io::Write,
+{
+    let block_header = parse_block_header(count_input, header_size)?;
+    let (mut decoder, filters) = block_decoder(&block_header, options)?;
+
+    // The decoded data is streamed to the output, through the other filters
+    // and the Block Check.
+    let (packed_size, unpacked_size, check) = {
+        let mut packed_input = util::CountBufRead::new(count_input);
+        let check_output = util::CheckCountWrite::new(&mut *output, Check::new(check_method));
+        let mut filter_output = util::FilterWrite::new(check_output, filters);
+        decoder.decompress(&mut packed_input, &mut filter_output)?;
+        let check_output = filter_output.finish()?;
+        let unpacked_size = check_output.count();
+        let (_, check) = check_output.into_inner();
+        (packed_input.count(), unpacked_size, check)
+    };
+    lzma_info!("XZ block decompressed to {} byte(s)", unpacked_size);
+    check_block_sizes(&block_header, packed_size, unpacked_size)?;
+
+    let count = count_input.count();
+    lzma_info!(
+        "XZ block: {} byte(s) read, {} byte(s) of padding, check method {:?}",
+        count,
+        padding_size(count),
+        check_method
+    );
+    read_block_padding(count_input, count)?;
+    validate_block_check(count_input, check)?;
+
+    records.push(Record {
+        unpadded_size: (count + check_method.size()) as u64,
+        unpacked_size: unpacked_size as u64,
+    });
+
+    let finished = false;
+    Ok(finished)
+}
+
+/// Decode a whole block read into `input`, and check it against its Index
+/// Record. Decoding stops as soon as the block exceeds the decompressed size
+/// given by the record.
+pub(crate) fn decode_block(
+    mut input: &[u8],
+    check_method: CheckMethod,
+    record: &Record,
+    options: &Options,
+) -> error::Result<Vec<u8>> {
+    let mut count_input = util::CountBufRead::new(&mut input);
+    let header_size = count_input.read_u8()?;
+    if header_size == 0 {
+        return Err(error::Error::XzError(
+            "Invalid block header, found the index indicator".to_string(),
+        ));
+    }
+
+    // The output is allocated at once, so that its memory is the size given
+    // by the record.
+    let mut output = Vec::new();
+    output
+        .try_reserve_exact(record.unpacked_size as usize)
+        .map_err(|_| {
+            error::Error::XzError(format!(
+                "Invalid index for block: can't allocate {} byte(s)",
+                record.unpacked_size
+            ))
+        })?;
+    let mut output = util::LimitWrite::new(output, record.unpacked_size);
+    let mut records = Vec::with_capacity(1);
+    let result = read_block(
+        &mut count_input,
+        &mut output,
+        check_method,
+        &mut records,
+        header_size,
+        options,
+    );
+    if output.exceeded() {
+        return Err(error::Error::XzError(format!(
+            "Invalid index for block: decompressed to more than {} byte(s)",
+            record.unpacked_size
+        )));
+    }
+    result?;
+    let output = output.into_inner();
+    if records[0] != *record {
+        return Err(error::Error::XzError(format!(
+            "Invalid index for block: {:?} does not match index ({:?})",
+            records[0], record
+        )));
+    }
+    Ok(output)
+}
+
+/// Read the Block Header at `offset` in a seekable input, and return it with
+/// its size byte.
+pub(crate) fn read_block_header_at<R>(
+    input: &mut R,
+    offset: u64,
+) -> error::Result<(BlockHeader, u8)>
+where
+    R: io::Read + io::Seek,
+{
+    input.seek(io::SeekFrom::Start(offset))?;
+    let mut input = io::BufReader::new(input);
+    let header_size = input.read_u8()?;
+    if header_size == 0 {
+        return Err(error::Error::XzError(
+            "Invalid block header, found the index indicator".to_string(),
+        ));
+    }
+    let block_header = parse_block_header(&mut input, header_size)?;
+    Ok((block_header, header_size))
+}
+
+/// Parse a Block Header after its size byte, and verify its CRC32.
+pub(crate) fn parse_block_header<R>(input: &mut R, header_size: u8) -> error::Result<BlockHeader>
+where
+    R: io::BufRead,
 {
     let mut digest = CRC32.digest();
     digest.update(&[header_size]);
     let header_size = ((header_size as u64) << 2) - 1;
 
     let block_header = {
-        let mut taken = count_input.take(header_size);
+        let mut taken = input.take(header_size);
         let mut digested = io::BufReader::new(util::CrcDigestRead::new(&mut taken, &mut digest));
         read_block_header(&mut digested, header_size)?
     };
 
-    let crc32 = count_input.read_u32::<LittleEndian>()?;
+    let crc32 = input.read_u32::<LittleEndian>()?;
     let digest_crc32 = digest.finalize();
     if crc32 != digest_crc32 {
         return Err(error::Error::XzError(format!(
@@ -222,35 +371,117 @@ where
             crc32, digest_crc32
         )));
     }
+    Ok(block_header)
+}
+
+/// Create the decoder for the filter chain of a block, i.e. the LZMA2
+/// decoder and the other filters in decoding order.
+pub(crate) fn block_decoder(
+    block_header: &BlockHeader,
+    options: &Options,
+) -> error::Result<(Lzma2Decoder, FilterChain)> {
+    let (filters, dict_size) = block_filters(block_header)?;
+    lzma_info!("XZ LZMA2 dict_size: {}", dict_size);
+    // Filters are listed in encoding order, so they decode in reverse.
+    let converters = filters
+        .iter()
+        .rev()
+        .map(|filter| filter.converter(false))
+        .collect();
+    Ok((
+        Lzma2Decoder::with_dict_size(dict_size, options.memlimit),
+        FilterChain::new(converters),
+    ))
+}
+
+/// Parse the filter chain of a block, i.e. the filters before LZMA2 in
+/// encoding order, and the LZMA2 dictionary size.
+pub(crate) fn block_filters(
+    block_header: &BlockHeader,
+) -> error::Result<(Vec<compress::Filter>, u32)> {
+    // The last filter decodes the compressed data.
+    let (last_filter, other_filters) = block_header
+        .filters
+        .split_last()
+        .expect("a block header has at least one filter");
+
+    let mut filters = Vec::with_capacity(other_filters.len());
+    for filter in other_filters {
+        match filter.filter_id {
+            FilterId::Delta => filters.push(compress::Filter::Delta(delta_options(filter)?)),
+            FilterId::Bcj(arch) => {
+                let options = compress::BcjOptions {
+                    start_offset: bcj_start_offset(filter)?,
+                };
+                filters.push(compress::Filter::bcj(arch, options));
+            }
+            FilterId::Lzma2 => {
+                return Err(error::Error::XzError(format!(
+                    "Invalid filter chain, filter {:?} must be the last filter",
+                    filter.filter_id
+                )));
+            }
+        }
+    }
 
-    let mut tmpbuf: Vec<u8> = Vec::new();
-    let filters = block_header.filters;
-    for (i, filter) in filters.iter().enumerate() {
-        if i == 0 {
-            // TODO: use SubBufRead on input if packed_size is known?
-            let packed_size = decode_filter(count_input, &mut tmpbuf, filter)?;
-            if let Some(expected_packed_size) = block_header.packed_size {
-                if (packed_size as u64) != expected_packed_size {
-                    return Err(error::Error::XzError(format!(
-                        "Invalid compressed size: expected {} but got {}",
-                        expected_packed_size, packed_size
-                    )));
-                }
+    match last_filter.filter_id {
+        FilterId::Lzma2 => {
+            if last_filter.props.len() != 1 {
+                return Err(error::Error::XzError(format!(
+                    "Invalid properties for filter {:?}",
+                    last_filter.filter_id
+                )));
             }
-        } else {
-            let mut newbuf: Vec<u8> = Vec::new();
-            decode_filter(
-                &mut io::BufReader::new(tmpbuf.as_slice()),
-                &mut newbuf,
-                filter,
-            )?;
-            // TODO: does this move or copy?
-            tmpbuf = newbuf;
+            let dict_size = lzma2::dict_size_from_property(last_filter.props[0])?;
+            Ok((filters, dict_size))
         }
+        FilterId::Delta | FilterId::Bcj(_) => Err(error::Error::XzError(format!(
+            "Invalid filter chain, filter {:?} can't be the last filter",
+            last_filter.filter_id
+        ))),
+    }
+}
+
+/// Options of a Delta filter, given by its properties, see sect. 5.3.3.
+fn delta_options(filter: &Filter) -> error::Result<compress::DeltaOptions> {
+    if filter.props.len() != 1 {
+        return Err(error::Error::XzError(format!(
+            "Invalid properties for filter {:?}",
+            filter.filter_id
+        )));
     }
+    Ok(compress::DeltaOptions {
+        dist: filter.props[0] as u32 + 1,
+    })
+}
+
+/// Start offset of a BCJ filter, given by its optional properties, see
+/// sect. 5.3.2.
+fn bcj_start_offset(filter: &Filter) -> error::Result<u32> {
+    match filter.props.len() {
+        0 => Ok(0),
+        4 => Ok(LittleEndian::read_u32(&filter.props)),
+        _ => Err(error::Error::XzError(format!(
+            "Invalid properties for filter {:?}",
+            filter.filter_id
+        ))),
+    }
+}
 
-    let unpacked_size = tmpbuf.len();
-    lzma_info!("XZ block decompressed to {} byte(s)", tmpbuf.len());
+/// Verify the sizes of a decoded block against its Block Header.
+pub(crate) fn check_block_sizes(
+    block_header: &BlockHeader,
+    packed_size: usize,
+    unpacked_size: usize,
+) -> error::Result<()> {
+    if let Some(expected_packed_size) = block_header.packed_size {
+        if (packed_size as u64) != expected_packed_size {
+            return Err(error::Error::XzError(format!(
+                "Invalid compressed size: expected {} but got {}",
+                expected_packed_size, packed_size
+            )));
+        }
+    }
 
     if let Some(expected_unpacked_size) = block_header.unpacked_size {
         if (unpacked_size as u64) != expected_unpacked_size {
@@ -260,51 +491,37 @@ where
             )));
         }
     }
+    Ok(())
+}
 
-    let count = count_input.count();
-    let padding_size = ((count ^ 0x03) + 1) & 0x03;
-    lzma_info!(
-        "XZ block: {} byte(s) read, {} byte(s) of padding, check method {:?}",
-        count,
-        padding_size,
-        check_method
-    );
-    for _ in 0..padding_size {
-        let byte = count_input.read_u8()?;
+/// Read the Block Padding after a block of the given size.
+pub(crate) fn read_block_padding<R>(input: &mut R, count: usize) -> error::Result<()>
+where
+    R: io::BufRead,
+{
+    for _ in 0..padding_size(count) {
+        let byte = input.read_u8()?;
         if byte != 0 {
             return Err(error::Error::XzError(
                 "Invalid block padding, must be null bytes".to_string(),
             ));
         }
     }
-    validate_block_check(count_input, tmpbuf.as_slice(), check_method)?;
-
-    output.write_all(tmpbuf.as_slice())?;
-    records.push(Record {
-        unpadded_size: (count_input.count() - padding_size) as u64,
-        unpacked_size: unpacked_size as u64,
-    });
-
-    let finished = false;
-    Ok(finished)
+    Ok(())
 }
 
 /// Verify block checksum against the "Block Check" field.
 ///
 /// See spec section 3.4 for details.
-fn validate_block_check<R>(
-    input: &mut R,
-    buf: &[u8],
-    check_method: CheckMethod,
-) -> error::Result<()>
+pub(crate) fn validate_block_check<R>(input: &mut R, check: Check) -> error::Result<()>
 where
     R: io::BufRead,
 {
-    match check_method {
-        CheckMethod::None => (),
-        CheckMethod::Crc32 => {
+    match check {
+        Check::None => (),
+        Check::Crc32(digest) => {
             let crc32 = input.read_u32::<LittleEndian>()?;
-            let digest_crc32 = CRC32.checksum(buf);
+            let digest_crc32 = digest.finalize();
             if crc32 != digest_crc32 {
                 return Err(error::Error::XzError(format!(
                     "Invalid block CRC32, expected 0x{:08x} but got 0x{:08x}",
@@ -312,9 +529,9 @@ where
                 )));
             }
         }
-        CheckMethod::Crc64 => {
+        Check::Crc64(digest) => {
             let crc64 = input.read_u64::<LittleEndian>()?;
-            let digest_crc64 = CRC64.checksum(buf);
+            let digest_crc64 = digest.finalize();
             if crc64 != digest_crc64 {
                 return Err(error::Error::XzError(format!(
                     "Invalid block CRC64, expected 0x{:016x} but got 0x{:016x}",
@@ -322,35 +539,24 @@ where
                 )));
             }
         }
-        // TODO
-        CheckMethod::Sha256 => {
-            return Err(error::Error::XzError(
-                "Unsupported SHA-256 checksum (not yet implemented)".to_string(),
-            ));
-        }
-    }
-    Ok(())
-}
-
-fn decode_filter<R, W>(input: &mut R, output: &mut W, filter: &Filter) -> error::Result<usize>
-where
-    R: io::BufRead,
-    W: io::Write,
-{
-    let mut count_input = util::CountBufRead::new(input);
-    match filter.filter_id {
-        FilterId::Lzma2 => {
-            if filter.props.len() != 1 {
+        Check::Sha256(hasher) => {
+            let mut sha256 = [0; sha256::DIGEST_SIZE];
+            input.read_exact(&mut sha256)?;
+            let digest_sha256 = hasher.finalize();
+            if sha256 != digest_sha256 {
                 return Err(error::Error::XzError(format!(
-                    "Invalid properties for filter {:?}",
-                    filter.filter_id
+                    "Invalid block SHA-256, expected {} but got {}",
+                    to_hex(&sha256),
+                    to_hex(&digest_sha256)
                 )));
             }
-            // TODO: properties??
-            Lzma2Decoder::new().decompress(&mut count_input, output)?;
-            Ok(count_input.count())
         }
     }
+    Ok(())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
 }
 
 fn read_block_header<R>(input: &mut R, header_size: u64) -> error::Result<BlockHeader>
@@ -0,0 +1,206 @@
+//! Indexes of the XZ streams of a seekable input, read backwards from its
+//! end, see sect. 4.
+
+use crate::decode::xz::{get_multibyte, padding_size, Record};
+use crate::error;
+use crate::xz::crc::CRC32;
+use crate::xz::{footer, header, StreamFlags};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
+use std::io::{self, SeekFrom};
+
+/// Size of the Stream Header and of the Stream Footer.
+const STREAM_HEADER_SIZE: u64 = 12;
+
+/// Location and Index of a stream.
+#[derive(Debug)]
+pub(crate) struct StreamIndex {
+    /// Position of the Stream Header in the input.
+    pub(crate) offset: u64,
+    pub(crate) stream_flags: StreamFlags,
+    pub(crate) records: Vec<Record>,
+    /// Size of the Index.
+    pub(crate) index_size: u64,
+    /// Size of the Stream Padding after the stream.
+    pub(crate) padding: u64,
+}
+
+impl StreamIndex {
+    /// Positions of the blocks in the input, with their records.
+    pub(crate) fn blocks(&self) -> impl Iterator<Item = (u64, &Record)> {
+        let mut offset = self.offset + STREAM_HEADER_SIZE;
+        self.records.iter().map(move |record| {
+            let block_offset = offset;
+            offset += block_size(record);
+            (block_offset, record)
+        })
+    }
+
+    /// Size of the stream, without the Stream Padding after it.
+    pub(crate) fn size(&self) -> u64 {
+        2 * STREAM_HEADER_SIZE + self.blocks_size() + self.index_size
+    }
+
+    /// Total size of the blocks, with their padding.
+    fn blocks_size(&self) -> u64 {
+        // The sizes are checked against the input, so they can't overflow
+        // once read.
+        self.records.iter().fold(0, |size: u64, record| {
+            size.saturating_add(block_size(record))
+        })
+    }
+}
+
+/// Size of a block in the input, including its padding.
+pub(crate) fn block_size(record: &Record) -> u64 {
+    record
+        .unpadded_size
+        .saturating_add(padding_size(record.unpadded_size as usize) as u64)
+}
+
+/// Read the Indexes of the streams between `start` and the end of the input,
+/// in the order of the streams. The input is left at an unspecified position.
+pub(crate) fn read_indexes<R>(input: &mut R, start: u64) -> error::Result<Vec<StreamIndex>>
+where
+    R: io::Read + io::Seek,
+{
+    let mut streams = Vec::new();
+    let mut end = input.seek(SeekFrom::End(0))?;
+    let mut padding = 0;
+    while end > start {
+        if end - start < 2 * STREAM_HEADER_SIZE {
+            return Err(error::Error::XzError(format!(
+                "Invalid stream of {} byte(s), must be at least {} bytes",
+                end - start,
+                2 * STREAM_HEADER_SIZE
+            )));
+        }
+
+        // Stream Padding, see sect. 2.2.
+        let mut word = [0; 4];
+        input.seek(SeekFrom::Start(end - 4))?;
+        input.read_exact(&mut word)?;
+        if word == [0; 4] {
+            padding += 4;
+            end -= 4;
+            continue;
+        }
+
+        let stream = read_stream_index(input, start, end, padding)?;
+        lzma_info!("XZ stream index: {:?}", stream);
+        end = stream.offset;
+        padding = 0;
+        streams.push(stream);
+    }
+
+    if padding != 0 {
+        return Err(error::Error::XzError(
+            "Invalid stream padding before the first stream".to_string(),
+        ));
+    }
+    streams.reverse();
+    Ok(streams)
+}
+
+/// Read the Index of the stream ending at `end` and followed by `padding`
+/// bytes of Stream Padding, and check its Stream Header.
+fn read_stream_index<R>(
+    input: &mut R,
+    start: u64,
+    end: u64,
+    padding: u64,
+) -> error::Result<StreamIndex>
+where
+    R: io::Read + io::Seek,
+{
+    input.seek(SeekFrom::Start(end - STREAM_HEADER_SIZE))?;
+    let footer = footer::StreamFooter::parse(&mut io::BufReader::new(&mut *input))?;
+    let index_size = footer.index_size;
+    if end - start < 2 * STREAM_HEADER_SIZE + index_size {
+        return Err(error::Error::XzError(format!(
+            "Invalid index size {}, larger than the stream",
+            index_size
+        )));
+    }
+
+    let index_offset = end - STREAM_HEADER_SIZE - index_size;
+    input.seek(SeekFrom::Start(index_offset))?;
+    let mut index = vec![0; index_size as usize];
+    input.read_exact(&mut index)?;
+    let records = parse_index(&index)?;
+
+    let stream = StreamIndex {
+        offset: 0,
+        stream_flags: footer.stream_flags,
+        records,
+        index_size,
+        padding,
+    };
+    let blocks_size = stream.blocks_size();
+    if index_offset - start - STREAM_HEADER_SIZE < blocks_size {
+        return Err(error::Error::XzError(format!(
+            "Invalid index, its blocks of {} byte(s) are larger than the stream",
+            blocks_size
+        )));
+    }
+    let offset = index_offset - blocks_size - STREAM_HEADER_SIZE;
+
+    input.seek(SeekFrom::Start(offset))?;
+    let header = header::StreamHeader::parse(&mut io::BufReader::new(&mut *input))?;
+    if header.stream_flags != footer.stream_flags {
+        return Err(error::Error::XzError(format!(
+            "Flags in header ({:?}) does not match footer ({:?})",
+            header.stream_flags, footer.stream_flags
+        )));
+    }
+
+    Ok(StreamIndex { offset, ..stream })
+}
+
+/// Parse a whole Index, from its indicator to its CRC32.
+fn parse_index(index: &[u8]) -> error::Result<Vec<Record>> {
+    let (mut fields, crc32) = index.split_at(index.len() - 4);
+    let crc32 = LittleEndian::read_u32(crc32);
+    let digest_crc32 = CRC32.checksum(fields);
+    if crc32 != digest_crc32 {
+        return Err(error::Error::XzError(format!(
+            "Invalid index CRC32: expected 0x{:08x} but got 0x{:08x}",
+            crc32, digest_crc32
+        )));
+    }
+
+    if fields.read_u8()? != 0 {
+        return Err(error::Error::XzError(
+            "Invalid index indicator, must be a null byte".to_string(),
+        ));
+    }
+    let num_records = get_multibyte(&mut fields)?;
+    // Each record takes at least 2 bytes.
+    if num_records > fields.len() as u64 / 2 {
+        return Err(error::Error::XzError(format!(
+            "Invalid index of {} records, larger than the index",
+            num_records
+        )));
+    }
+    let mut records = Vec::with_capacity(num_records as usize);
+    for _ in 0..num_records {
+        let unpadded_size = get_multibyte(&mut fields)?;
+        let unpacked_size = get_multibyte(&mut fields)?;
+        if unpadded_size == 0 {
+            return Err(error::Error::XzError(
+                "Invalid index, unpadded size must be positive".to_string(),
+            ));
+        }
+        records.push(Record {
+            unpadded_size,
+            unpacked_size,
+        });
+    }
+
+    let count = index.len() - 4 - fields.len();
+    if fields.len() != padding_size(count) || fields.iter().any(|&byte| byte != 0) {
+        return Err(error::Error::XzError(
+            "Invalid index padding, must be null bytes".to_string(),
+        ));
+    }
+    Ok(records)
+}
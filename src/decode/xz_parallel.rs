@@ -0,0 +1,184 @@
+//! Decoder for `.xz` files decoding their blocks in parallel, from a seekable
+//! input whose Indexes locate the blocks.
+
+use crate::decode::options::Options;
+use crate::decode::xz::{self, Record};
+use crate::decode::xz_index::{self, StreamIndex};
+use crate::error;
+use crate::xz::pool::Pool;
+use crate::xz::CheckMethod;
+use std::io::{self, SeekFrom};
+
+/// Block read from the input, to decode on a thread of the pool.
+struct BlockJob {
+    input: Vec<u8>,
+    check_method: CheckMethod,
+    record: Record,
+}
+
+/// Decode the XZ streams of the input on `options.threads` threads, or on
+/// the calling thread like [`xz::decode_stream`] if the input isn't seekable,
+/// has at most one block, or has blocks too large for the memory limit.
+pub fn decode_stream<R, W>(input: &mut R, output: &mut W, options: &Options) -> error::Result<()>
+where
+    R: io::BufRead + io::Seek,
+    W: io::Write,
+{
+    let threads = options.thread_count();
+    // The Indexes locate all the streams, so the input must end after them.
+    if threads > 1 && !options.single_stream {
+        if let Ok(start) = input.stream_position() {
+            let streams = xz_index::read_indexes(input, start)?;
+            let num_blocks: usize = streams.iter().map(|stream| stream.records.len()).sum();
+            if num_blocks > 1 && fits_memlimit(input, &streams, threads, options)? {
+                decode_blocks(input, output, options, threads, &streams)?;
+                input.seek(SeekFrom::End(0))?;
+                return Ok(());
+            }
+            input.seek(SeekFrom::Start(start))?;
+        }
+    }
+    xz::decode_stream(input, output, options)
+}
+
+/// Whether `threads` threads can each hold a whole block, both compressed
+/// and decompressed, with its dictionary, while the calling thread reads
+/// another compressed block, within the memory limit. The dictionary sizes
+/// are read from the Block Headers.
+fn fits_memlimit<R>(
+    input: &mut R,
+    streams: &[StreamIndex],
+    threads: usize,
+    options: &Options,
+) -> error::Result<bool>
+where
+    R: io::Read + io::Seek,
+{
+    let memlimit = match options.memlimit {
+        Some(memlimit) => memlimit as u64,
+        None => return Ok(true),
+    };
+    let mut block_memory: u64 = 0;
+    let mut input_memory: u64 = 0;
+    for stream in streams {
+        for (offset, record) in stream.blocks() {
+            let (block_header, _) = xz::read_block_header_at(input, offset)?;
+            let (_, dict_size) = xz::block_filters(&block_header)?;
+            // The dictionary grows up to the decompressed size.
+            let dict_size = std::cmp::min(dict_size as u64, record.unpacked_size);
+            let block_size = xz_index::block_size(record);
+            let memory = record
+                .unpacked_size
+                .saturating_add(block_size)
+                .saturating_add(dict_size);
+            block_memory = std::cmp::max(block_memory, memory);
+            input_memory = std::cmp::max(input_memory, block_size);
+        }
+    }
+    Ok(block_memory
+        .saturating_mul(threads as u64)
+        .saturating_add(input_memory)
+        <= memlimit)
+}
+
+/// Decode the blocks of the streams on a pool of threads, writing them in
+/// order.
+fn decode_blocks<R, W>(
+    input: &mut R,
+    output: &mut W,
+    options: &Options,
+    threads: usize,
+    streams: &[StreamIndex],
+) -> error::Result<()>
+where
+    R: io::Read + io::Seek,
+    W: io::Write,
+{
+    let decode_options = *options;
+    let mut pool = Pool::new(threads, move |job: BlockJob| {
+        xz::decode_block(&job.input, job.check_method, &job.record, &decode_options)
+    });
+
+    for stream in streams {
+        for (offset, record) in stream.blocks() {
+            if pool.is_full() {
+                output.write_all(&pool.next().unwrap()?)?;
+            }
+            let mut block = vec![0; xz_index::block_size(record) as usize];
+            input.seek(SeekFrom::Start(offset))?;
+            input.read_exact(&mut block)?;
+            pool.submit(BlockJob {
+                input: block,
+                check_method: stream.stream_flags.check_method,
+                record: record.clone(),
+            });
+        }
+    }
+    while let Some(decoded) = pool.next() {
+        output.write_all(&decoded?)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::util::compress_blocks;
+
+    #[test]
+    fn test_fits_memlimit() {
+        let input = include_bytes!("../../tests/files/foo.txt");
+        let compressed = compress_blocks(input, 10000);
+        let mut cursor = io::Cursor::new(&compressed);
+        let streams = xz_index::read_indexes(&mut cursor, 0).unwrap();
+        let records = &streams[0].records;
+        let input_memory = records.iter().map(xz_index::block_size).max().unwrap();
+        // The dictionaries of 8 MiB grow up to the 10000 bytes of the blocks.
+        let block_memory = records
+            .iter()
+            .map(|record| 2 * record.unpacked_size + xz_index::block_size(record))
+            .max()
+            .unwrap();
+        let memlimit = (4 * block_memory + input_memory) as usize;
+
+        let mut options = Options::default();
+        assert!(fits_memlimit(&mut cursor, &streams, 4, &options).unwrap());
+        options.memlimit = Some(memlimit);
+        assert!(fits_memlimit(&mut cursor, &streams, 4, &options).unwrap());
+        assert!(!fits_memlimit(&mut cursor, &streams, 5, &options).unwrap());
+        // The dictionaries don't fit along with the blocks.
+        options.memlimit = Some(memlimit - 4 * 10000);
+        assert!(!fits_memlimit(&mut cursor, &streams, 4, &options).unwrap());
+
+        // Too large blocks are decoded sequentially, within the limit.
+        options.threads = 5;
+        let mut output = Vec::new();
+        decode_stream(&mut io::Cursor::new(&compressed), &mut output, &options).unwrap();
+        assert_eq!(&output[..], &input[..]);
+    }
+
+    #[test]
+    fn test_block_larger_than_index() {
+        let input = include_bytes!("../../tests/files/foo.txt");
+        let compressed = compress_blocks(input, 10000);
+        let streams = xz_index::read_indexes(&mut io::Cursor::new(&compressed), 0).unwrap();
+        let stream = &streams[0];
+        let (offset, record) = stream.blocks().next().unwrap();
+        let block = &compressed[offset as usize..][..xz_index::block_size(record) as usize];
+        let check_method = stream.stream_flags.check_method;
+        let options = Options::default();
+        xz::decode_block(block, check_method, record, &options).unwrap();
+
+        let small = Record {
+            unpacked_size: 1000,
+            ..record.clone()
+        };
+        let err = xz::decode_block(block, check_method, &small, &options).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("decompressed to more than 1000 byte(s)"),
+            "{}",
+            err
+        );
+    }
+}
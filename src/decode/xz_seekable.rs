@@ -0,0 +1,432 @@
+//! Random access to the decompressed data of `.xz` files, through the Indexes
+//! locating their blocks.
+
+use crate::decode::options::Options;
+use crate::decode::util::LimitWrite;
+use crate::decode::xz::Record;
+use crate::decode::xz_index;
+use crate::decode::xz_stream::XzStream;
+use crate::error;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Size of the Stream Header.
+const STREAM_HEADER_SIZE: usize = 12;
+
+/// Size of the compressed chunks read from the input.
+const CHUNK_SIZE: u64 = 16 << 10;
+
+/// Size of the compressed pieces fed to the decoder at once, so that few
+/// LZMA2 chunks are decompressed past the position read.
+const FEED_SIZE: usize = 1 << 10;
+
+/// Largest block kept in memory without a memory limit, the dictionary size
+/// of `xz -9`.
+const CACHE_SIZE_MAX: u64 = 64 << 20;
+
+/// Location of a block in the input and in the decompressed data.
+#[derive(Debug)]
+struct Block {
+    /// Position of the Stream Header of its stream in the input.
+    stream_offset: u64,
+    /// Position of the block in the input.
+    offset: u64,
+    /// Position of its first byte in the decompressed data.
+    start: u64,
+    record: Record,
+}
+
+/// Decoder of a block, fed with its compressed data up to the decompressed
+/// bytes read.
+#[derive(Debug)]
+struct BlockDecoder {
+    /// Index of the block.
+    index: usize,
+    stream: XzStream<LimitWrite<Vec<u8>>>,
+    /// Number of compressed bytes of the block read from the input.
+    read: u64,
+    /// Compressed bytes read and not fed to the stream yet, from
+    /// `input_pos`.
+    input: Vec<u8>,
+    input_pos: usize,
+    /// Decompressed bytes kept in memory, starting at `data_start` in the
+    /// block.
+    data: Vec<u8>,
+    data_start: u64,
+    /// Whether all the decompressed bytes of the block are kept.
+    cache: bool,
+}
+
+/// XZ decompressor implementing [`io::Read`] and [`io::Seek`] over the
+/// decompressed data of an underlying seekable reader.
+///
+/// The Indexes at the end of the streams locate the blocks, so that reading
+/// at a position only decompresses the block holding it, from its start up
+/// to the position. The decoder of this block is kept to serve the next
+/// reads, and the whole block is kept in memory if its size fits within the
+/// [`memlimit`](Options::memlimit), or 64 MiB without one, to serve reads
+/// before the position. The integrity check of a block is verified once it
+/// is read up to its end. Files made of small blocks, see
+/// [`XzOptions::block_size`](crate::compress::XzOptions::block_size), are
+/// best suited to random access.
+#[derive(Debug)]
+pub struct XzSeekableReader<R> {
+    input: R,
+    options: Options,
+    blocks: Vec<Block>,
+    /// Size of the decompressed data.
+    size: u64,
+    /// Position in the decompressed data.
+    pos: u64,
+    /// Decoder of the last block read.
+    decoder: Option<BlockDecoder>,
+}
+
+impl<R> XzSeekableReader<R>
+where
+    R: Read + Seek,
+{
+    /// Create a reader decompressing the XZ streams between the current
+    /// position of `input` and its end.
+    pub fn new(input: R) -> error::Result<Self> {
+        Self::new_with_options(input, &Options::default())
+    }
+
+    /// Create a reader decompressing the XZ streams between the current
+    /// position of `input` and its end, with the given `options`.
+    ///
+    /// The streams must be followed only by their Stream Padding, and the
+    /// `single_stream` and `threads` options don't apply.
+    pub fn new_with_options(mut input: R, options: &Options) -> error::Result<Self> {
+        let start = input.stream_position()?;
+        let streams = xz_index::read_indexes(&mut input, start)?;
+
+        let mut blocks = Vec::new();
+        let mut size: u64 = 0;
+        for stream in &streams {
+            for (offset, record) in stream.blocks() {
+                blocks.push(Block {
+                    stream_offset: stream.offset,
+                    offset,
+                    start: size,
+                    record: record.clone(),
+                });
+                size = size.checked_add(record.unpacked_size).ok_or_else(|| {
+                    error::Error::XzError("Invalid index, too large decompressed size".to_string())
+                })?;
+            }
+        }
+
+        Ok(Self {
+            input,
+            options: *options,
+            blocks,
+            size,
+            pos: 0,
+            decoder: None,
+        })
+    }
+
+    /// Size of the decompressed data, given by the Indexes.
+    pub fn decompressed_size(&self) -> u64 {
+        self.size
+    }
+
+    /// Get a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.input
+    }
+
+    /// Get a mutable reference to the underlying reader. The position of
+    /// the decompressed data doesn't depend on its position.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.input
+    }
+
+    /// Consume this decoder, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+
+    /// Decompress the block at `index` until the decompressed bytes kept in
+    /// memory hold `offset` in the block. The current decoder is reused if
+    /// it is at this block and hasn't discarded the bytes at `offset`.
+    fn decode_to(&mut self, index: usize, offset: u64) -> error::Result<()> {
+        let reusable = matches!(
+            &self.decoder,
+            Some(decoder) if decoder.index == index && decoder.data_start <= offset
+        );
+        if !reusable {
+            self.decoder = None;
+            self.decoder = Some(self.new_decoder(index)?);
+        }
+        loop {
+            let decoder = self.decoder.as_ref().unwrap();
+            if offset < decoder.data_start + decoder.data.len() as u64 {
+                return Ok(());
+            }
+            self.decode_chunk()?;
+        }
+    }
+
+    /// Create a decoder for the block at `index`, fed with the Stream Header
+    /// of its stream which gives the check method of the block.
+    fn new_decoder(&mut self, index: usize) -> error::Result<BlockDecoder> {
+        let block = &self.blocks[index];
+        lzma_info!("XZ seekable reader: decompressing block {:?}", block);
+        let mut header = [0; STREAM_HEADER_SIZE];
+        self.input.seek(SeekFrom::Start(block.stream_offset))?;
+        self.input.read_exact(&mut header)?;
+
+        let output = LimitWrite::new(Vec::new(), block.record.unpacked_size);
+        let mut stream = XzStream::new_with_options(&self.options, output);
+        stream.write_all(&header)?;
+        let cache_size = self
+            .options
+            .memlimit
+            .map_or(CACHE_SIZE_MAX, |memlimit| memlimit as u64);
+        Ok(BlockDecoder {
+            index,
+            stream,
+            read: 0,
+            input: Vec::new(),
+            input_pos: 0,
+            data: Vec::new(),
+            data_start: 0,
+            cache: block.record.unpacked_size <= cache_size,
+        })
+    }
+
+    /// Feed the next piece of compressed data to the decoder, reading it
+    /// from the input by chunks. Unless the whole block is cached, the
+    /// decompressed bytes of the previous pieces are discarded.
+    fn decode_chunk(&mut self) -> error::Result<()> {
+        let decoder = self.decoder.as_mut().unwrap();
+        let block = &self.blocks[decoder.index];
+        let block_size = xz_index::block_size(&block.record);
+        if decoder.input_pos == decoder.input.len() {
+            if decoder.read == block_size {
+                return Err(error::Error::XzError(format!(
+                    "Invalid index for block: decompressed to less than {} byte(s)",
+                    block.record.unpacked_size
+                )));
+            }
+            self.input
+                .seek(SeekFrom::Start(block.offset + decoder.read))?;
+            decoder.input.clear();
+            decoder.input_pos = 0;
+            (&mut self.input)
+                .take((block_size - decoder.read).min(CHUNK_SIZE))
+                .read_to_end(&mut decoder.input)?;
+            if decoder.input.is_empty() {
+                return Err(error::Error::IoError(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "input ended within an XZ block",
+                )));
+            }
+            decoder.read += decoder.input.len() as u64;
+        }
+        if !decoder.cache {
+            decoder.data_start += decoder.data.len() as u64;
+            decoder.data.clear();
+        }
+
+        let end = (decoder.input_pos + FEED_SIZE).min(decoder.input.len());
+        // The output fails once the block decompresses to more bytes than
+        // its record.
+        decoder
+            .stream
+            .write_all(&decoder.input[decoder.input_pos..end])?;
+        decoder.input_pos = end;
+        let output = decoder.stream.get_output_mut().unwrap();
+        decoder.data.append(output.get_mut());
+
+        if decoder.read == block_size
+            && decoder.input_pos == decoder.input.len()
+            && decoder.stream.records() != [block.record.clone()]
+        {
+            return Err(error::Error::XzError(format!(
+                "Invalid index for block: {:?} does not match index ({:?})",
+                decoder.stream.records(),
+                block.record
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl<R> Read for XzSeekableReader<R>
+where
+    R: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.size {
+            return Ok(0);
+        }
+        // The last block starting at or before the position holds it, as the
+        // blocks after it start further.
+        let index = self.blocks.partition_point(|block| block.start <= self.pos) - 1;
+        let offset = self.pos - self.blocks[index].start;
+        if let Err(error) = self.decode_to(index, offset) {
+            // The decoder can't go on after an error.
+            self.decoder = None;
+            return Err(error.into());
+        }
+        let decoder = self.decoder.as_ref().unwrap();
+        let n = (&decoder.data[(offset - decoder.data_start) as usize..]).read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R> Seek for XzSeekableReader<R>
+where
+    R: Read + Seek,
+{
+    /// Seek in the decompressed data. Seeking past its end is allowed, and
+    /// reading there returns no bytes.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+
+    fn stream_position(&mut self) -> io::Result<u64> {
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compress::XzOptions;
+    use crate::decode::util::{compress_blocks, lcg};
+
+    #[test]
+    fn test_read_seek() {
+        let input = include_bytes!("../../tests/files/foo.txt");
+        let compressed = compress_blocks(input, 10000);
+        let mut reader = XzSeekableReader::new(io::Cursor::new(&compressed)).unwrap();
+        assert_eq!(reader.decompressed_size(), input.len() as u64);
+
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(&output[..], &input[..]);
+
+        // Ranges within a block, across blocks and at the end.
+        for &(pos, len) in &[(123_456, 100), (9990, 20), (5, 30_000), (173_500, 95)] {
+            let mut buf = vec![0; len];
+            assert_eq!(reader.seek(SeekFrom::Start(pos)).unwrap(), pos);
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &input[pos as usize..pos as usize + len]);
+        }
+
+        assert_eq!(reader.seek(SeekFrom::Current(-100)).unwrap(), 173_495);
+        assert_eq!(reader.seek(SeekFrom::End(-10)).unwrap(), 173_585);
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(&output[..], &input[input.len() - 10..]);
+
+        assert_eq!(reader.seek(SeekFrom::End(10)).unwrap(), 173_605);
+        assert_eq!(reader.read(&mut [0; 10]).unwrap(), 0);
+        let err = reader.seek(SeekFrom::Current(-200_000)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_streams() {
+        let input = include_bytes!("../../tests/files/foo.txt");
+        // Leading data is skipped by starting at the current position.
+        let mut compressed = b"head".to_vec();
+        compressed.extend_from_slice(&compress_blocks(&input[..1000], 300));
+        compressed.extend_from_slice(&[0; 4]);
+        let mut empty = Vec::new();
+        crate::xz_compress(&mut &b""[..], &mut empty).unwrap();
+        compressed.extend_from_slice(&empty);
+        compressed.extend_from_slice(&compress_blocks(&input[1000..], 50_000));
+
+        let mut cursor = io::Cursor::new(&compressed);
+        cursor.set_position(4);
+        let mut reader = XzSeekableReader::new(cursor).unwrap();
+        let mut buf = vec![0; 2000];
+        reader.seek(SeekFrom::Start(500)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &input[500..2500]);
+    }
+
+    #[test]
+    fn test_memlimit() {
+        // Hardly compressible data, in a single block of many LZMA2 chunks
+        // with a 256 KiB dictionary.
+        let mut next = lcg(1);
+        let input: Vec<u8> = (0..1_000_000)
+            .map(|_| b'a' + (next() >> 16) as u8 % 26)
+            .collect();
+        let options = XzOptions {
+            lzma2: crate::compress::Options::preset(0, false).unwrap(),
+            ..Default::default()
+        };
+        let mut compressed = Vec::new();
+        crate::xz_compress_with_options(&mut &input[..], &mut compressed, &options).unwrap();
+
+        let options = Options {
+            memlimit: Some(300_000),
+            ..Default::default()
+        };
+        let mut reader =
+            XzSeekableReader::new_with_options(io::Cursor::new(&compressed), &options).unwrap();
+        let mut buf = vec![0; 1000];
+        for &pos in &[800_000, 300_000] {
+            reader.seek(SeekFrom::Start(pos)).unwrap();
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &input[pos as usize..pos as usize + 1000]);
+            // The block doesn't fit within the memory limit, so only the
+            // bytes flushed from the dictionary by the last piece are kept.
+            let decoder = reader.decoder.as_ref().unwrap();
+            assert!(!decoder.cache);
+            assert!(decoder.data_start > 0);
+            assert!(decoder.data.len() <= 256 << 10, "{}", decoder.data.len());
+        }
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(&output[..], &input[301_000..]);
+
+        // Without a memory limit, the block is kept in memory.
+        let mut reader = XzSeekableReader::new(io::Cursor::new(&compressed)).unwrap();
+        for &pos in &[800_000, 300_000] {
+            reader.seek(SeekFrom::Start(pos)).unwrap();
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &input[pos as usize..pos as usize + 1000]);
+        }
+        let decoder = reader.decoder.as_ref().unwrap();
+        assert!(decoder.cache);
+        assert_eq!(decoder.data_start, 0);
+        assert!(decoder.data.len() >= 801_000);
+    }
+
+    #[test]
+    fn test_corrupted_block() {
+        let input = include_bytes!("../../tests/files/foo.txt");
+        let mut compressed = compress_blocks(input, 10000);
+        let pos = compressed.len() / 2;
+        compressed[pos] ^= 0x01;
+
+        let mut reader = XzSeekableReader::new(io::Cursor::new(&compressed)).unwrap();
+        let mut buf = vec![0; 100];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &input[..100]);
+        reader
+            .seek(SeekFrom::Start(input.len() as u64 / 2))
+            .unwrap();
+        // The corrupted block is only detected once read up to its check.
+        reader.read_to_end(&mut Vec::new()).unwrap_err();
+    }
+}
@@ -0,0 +1,566 @@
+use crate::decode::lzma2_stream::ChunkDecoder;
+use crate::decode::util::{self, CheckCountWrite, FilterWrite};
+use crate::decode::xz::{self, BlockHeader, Record};
+use crate::decompress::Options;
+use crate::error::Error;
+use crate::xz::header::StreamHeader;
+use crate::xz::{Check, StreamFlags};
+use byteorder::ReadBytesExt;
+use std::fmt::Debug;
+use std::io::{self, Write};
+
+/// Size of the Stream Header and of the Stream Footer.
+const STREAM_HEADER_SIZE: usize = 12;
+
+/// Internal state of this streaming decoder, i.e. the next field of the XZ
+/// format to decode.
+enum State<W>
+where
+    W: Write,
+{
+    /// Expecting a Stream Header.
+    StreamHeader(W),
+    /// Expecting a Block Header, or the Index.
+    BlockHeader(W),
+    /// Decoding the LZMA2 chunks of a block, and its other filters.
+    Block(Box<BlockState<W>>),
+    /// Expecting the Block Padding and the Block Check.
+    BlockCheck {
+        output: W,
+        check: Check,
+        /// Size of the block up to the padding.
+        count: usize,
+        unpacked_size: usize,
+    },
+    /// Expecting the Index.
+    Index(W),
+    /// Expecting the Stream Footer.
+    StreamFooter(W),
+    /// After a stream, expecting Stream Padding or another stream. The number
+    /// of padding bytes is kept to validate it.
+    StreamPadding(W, usize),
+    /// After the first stream, when only decoding a single stream.
+    Finished(W),
+}
+
+/// Structures needed while decoding a block.
+struct BlockState<W>
+where
+    W: Write,
+{
+    header: BlockHeader,
+    /// Size of the Block Header.
+    header_size: usize,
+    chunks: ChunkDecoder<FilterWrite<CheckCountWrite<W>>>,
+}
+
+impl<W> State<W>
+where
+    W: Write,
+{
+    fn name(&self) -> &'static str {
+        match self {
+            State::StreamHeader(_) => "StreamHeader",
+            State::BlockHeader(_) => "BlockHeader",
+            State::Block(_) => "Block",
+            State::BlockCheck { .. } => "BlockCheck",
+            State::Index(_) => "Index",
+            State::StreamFooter(_) => "StreamFooter",
+            State::StreamPadding(..) => "StreamPadding",
+            State::Finished(_) => "Finished",
+        }
+    }
+}
+
+/// XZ decompressor that can process multiple chunks of data using the
+/// [`io::Write`] interface.
+///
+/// Each field of the format is buffered until it is complete, so that at most
+/// one LZMA2 chunk (64 KiB) is buffered besides the dictionary.
+#[cfg_attr(docsrs, doc(cfg(stream)))]
+pub struct XzStream<W>
+where
+    W: Write,
+{
+    /// Temporary buffer to hold the field being read.
+    buf: Vec<u8>,
+    /// Internal state, or [`None`] after an error.
+    state: Option<State<W>>,
+    /// Stream Flags of the current stream.
+    stream_flags: Option<StreamFlags>,
+    /// Records of the blocks of the current stream, to validate the Index.
+    records: Vec<Record>,
+    /// Size of the Index of the current stream.
+    index_size: usize,
+    /// Options given when a stream is created.
+    options: Options,
+}
+
+impl<W> XzStream<W>
+where
+    W: Write,
+{
+    /// Initialize the stream. This will consume the `output` which is the sink
+    /// implementing [`io::Write`] that will receive decompressed bytes.
+    pub fn new(output: W) -> Self {
+        Self::new_with_options(&Options::default(), output)
+    }
+
+    /// Initialize the stream with the given `options`. This will consume the
+    /// `output` which is the sink implementing [`io::Write`] that will
+    /// receive decompressed bytes.
+    pub fn new_with_options(options: &Options, output: W) -> Self {
+        Self {
+            buf: Vec::new(),
+            state: Some(State::StreamHeader(output)),
+            stream_flags: None,
+            records: Vec::new(),
+            index_size: 0,
+            options: *options,
+        }
+    }
+
+    /// Get a reference to the output sink.
+    pub fn get_output(&self) -> Option<&W> {
+        self.state.as_ref().map(|state| match state {
+            State::StreamHeader(output)
+            | State::BlockHeader(output)
+            | State::BlockCheck { output, .. }
+            | State::Index(output)
+            | State::StreamFooter(output)
+            | State::StreamPadding(output, _)
+            | State::Finished(output) => output,
+            State::Block(block) => block.chunks.get_output().get_ref().get_ref(),
+        })
+    }
+
+    /// Get a mutable reference to the output sink.
+    pub fn get_output_mut(&mut self) -> Option<&mut W> {
+        self.state.as_mut().map(|state| match state {
+            State::StreamHeader(output)
+            | State::BlockHeader(output)
+            | State::BlockCheck { output, .. }
+            | State::Index(output)
+            | State::StreamFooter(output)
+            | State::StreamPadding(output, _)
+            | State::Finished(output) => output,
+            State::Block(block) => block.chunks.get_output_mut().get_mut().get_mut(),
+        })
+    }
+
+    /// Records of the blocks decoded so far in the current stream.
+    pub(crate) fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    /// Consumes the stream and returns the output sink. This also makes sure
+    /// we have properly reached the end of the stream.
+    pub fn finish(mut self) -> crate::error::Result<W> {
+        let mut output = match self.state.take() {
+            Some(State::StreamPadding(output, padding_size)) => {
+                check_stream_padding(padding_size)?;
+                output
+            }
+            Some(State::Finished(output)) => output,
+            Some(State::Block(block)) if self.options.allow_incomplete => {
+                let (output, _) = block.chunks.finish_incomplete()?.finish()?.into_inner();
+                output
+            }
+            Some(State::StreamHeader(output))
+            | Some(State::BlockHeader(output))
+            | Some(State::BlockCheck { output, .. })
+            | Some(State::Index(output))
+            | Some(State::StreamFooter(output))
+                if self.options.allow_incomplete =>
+            {
+                output
+            }
+            Some(state) => {
+                return Err(Error::XzError(format!(
+                    "Unexpected end of input, expected {} after {} byte(s)",
+                    state.name(),
+                    self.buf.len()
+                )));
+            }
+            // this will occur if a call to `write()` fails
+            None => {
+                return Err(Error::XzError(
+                    "can't finish stream because of previous write error".to_string(),
+                ));
+            }
+        };
+        output.flush()?;
+        Ok(output)
+    }
+
+    /// Move bytes from the input to the temporary buffer until it holds
+    /// `len` bytes. Returns false if the input doesn't have enough bytes.
+    fn fill(&mut self, input: &mut &[u8], len: usize) -> bool {
+        let n = std::cmp::min(len.saturating_sub(self.buf.len()), input.len());
+        self.buf.extend_from_slice(&input[..n]);
+        *input = &input[n..];
+        self.buf.len() >= len
+    }
+
+    /// Decode as much of the input as possible, and return the next state.
+    fn process(&mut self, state: State<W>, input: &mut &[u8]) -> crate::error::Result<State<W>> {
+        let mut state = state;
+        loop {
+            let (next, need_input) = self.process_next(state, input)?;
+            state = next;
+            if need_input {
+                return Ok(state);
+            }
+        }
+    }
+
+    /// Decode the next field of the format. Returns the next state, and
+    /// whether more input is needed to make progress from it.
+    fn process_next(
+        &mut self,
+        state: State<W>,
+        input: &mut &[u8],
+    ) -> crate::error::Result<(State<W>, bool)> {
+        let next = match state {
+            State::StreamHeader(output) => {
+                if !self.fill(input, STREAM_HEADER_SIZE) {
+                    return Ok((State::StreamHeader(output), true));
+                }
+                let header = StreamHeader::parse(&mut self.buf.as_slice())?;
+                self.stream_flags = Some(header.stream_flags);
+                self.records.clear();
+                State::BlockHeader(output)
+            }
+            State::BlockHeader(output) => {
+                if !self.fill(input, 1) {
+                    return Ok((State::BlockHeader(output), true));
+                }
+                let header_size = self.buf[0];
+                if header_size == 0 {
+                    // This is the Index Indicator, which is part of the Index.
+                    return Ok((State::Index(output), false));
+                }
+
+                let len = (header_size as usize + 1) << 2;
+                if !self.fill(input, len) {
+                    return Ok((State::BlockHeader(output), true));
+                }
+                let header = xz::parse_block_header(&mut &self.buf[1..], header_size)?;
+                let (decoder, filters) = xz::block_decoder(&header, &self.options)?;
+                let check = Check::new(self.stream_flags().check_method);
+                let output = FilterWrite::new(CheckCountWrite::new(output, check), filters);
+                State::Block(Box::new(BlockState {
+                    header,
+                    header_size: len,
+                    chunks: ChunkDecoder::new(decoder, output),
+                }))
+            }
+            State::Block(mut block) => {
+                if !block.chunks.process(input)? {
+                    return Ok((State::Block(block), true));
+                }
+
+                let block = *block;
+                let packed_size = block.chunks.packed_size();
+                let output = block.chunks.finish()?.finish()?;
+                let unpacked_size = output.count();
+                let (output, check) = output.into_inner();
+                lzma_info!("XZ block decompressed to {} byte(s)", unpacked_size);
+                xz::check_block_sizes(&block.header, packed_size, unpacked_size)?;
+                State::BlockCheck {
+                    output,
+                    check,
+                    count: block.header_size + packed_size,
+                    unpacked_size,
+                }
+            }
+            State::BlockCheck {
+                output,
+                check,
+                count,
+                unpacked_size,
+            } => {
+                let check_size = self.stream_flags().check_method.size();
+                if !self.fill(input, xz::padding_size(count) + check_size) {
+                    return Ok((
+                        State::BlockCheck {
+                            output,
+                            check,
+                            count,
+                            unpacked_size,
+                        },
+                        true,
+                    ));
+                }
+                let mut buf = self.buf.as_slice();
+                xz::read_block_padding(&mut buf, count)?;
+                xz::validate_block_check(&mut buf, check)?;
+                self.records.push(Record {
+                    unpadded_size: (count + check_size) as u64,
+                    unpacked_size: unpacked_size as u64,
+                });
+                State::BlockHeader(output)
+            }
+            State::Index(output) => {
+                // The Index must describe the blocks that were decoded, which
+                // gives its size.
+                let len = index_size(&self.records);
+                if !self.fill(input, len) {
+                    return Ok((State::Index(output), true));
+                }
+                lzma_info!("XZ records: {:?}", self.records);
+                let mut buf = self.buf.as_slice();
+                let mut count_input = util::CountBufRead::new(&mut buf);
+                count_input.read_u8()?;
+                xz::check_index(&mut count_input, &self.records)?;
+                self.index_size = count_input.count();
+                State::StreamFooter(output)
+            }
+            State::StreamFooter(output) => {
+                if !self.fill(input, STREAM_HEADER_SIZE) {
+                    return Ok((State::StreamFooter(output), true));
+                }
+                xz::check_footer(
+                    &mut self.buf.as_slice(),
+                    self.stream_flags(),
+                    self.index_size,
+                )?;
+                if self.options.single_stream {
+                    State::Finished(output)
+                } else {
+                    State::StreamPadding(output, 0)
+                }
+            }
+            State::StreamPadding(output, padding_size) => {
+                let n = input.iter().take_while(|&&x| x == 0).count();
+                *input = &input[n..];
+                let padding_size = padding_size + n;
+                if input.is_empty() {
+                    return Ok((State::StreamPadding(output, padding_size), true));
+                }
+                check_stream_padding(padding_size)?;
+                lzma_info!("XZ stream padding: {} byte(s)", padding_size);
+                State::StreamHeader(output)
+            }
+            // Any remaining input is left unprocessed.
+            State::Finished(output) => return Ok((State::Finished(output), true)),
+        };
+        self.buf.clear();
+        Ok((next, false))
+    }
+
+    fn stream_flags(&self) -> StreamFlags {
+        self.stream_flags
+            .expect("the Stream Header is decoded before the rest of the stream")
+    }
+}
+
+/// Size of the Index describing the given blocks, see sect. 4.
+fn index_size(records: &[Record]) -> usize {
+    let count = 1
+        + multibyte_size(records.len() as u64)
+        + records
+            .iter()
+            .map(|record| {
+                multibyte_size(record.unpadded_size) + multibyte_size(record.unpacked_size)
+            })
+            .sum::<usize>();
+    count + xz::padding_size(count) + 4
+}
+
+/// Size of a variable-length integer, see sect. 1.2.
+fn multibyte_size(value: u64) -> usize {
+    let bits = 64 - value.leading_zeros() as usize;
+    std::cmp::max(1, (bits + 6) / 7)
+}
+
+fn check_stream_padding(padding_size: usize) -> crate::error::Result<()> {
+    if padding_size % 4 != 0 {
+        return Err(Error::XzError(format!(
+            "Invalid stream padding of {} byte(s), must be a multiple of 4",
+            padding_size
+        )));
+    }
+    Ok(())
+}
+
+impl<W> Debug for XzStream<W>
+where
+    W: Write + Debug,
+{
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("XzStream")
+            .field("buf", &self.buf.len())
+            .field("state", &self.state.as_ref().map(State::name))
+            .field("output", &self.get_output())
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+impl<W> Write for XzStream<W>
+where
+    W: Write,
+{
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let state = self.state.take().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                "can't write to stream because of previous write error",
+            )
+        })?;
+        let mut input = data;
+        let state = self.process(state, &mut input)?;
+        self.state = Some(state);
+        Ok(data.len() - input.len())
+    }
+
+    /// Flushes the output sink. The internal buffer isn't flushed to avoid
+    /// corrupting the internal state. Instead, call [`Self::finish()`] to
+    /// finalize the stream and flush all remaining internal data.
+    fn flush(&mut self) -> io::Result<()> {
+        match self.get_output_mut() {
+            Some(output) => output.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn xz_compress(x: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        crate::xz_compress(&mut io::Cursor::new(x), &mut compressed).unwrap();
+        compressed
+    }
+
+    #[test]
+    fn test_multibyte_size() {
+        assert_eq!(multibyte_size(0), 1);
+        assert_eq!(multibyte_size(0x7F), 1);
+        assert_eq!(multibyte_size(0x80), 2);
+        assert_eq!(multibyte_size(0x3FFF), 2);
+        assert_eq!(multibyte_size(0x4000), 3);
+        assert_eq!(multibyte_size(u64::MAX >> 1), 9);
+    }
+
+    /// Test an empty stream
+    #[test]
+    fn test_stream_noop() {
+        let stream = XzStream::new(Vec::new());
+        assert!(stream.get_output().unwrap().is_empty());
+
+        let err = stream.finish().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "xz error: Unexpected end of input, expected StreamHeader after 0 byte(s)"
+        );
+    }
+
+    /// Test processing all chunk sizes
+    #[test]
+    fn test_stream_chunked() {
+        let small_input = include_bytes!("../../tests/files/small.txt");
+        let mut input = xz_compress(small_input);
+        input.extend_from_slice(&[0; 8]);
+        input.extend_from_slice(&xz_compress(b""));
+
+        for chunk in 1..input.len() {
+            let mut stream = XzStream::new(Vec::new());
+            for data in input.chunks(chunk) {
+                stream.write_all(data).unwrap();
+            }
+            let output = stream.finish().unwrap();
+            assert_eq!(&output[..], &small_input[..]);
+        }
+    }
+
+    #[test]
+    fn test_stream_incomplete() {
+        let small_input = include_bytes!("../../tests/files/small.txt");
+        let input = xz_compress(small_input);
+
+        for end in 0..input.len() {
+            let mut stream = XzStream::new(Vec::new());
+            stream.write_all(&input[..end]).unwrap();
+            let err = stream.finish().unwrap_err();
+            assert!(
+                err.to_string().contains("Unexpected end of input"),
+                "error was: {}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_stream_corrupted() {
+        let mut stream = XzStream::new(Vec::new());
+        let err = stream
+            .write_all(b"corrupted bytes here corrupted bytes here")
+            .unwrap_err();
+        assert!(err.to_string().contains("Invalid XZ magic"), "{}", err);
+        let err = stream.finish().unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("can\'t finish stream because of previous write error"));
+    }
+
+    #[test]
+    fn test_invalid_stream_padding() {
+        let mut input = xz_compress(b"Hello");
+        input.extend_from_slice(&[0; 3]);
+
+        let mut stream = XzStream::new(Vec::new());
+        stream.write_all(&input).unwrap();
+        let err = stream.finish().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "xz error: Invalid stream padding of 3 byte(s), must be a multiple of 4"
+        );
+    }
+
+    #[test]
+    fn test_single_stream() {
+        let mut input = xz_compress(b"Hello world");
+        let stream_size = input.len();
+        input.extend_from_slice(b"trailing data");
+
+        let mut stream = XzStream::new_with_options(
+            &Options {
+                single_stream: true,
+                ..Default::default()
+            },
+            Vec::new(),
+        );
+        assert_eq!(stream.write(&input).unwrap(), stream_size);
+        assert_eq!(stream.write(&input[stream_size..]).unwrap(), 0);
+        let output = stream.finish().unwrap();
+        assert_eq!(output, b"Hello world");
+    }
+
+    #[test]
+    fn test_allow_incomplete() {
+        let input = vec![0x42; 0x10000];
+        let compressed = xz_compress(&input);
+        let compressed = &compressed[..compressed.len() - 20];
+
+        // Should fail to finish() without the allow_incomplete option.
+        let mut stream = XzStream::new(Vec::new());
+        stream.write_all(compressed).unwrap();
+        stream.finish().unwrap_err();
+
+        // Should succeed with the allow_incomplete option.
+        let mut stream = XzStream::new_with_options(
+            &Options {
+                allow_incomplete: true,
+                ..Default::default()
+            },
+            Vec::new(),
+        );
+        stream.write_all(compressed).unwrap();
+        let output = stream.finish().unwrap();
+        assert_eq!(output, input);
+    }
+}
@@ -0,0 +1,614 @@
+//! Match finders, shared by the LZMA and LZMA2 encoders.
+//!
+//! These follow the hash chain and binary tree match finders of liblzma.
+
+use crate::encode::options::{self, Options};
+use std::cmp;
+use std::convert::TryInto;
+
+/// Minimum length of a match.
+pub(crate) const MATCH_LEN_MIN: usize = 2;
+
+/// Maximum length of a match.
+pub(crate) const MATCH_LEN_MAX: usize = 273;
+
+/// Number of entries of the hash table of the first 2 bytes.
+const HASH_2_SIZE: usize = 1 << 10;
+
+/// Number of entries of the hash table of the first 3 bytes.
+const HASH_3_SIZE: usize = 1 << 16;
+
+/// CRC32 table, used to spread the bits of the first byte in the hashes.
+///
+/// The hashes of the first 2 and 3 bytes are computed so that two sequences
+/// with the same first byte and the same hash are equal.
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// A match found in the dictionary.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Match {
+    /// Length of the match.
+    pub len: usize,
+    /// Distance of the match, minus one.
+    pub dist: usize,
+}
+
+/// Sliding window over the input, indexed by a match finder.
+pub(crate) struct MatchFinder {
+    /// Window data, including the bytes ahead of the current position.
+    pub buf: Vec<u8>,
+    /// Position in `buf` of the next byte to insert in the match finder.
+    pub read_pos: usize,
+    /// Number of positions inserted in the match finder but not yet encoded.
+    pub read_ahead: usize,
+    /// Value added to a position in `buf` to obtain the position stored in
+    /// the hash tables and in `son`. Stored positions that are more than
+    /// `cyclic_size` behind the current one are not valid, which includes
+    /// the empty value 0.
+    offset: u32,
+    /// Maximum distance of a match.
+    dict_size: usize,
+    /// Number of bytes to keep ahead of the current position when
+    /// discarding old data.
+    keep_ahead: usize,
+    /// Whether positions are organized in binary trees rather than in hash
+    /// chains.
+    is_bt: bool,
+    /// Number of bytes hashed in `head`.
+    hash_bytes: usize,
+    /// Most recent position of each hash of the first 2 bytes, if
+    /// `hash_bytes` is larger.
+    hash2: Vec<u32>,
+    /// Most recent position of each hash of the first 3 bytes, if
+    /// `hash_bytes` is larger.
+    hash3: Vec<u32>,
+    /// Most recent position of each hash of the first `hash_bytes` bytes.
+    head: Vec<u32>,
+    hash_mask: u32,
+    /// Previous position with the same hash for hash chains, or children of
+    /// the position for binary trees, indexed cyclically by position.
+    son: Vec<u32>,
+    /// Index in `son` of the position `read_pos`.
+    cyclic_pos: usize,
+    cyclic_size: usize,
+    /// Matches of at least this length are taken without looking further.
+    pub nice_len: usize,
+    /// Maximum number of candidates visited at each position.
+    depth: u32,
+}
+
+impl MatchFinder {
+    pub fn new(options: &Options, keep_ahead: usize) -> Self {
+        let kind = options.match_finder;
+        let hash_bytes = kind.hash_bytes();
+        let is_bt = kind.is_bt();
+        let nice_len = cmp::max(options.nice_len as usize, hash_bytes);
+        let depth = match options.depth {
+            0 if is_bt => 16 + nice_len as u32 / 2,
+            0 => 4 + nice_len as u32 / 4,
+            depth => depth,
+        };
+        let hash_mask = hash_mask(kind, options.dict_size);
+        let cyclic_size = options.dict_size as usize + 1;
+
+        Self {
+            buf: Vec::new(),
+            read_pos: 0,
+            read_ahead: 0,
+            offset: cyclic_size as u32,
+            dict_size: options.dict_size as usize,
+            keep_ahead,
+            is_bt,
+            hash_bytes,
+            hash2: vec![0; if hash_bytes > 2 { HASH_2_SIZE } else { 0 }],
+            hash3: vec![0; if hash_bytes > 3 { HASH_3_SIZE } else { 0 }],
+            head: vec![0; hash_mask as usize + 1],
+            hash_mask,
+            son: vec![0; if is_bt { 2 * cyclic_size } else { cyclic_size }],
+            cyclic_pos: 0,
+            cyclic_size,
+            nice_len,
+            depth,
+        }
+    }
+
+    /// Approximate memory usage in bytes of a match finder with the given
+    /// options.
+    pub fn memusage(options: &Options, keep_ahead: usize) -> u64 {
+        let kind = options.match_finder;
+        let hash_bytes = kind.hash_bytes();
+        let cyclic_size = options.dict_size as u64 + 1;
+
+        let mut entries = hash_mask(kind, options.dict_size) as u64 + 1;
+        if hash_bytes > 2 {
+            entries += HASH_2_SIZE as u64;
+        }
+        if hash_bytes > 3 {
+            entries += HASH_3_SIZE as u64;
+        }
+        entries += if kind.is_bt() {
+            2 * cyclic_size
+        } else {
+            cyclic_size
+        };
+
+        // The window keeps up to 1.5 times the dictionary size behind the
+        // current position, see `append`.
+        let window = options.dict_size as u64 * 3 / 2 + keep_ahead as u64;
+        entries * 4 + window
+    }
+
+    /// Number of bytes available from the next position to insert.
+    pub fn avail(&self) -> usize {
+        self.buf.len() - self.read_pos
+    }
+
+    /// Append input data to the window, discarding data that is too far
+    /// behind to be referenced by a match.
+    pub fn append(&mut self, data: &[u8]) {
+        let keep_from = self
+            .read_pos
+            .saturating_sub(self.dict_size + self.keep_ahead);
+        if keep_from > 0 && keep_from >= self.dict_size / 2 {
+            self.buf.drain(..keep_from);
+            self.read_pos -= keep_from;
+            self.offset = self.offset.wrapping_add(keep_from as u32);
+        }
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Position of `read_pos`, as stored in the hash tables and in `son`.
+    fn pos(&self) -> u32 {
+        (self.read_pos as u32).wrapping_add(self.offset)
+    }
+
+    /// Subtract a constant from all stored positions before they overflow.
+    /// Positions that become invalid are reset to 0.
+    fn normalize(&mut self) {
+        let sub = self.pos() - self.cyclic_size as u32;
+        for x in self
+            .hash2
+            .iter_mut()
+            .chain(self.hash3.iter_mut())
+            .chain(self.head.iter_mut())
+            .chain(self.son.iter_mut())
+        {
+            *x = x.saturating_sub(sub);
+        }
+        self.offset = self.offset.wrapping_sub(sub);
+    }
+
+    fn move_pos(&mut self) {
+        self.read_pos += 1;
+        self.read_ahead += 1;
+        self.cyclic_pos += 1;
+        if self.cyclic_pos == self.cyclic_size {
+            self.cyclic_pos = 0;
+        }
+        if self.pos() == u32::MAX {
+            self.normalize();
+        }
+    }
+
+    /// Index in `son` of the position `delta` bytes behind the current one,
+    /// divided by 2 for binary trees.
+    fn cyclic_index(&self, delta: usize) -> usize {
+        if delta > self.cyclic_pos {
+            self.cyclic_pos + self.cyclic_size - delta
+        } else {
+            self.cyclic_pos - delta
+        }
+    }
+
+    /// Insert the current position in the hash tables. Returns the previous
+    /// positions with the same hash of the first 2 bytes, of the first 3
+    /// bytes, and of the first `hash_bytes` bytes.
+    fn insert_hashes(&mut self) -> (u32, u32, u32) {
+        let pos = self.pos();
+        let cur = &self.buf[self.read_pos..];
+
+        let mut prev2 = 0;
+        let mut prev3 = 0;
+        let hash = if self.hash_bytes == 2 {
+            cur[0] as u32 | (cur[1] as u32) << 8
+        } else {
+            let temp = CRC_TABLE[cur[0] as usize] ^ cur[1] as u32;
+            let hash2 = temp as usize & (HASH_2_SIZE - 1);
+            prev2 = std::mem::replace(&mut self.hash2[hash2], pos);
+
+            let temp = temp ^ (cur[2] as u32) << 8;
+            if self.hash_bytes == 3 {
+                temp & self.hash_mask
+            } else {
+                let hash3 = temp as usize & (HASH_3_SIZE - 1);
+                prev3 = std::mem::replace(&mut self.hash3[hash3], pos);
+                (temp ^ CRC_TABLE[cur[3] as usize] << 5) & self.hash_mask
+            }
+        };
+        let prev = std::mem::replace(&mut self.head[hash as usize], pos);
+
+        (prev2, prev3, prev)
+    }
+
+    /// Length of the match at `delta` bytes behind `cur`, given that the
+    /// first `start` bytes are equal.
+    fn match_len(&self, cur: usize, delta: usize, start: usize, limit: usize) -> usize {
+        start
+            + common_prefix(
+                &self.buf[cur - delta + start..],
+                &self.buf[cur + start..cur + limit],
+            )
+    }
+
+    /// Find the matches at the current position, and move to the next
+    /// position.
+    ///
+    /// Matches are sorted by increasing length, and each one has the smallest
+    /// distance found for its length. Returns the length of the longest
+    /// match, or 0 if there is none.
+    pub fn find(&mut self, matches: &mut Vec<Match>) -> usize {
+        matches.clear();
+
+        let avail = self.avail();
+        if avail < self.hash_bytes {
+            self.move_pos();
+            return 0;
+        }
+
+        let cur = self.read_pos;
+        let pos = self.pos();
+        let len_limit = cmp::min(avail, self.nice_len);
+        let (prev2, prev3, cur_match) = self.insert_hashes();
+        let delta2 = pos.wrapping_sub(prev2) as usize;
+        let delta3 = pos.wrapping_sub(prev3) as usize;
+
+        // Matches found with the hashes of the first bytes are only checked
+        // on the first byte, as the hashes then imply that the next bytes
+        // are equal.
+        let mut len_best = 1;
+        match self.hash_bytes {
+            2 => (),
+            3 => {
+                len_best = 2;
+                if delta2 < self.cyclic_size && self.buf[cur - delta2] == self.buf[cur] {
+                    len_best = self.match_len(cur, delta2, 2, len_limit);
+                    matches.push(Match {
+                        len: len_best,
+                        dist: delta2 - 1,
+                    });
+                }
+            }
+            _ => {
+                let mut delta = 0;
+                if delta2 < self.cyclic_size && self.buf[cur - delta2] == self.buf[cur] {
+                    len_best = 2;
+                    delta = delta2;
+                    matches.push(Match {
+                        len: 2,
+                        dist: delta2 - 1,
+                    });
+                }
+                if delta2 != delta3
+                    && delta3 < self.cyclic_size
+                    && self.buf[cur - delta3] == self.buf[cur]
+                {
+                    len_best = 3;
+                    delta = delta3;
+                    matches.push(Match {
+                        len: 3,
+                        dist: delta3 - 1,
+                    });
+                }
+                if !matches.is_empty() {
+                    len_best = self.match_len(cur, delta, len_best, len_limit);
+                    matches.last_mut().unwrap().len = len_best;
+                }
+                len_best = cmp::max(len_best, 3);
+            }
+        }
+
+        if len_best == len_limit {
+            // Insert the position without looking for longer matches.
+            if self.is_bt {
+                self.bt_insert(len_limit, cur_match, None, len_best);
+            } else {
+                self.son[self.cyclic_pos] = cur_match;
+            }
+        } else if self.is_bt {
+            self.bt_insert(len_limit, cur_match, Some(matches), len_best);
+        } else {
+            self.hc_insert(len_limit, cur_match, matches, len_best);
+        }
+        self.move_pos();
+
+        // Extend the longest match beyond the nice length.
+        match matches.last_mut() {
+            None => 0,
+            Some(m) => {
+                if m.len == self.nice_len {
+                    let limit = cmp::min(avail, MATCH_LEN_MAX);
+                    m.len = self.match_len(cur, m.dist + 1, m.len, limit);
+                }
+                m.len
+            }
+        }
+    }
+
+    /// Move forward by `count` positions, keeping the match finder
+    /// up-to-date.
+    pub fn skip(&mut self, count: usize) {
+        for _ in 0..count {
+            let avail = self.avail();
+            if avail >= self.hash_bytes {
+                let len_limit = cmp::min(avail, self.nice_len);
+                let (_, _, cur_match) = self.insert_hashes();
+                if self.is_bt {
+                    self.bt_insert(len_limit, cur_match, None, 0);
+                } else {
+                    self.son[self.cyclic_pos] = cur_match;
+                }
+            }
+            self.move_pos();
+        }
+    }
+
+    /// Insert the current position at the head of its hash chain, and
+    /// collect the matches longer than `len_best` along the chain.
+    fn hc_insert(
+        &mut self,
+        len_limit: usize,
+        mut cur_match: u32,
+        matches: &mut Vec<Match>,
+        mut len_best: usize,
+    ) {
+        let cur = self.read_pos;
+        let pos = self.pos();
+        self.son[self.cyclic_pos] = cur_match;
+
+        for _ in 0..self.depth {
+            let delta = pos.wrapping_sub(cur_match) as usize;
+            if delta >= self.cyclic_size {
+                break;
+            }
+
+            let match_pos = cur - delta;
+            cur_match = self.son[self.cyclic_index(delta)];
+            if self.buf[match_pos + len_best] == self.buf[cur + len_best]
+                && self.buf[match_pos] == self.buf[cur]
+            {
+                let len = self.match_len(cur, delta, 1, len_limit);
+                if len > len_best {
+                    len_best = len;
+                    matches.push(Match {
+                        len,
+                        dist: delta - 1,
+                    });
+                    if len == len_limit {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Insert the current position at the root of the binary tree of its
+    /// hash, and collect the matches longer than `len_best` along the way if
+    /// `matches` is provided.
+    ///
+    /// The tree is sorted by the data following each position, so that
+    /// moving the current position to the root only requires to split the
+    /// old tree into smaller and larger positions.
+    fn bt_insert(
+        &mut self,
+        len_limit: usize,
+        mut cur_match: u32,
+        mut matches: Option<&mut Vec<Match>>,
+        mut len_best: usize,
+    ) {
+        let cur = self.read_pos;
+        let pos = self.pos();
+        // Where to attach the next position smaller or larger than the
+        // current one.
+        let mut ptr0 = (self.cyclic_pos << 1) + 1;
+        let mut ptr1 = self.cyclic_pos << 1;
+        // Length of the common prefix with all the positions attached to
+        // `ptr0` and `ptr1` respectively.
+        let mut len0 = 0;
+        let mut len1 = 0;
+
+        let mut depth = self.depth;
+        loop {
+            let delta = pos.wrapping_sub(cur_match) as usize;
+            if depth == 0 || delta >= self.cyclic_size {
+                self.son[ptr0] = 0;
+                self.son[ptr1] = 0;
+                return;
+            }
+            depth -= 1;
+
+            let pair = self.cyclic_index(delta) << 1;
+            let match_pos = cur - delta;
+            let mut len = cmp::min(len0, len1);
+            if self.buf[match_pos + len] == self.buf[cur + len] {
+                len = self.match_len(cur, delta, len + 1, len_limit);
+                if len > len_best {
+                    len_best = len;
+                    if let Some(matches) = matches.as_mut() {
+                        matches.push(Match {
+                            len,
+                            dist: delta - 1,
+                        });
+                    }
+                }
+                if len == len_limit {
+                    self.son[ptr1] = self.son[pair];
+                    self.son[ptr0] = self.son[pair + 1];
+                    return;
+                }
+            }
+
+            if self.buf[match_pos + len] < self.buf[cur + len] {
+                self.son[ptr1] = cur_match;
+                ptr1 = pair + 1;
+                cur_match = self.son[ptr1];
+                len1 = len;
+            } else {
+                self.son[ptr0] = cur_match;
+                ptr0 = pair;
+                cur_match = self.son[ptr0];
+                len0 = len;
+            }
+        }
+    }
+}
+
+/// Mask of the hash of the first bytes, depending on the dictionary size.
+fn hash_mask(kind: options::MatchFinder, dict_size: u32) -> u32 {
+    if kind.hash_bytes() == 2 {
+        return 0xFFFF;
+    }
+
+    let mut mask = dict_size - 1;
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+    mask >>= 1;
+    mask |= 0xFFFF;
+    if mask > 1 << 24 {
+        if kind.hash_bytes() == 3 {
+            mask = (1 << 24) - 1;
+        } else {
+            mask >>= 1;
+        }
+    }
+    mask
+}
+
+/// Length of the common prefix of two slices.
+pub(crate) fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    let len = cmp::min(a.len(), b.len());
+    let mut i = 0;
+    // Compare 8 bytes at a time, locating the first difference within a word
+    // from its lowest non-zero bit.
+    while i + 8 <= len {
+        let x = u64::from_le_bytes(a[i..i + 8].try_into().unwrap());
+        let y = u64::from_le_bytes(b[i..i + 8].try_into().unwrap());
+        let diff = x ^ y;
+        if diff != 0 {
+            return i + (diff.trailing_zeros() / 8) as usize;
+        }
+        i += 8;
+    }
+    while i < len && a[i] == b[i] {
+        i += 1;
+    }
+    i
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::util::lcg;
+
+    const MATCH_FINDERS: [options::MatchFinder; 5] = [
+        options::MatchFinder::Hc3,
+        options::MatchFinder::Hc4,
+        options::MatchFinder::Bt2,
+        options::MatchFinder::Bt3,
+        options::MatchFinder::Bt4,
+    ];
+
+    fn test_data(len: usize) -> Vec<u8> {
+        let mut next = lcg(1);
+        (0..len)
+            .map(|i| {
+                let seed = next();
+                if (seed >> 16) % 8 == 0 {
+                    (seed >> 24) as u8
+                } else {
+                    b"abracadabra"[i % 11]
+                }
+            })
+            .collect()
+    }
+
+    /// Check that the matches found at each position are valid, and sorted by
+    /// increasing length.
+    fn check_matches(mf: &mut MatchFinder, data: &[u8]) -> usize {
+        let mut matches = Vec::new();
+        let mut total = 0;
+        mf.append(data);
+        while mf.avail() > 0 {
+            let cur = mf.read_pos;
+            let len = mf.find(&mut matches);
+            assert_eq!(len, matches.last().map_or(0, |m| m.len));
+            let mut prev_len = 0;
+            for m in &matches {
+                assert!(m.len > prev_len);
+                assert!(m.len >= MATCH_LEN_MIN && m.len <= MATCH_LEN_MAX);
+                assert!(m.dist < mf.dict_size && m.dist < cur);
+                let match_pos = cur - m.dist - 1;
+                assert_eq!(
+                    mf.buf[match_pos..match_pos + m.len],
+                    mf.buf[cur..cur + m.len]
+                );
+                prev_len = m.len;
+            }
+            total += matches.len();
+        }
+        total
+    }
+
+    #[test]
+    fn test_find() {
+        let data = test_data(0x4000);
+        for &match_finder in &MATCH_FINDERS {
+            let options = Options {
+                match_finder,
+                dict_size: 0x1000,
+                ..Default::default()
+            };
+            let mut mf = MatchFinder::new(&options, MATCH_LEN_MAX);
+            assert!(check_matches(&mut mf, &data) > 0);
+        }
+    }
+
+    #[test]
+    fn test_normalize() {
+        let data = test_data(0x4000);
+        for &match_finder in &MATCH_FINDERS {
+            let options = Options {
+                match_finder,
+                dict_size: 0x1000,
+                ..Default::default()
+            };
+            let mut mf = MatchFinder::new(&options, MATCH_LEN_MAX);
+            // Start close to the overflow of stored positions.
+            mf.offset = u32::MAX - 0x2000;
+            assert!(check_matches(&mut mf, &data) > 0);
+            assert!(mf.pos() < 0x4000);
+        }
+    }
+}
@@ -0,0 +1,846 @@
+use crate::compress::{Mode, Options, UnpackedSize};
+use crate::decode::lzma::LzmaProperties;
+use crate::encode::lz::{common_prefix, Match, MatchFinder, MATCH_LEN_MAX, MATCH_LEN_MIN};
+use crate::encode::optimum::{Optimum, OPTS};
+use crate::encode::rangecoder::{
+    bit_price, reverse_bit_tree_price, BitTree, LenEncoder, RangeEncoder,
+};
+use crate::util::vec2d::Vec2D;
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::io;
+
+/// Number of bytes that must be available past the current position before
+/// encoding it, unless the end of the input has been reached.
+///
+/// The optimal parser looks up to `OPTS` bytes ahead, and needs a full match
+/// length after them.
+const KEEP_AHEAD: usize = OPTS + MATCH_LEN_MAX + 1;
+
+/// Size of the chunks read from the input.
+const READ_CHUNK_SIZE: usize = 1 << 16;
+
+/// Approximate memory usage in bytes of an encoder with the given options.
+pub(crate) fn memusage(options: &Options) -> u64 {
+    let literal_probs = 0x300 << (options.lc + options.lp);
+    MatchFinder::memusage(options, KEEP_AHEAD)
+        + READ_CHUNK_SIZE as u64
+        + 2 * literal_probs
+        + std::mem::size_of::<EncoderState>() as u64
+        + match options.mode {
+            Mode::Fast => 0,
+            Mode::Normal => Optimum::memusage(options.nice_len as usize),
+        }
+}
+
+/// Packet to encode at the current position, see the decoder's
+/// `DecoderState::process_next_inner`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Packet {
+    /// A single byte.
+    Literal,
+    /// A match with a new distance (minus one).
+    Match { len: usize, dist: usize },
+    /// A match reusing the distance `rep[idx]`.
+    Rep { idx: usize, len: usize },
+    /// A single byte at distance `rep[0]`.
+    ShortRep,
+}
+
+impl Packet {
+    fn len(&self) -> usize {
+        match *self {
+            Packet::Literal | Packet::ShortRep => 1,
+            Packet::Match { len, .. } | Packet::Rep { len, .. } => len,
+        }
+    }
+}
+
+/// Whether distance `big` is so much larger than `small` that a match at
+/// distance `small` should be preferred even if it is one byte shorter.
+fn change_pair(small: usize, big: usize) -> bool {
+    (big >> 7) > small
+}
+
+/// Slot of a distance, i.e. the position of its most significant bit and the
+/// bit right below it.
+pub(crate) fn dist_slot(dist: usize) -> usize {
+    if dist < 4 {
+        dist
+    } else {
+        let bits = (usize::BITS - 1 - dist.leading_zeros()) as usize;
+        (bits << 1) | ((dist >> (bits - 1)) & 1)
+    }
+}
+
+/// State after a literal, see the decoder's
+/// `DecoderState::process_next_inner`.
+pub(crate) fn state_after_literal(state: usize) -> usize {
+    if state < 4 {
+        0
+    } else if state < 10 {
+        state - 3
+    } else {
+        state - 6
+    }
+}
+
+/// State after a match.
+pub(crate) fn state_after_match(state: usize) -> usize {
+    if state < 7 {
+        7
+    } else {
+        10
+    }
+}
+
+/// State after a rep match.
+pub(crate) fn state_after_rep(state: usize) -> usize {
+    if state < 7 {
+        8
+    } else {
+        11
+    }
+}
+
+/// State after a short rep.
+pub(crate) fn state_after_short_rep(state: usize) -> usize {
+    if state < 7 {
+        9
+    } else {
+        11
+    }
+}
+
+/// Adaptive probabilities and LZ state of the encoder, mirroring
+/// `DecoderState`.
+pub(crate) struct EncoderState {
+    lzma_props: LzmaProperties,
+    literal_probs: Vec2D<u16>,
+    pos_slot_encoder: [BitTree<{ 1 << 6 }>; 4],
+    align_encoder: BitTree<{ 1 << 4 }>,
+    pos_encoders: [u16; 115],
+    is_match: [u16; 192], // true = LZ, false = literal
+    is_rep: [u16; 12],
+    is_rep_g0: [u16; 12],
+    is_rep_g1: [u16; 12],
+    is_rep_g2: [u16; 12],
+    is_rep_0long: [u16; 192],
+    pub(crate) state: usize,
+    pub(crate) rep: [usize; 4],
+    len_encoder: LenEncoder,
+    rep_len_encoder: LenEncoder,
+}
+
+impl EncoderState {
+    pub fn new(lzma_props: LzmaProperties) -> Self {
+        lzma_props.validate();
+        EncoderState {
+            lzma_props,
+            literal_probs: Vec2D::init(0x400, (1 << (lzma_props.lc + lzma_props.lp), 0x300)),
+            pos_slot_encoder: [
+                BitTree::new(),
+                BitTree::new(),
+                BitTree::new(),
+                BitTree::new(),
+            ],
+            align_encoder: BitTree::new(),
+            pos_encoders: [0x400; 115],
+            is_match: [0x400; 192],
+            is_rep: [0x400; 12],
+            is_rep_g0: [0x400; 12],
+            is_rep_g1: [0x400; 12],
+            is_rep_g2: [0x400; 12],
+            is_rep_0long: [0x400; 192],
+            state: 0,
+            rep: [0; 4],
+            len_encoder: LenEncoder::new(),
+            rep_len_encoder: LenEncoder::new(),
+        }
+    }
+
+    /// Reset the probabilities and the LZ state, keeping the properties.
+    pub(crate) fn reset(&mut self) {
+        *self = EncoderState::new(self.lzma_props);
+    }
+
+    /// Position state, i.e. the context given by the `pb` low bits of the
+    /// position.
+    pub(crate) fn pos_state(&self, position: u64) -> usize {
+        (position as usize) & ((1 << self.lzma_props.pb) - 1)
+    }
+
+    /// Literal state, i.e. the context given by the `lp` low bits of the
+    /// position and the `lc` high bits of the previous byte.
+    fn literal_state(&self, position: u64, prev_byte: u8) -> usize {
+        (((position as usize) & ((1 << self.lzma_props.lp) - 1)) << self.lzma_props.lc)
+            + (prev_byte as usize >> (8 - self.lzma_props.lc))
+    }
+
+    fn encode_literal<W: io::Write>(
+        &mut self,
+        rangecoder: &mut RangeEncoder<W>,
+        position: u64,
+        byte: u8,
+        prev_byte: u8,
+        match_byte: u8,
+    ) -> io::Result<()> {
+        let pos_state = self.pos_state(position);
+        rangecoder.encode_bit(&mut self.is_match[(self.state << 4) + pos_state], false)?;
+
+        let lit_state = self.literal_state(position, prev_byte);
+        let probs = &mut self.literal_probs[lit_state];
+
+        let mut result: usize = 1;
+        let mut i = 8;
+        if self.state >= 7 {
+            let mut match_byte = match_byte as usize;
+            while i > 0 {
+                i -= 1;
+                let match_bit = (match_byte >> 7) & 1;
+                match_byte <<= 1;
+                let bit = ((byte >> i) & 1) as usize;
+                rangecoder.encode_bit(&mut probs[((1 + match_bit) << 8) + result], bit != 0)?;
+                result = (result << 1) ^ bit;
+                if match_bit != bit {
+                    break;
+                }
+            }
+        }
+
+        while i > 0 {
+            i -= 1;
+            let bit = ((byte >> i) & 1) as usize;
+            rangecoder.encode_bit(&mut probs[result], bit != 0)?;
+            result = (result << 1) ^ bit;
+        }
+
+        self.state = state_after_literal(self.state);
+        Ok(())
+    }
+
+    fn encode_match<W: io::Write>(
+        &mut self,
+        rangecoder: &mut RangeEncoder<W>,
+        position: u64,
+        len: usize,
+        dist: usize,
+    ) -> io::Result<()> {
+        let pos_state = self.pos_state(position);
+        rangecoder.encode_bit(&mut self.is_match[(self.state << 4) + pos_state], true)?;
+        rangecoder.encode_bit(&mut self.is_rep[self.state], false)?;
+        self.len_encoder
+            .encode(rangecoder, pos_state, (len - MATCH_LEN_MIN) as u32)?;
+        self.encode_distance(rangecoder, len - MATCH_LEN_MIN, dist)?;
+
+        self.rep[3] = self.rep[2];
+        self.rep[2] = self.rep[1];
+        self.rep[1] = self.rep[0];
+        self.rep[0] = dist;
+        self.state = state_after_match(self.state);
+        Ok(())
+    }
+
+    fn encode_distance<W: io::Write>(
+        &mut self,
+        rangecoder: &mut RangeEncoder<W>,
+        length: usize,
+        dist: usize,
+    ) -> io::Result<()> {
+        let len_state = if length > 3 { 3 } else { length };
+
+        let pos_slot = dist_slot(dist);
+        self.pos_slot_encoder[len_state].encode(rangecoder, pos_slot as u32)?;
+        if pos_slot < 4 {
+            return Ok(());
+        }
+
+        let num_direct_bits = (pos_slot >> 1) - 1;
+        let base = (2 ^ (pos_slot & 1)) << num_direct_bits;
+        let reduced = (dist - base) as u32;
+
+        if pos_slot < 14 {
+            rangecoder.encode_reverse_bit_tree(
+                num_direct_bits,
+                &mut self.pos_encoders,
+                base - pos_slot,
+                reduced,
+            )
+        } else {
+            rangecoder.encode_direct_bits(num_direct_bits - 4, reduced >> 4)?;
+            self.align_encoder.encode_reverse(rangecoder, reduced & 0xF)
+        }
+    }
+
+    fn encode_rep<W: io::Write>(
+        &mut self,
+        rangecoder: &mut RangeEncoder<W>,
+        position: u64,
+        idx: usize,
+        len: usize,
+    ) -> io::Result<()> {
+        let pos_state = self.pos_state(position);
+        rangecoder.encode_bit(&mut self.is_match[(self.state << 4) + pos_state], true)?;
+        rangecoder.encode_bit(&mut self.is_rep[self.state], true)?;
+        if idx == 0 {
+            rangecoder.encode_bit(&mut self.is_rep_g0[self.state], false)?;
+            rangecoder.encode_bit(&mut self.is_rep_0long[(self.state << 4) + pos_state], true)?;
+        } else {
+            rangecoder.encode_bit(&mut self.is_rep_g0[self.state], true)?;
+            if idx == 1 {
+                rangecoder.encode_bit(&mut self.is_rep_g1[self.state], false)?;
+            } else {
+                rangecoder.encode_bit(&mut self.is_rep_g1[self.state], true)?;
+                rangecoder.encode_bit(&mut self.is_rep_g2[self.state], idx == 3)?;
+            }
+
+            // Update LRU
+            let dist = self.rep[idx];
+            for i in (0..idx).rev() {
+                self.rep[i + 1] = self.rep[i];
+            }
+            self.rep[0] = dist;
+        }
+
+        self.rep_len_encoder
+            .encode(rangecoder, pos_state, (len - MATCH_LEN_MIN) as u32)?;
+        self.state = state_after_rep(self.state);
+        Ok(())
+    }
+
+    fn encode_short_rep<W: io::Write>(
+        &mut self,
+        rangecoder: &mut RangeEncoder<W>,
+        position: u64,
+    ) -> io::Result<()> {
+        let pos_state = self.pos_state(position);
+        rangecoder.encode_bit(&mut self.is_match[(self.state << 4) + pos_state], true)?;
+        rangecoder.encode_bit(&mut self.is_rep[self.state], true)?;
+        rangecoder.encode_bit(&mut self.is_rep_g0[self.state], false)?;
+        rangecoder.encode_bit(&mut self.is_rep_0long[(self.state << 4) + pos_state], false)?;
+        self.state = state_after_short_rep(self.state);
+        Ok(())
+    }
+
+    /// Price of the `is_match` bit in the given state.
+    pub(crate) fn is_match_price(&self, state: usize, pos_state: usize, bit: bool) -> u32 {
+        bit_price(self.is_match[(state << 4) + pos_state], bit)
+    }
+
+    /// Price of the `is_rep` bit in the given state.
+    pub(crate) fn is_rep_price(&self, state: usize, bit: bool) -> u32 {
+        bit_price(self.is_rep[state], bit)
+    }
+
+    /// Price of a literal in the given state, excluding the `is_match` bit.
+    pub(crate) fn literal_price(
+        &self,
+        state: usize,
+        position: u64,
+        byte: u8,
+        prev_byte: u8,
+        match_byte: u8,
+    ) -> u32 {
+        let probs = &self.literal_probs[self.literal_state(position, prev_byte)];
+
+        let mut price = 0;
+        let mut result: usize = 1;
+        let mut i = 8;
+        if state >= 7 {
+            let mut match_byte = match_byte as usize;
+            while i > 0 {
+                i -= 1;
+                let match_bit = (match_byte >> 7) & 1;
+                match_byte <<= 1;
+                let bit = ((byte >> i) & 1) as usize;
+                price += bit_price(probs[((1 + match_bit) << 8) + result], bit != 0);
+                result = (result << 1) ^ bit;
+                if match_bit != bit {
+                    break;
+                }
+            }
+        }
+
+        while i > 0 {
+            i -= 1;
+            let bit = ((byte >> i) & 1) as usize;
+            price += bit_price(probs[result], bit != 0);
+            result = (result << 1) ^ bit;
+        }
+        price
+    }
+
+    /// Price of a short rep in the given state, excluding the `is_match` and
+    /// `is_rep` bits.
+    pub(crate) fn short_rep_price(&self, state: usize, pos_state: usize) -> u32 {
+        bit_price(self.is_rep_g0[state], false)
+            + bit_price(self.is_rep_0long[(state << 4) + pos_state], false)
+    }
+
+    /// Price of selecting the distance `rep[idx]` in the given state,
+    /// excluding the `is_match` and `is_rep` bits and the length.
+    pub(crate) fn pure_rep_price(&self, idx: usize, state: usize, pos_state: usize) -> u32 {
+        if idx == 0 {
+            bit_price(self.is_rep_g0[state], false)
+                + bit_price(self.is_rep_0long[(state << 4) + pos_state], true)
+        } else {
+            let price = bit_price(self.is_rep_g0[state], true);
+            if idx == 1 {
+                price + bit_price(self.is_rep_g1[state], false)
+            } else {
+                price
+                    + bit_price(self.is_rep_g1[state], true)
+                    + bit_price(self.is_rep_g2[state], idx == 3)
+            }
+        }
+    }
+
+    /// Price of the length of a match, or of a rep match if `rep` is set.
+    pub(crate) fn len_price(&self, rep: bool, pos_state: usize, len: usize) -> u32 {
+        let encoder = if rep {
+            &self.rep_len_encoder
+        } else {
+            &self.len_encoder
+        };
+        encoder.price(pos_state, (len - MATCH_LEN_MIN) as u32)
+    }
+
+    /// Price of the slot of a distance, given the length state.
+    pub(crate) fn dist_slot_price(&self, len_state: usize, slot: usize) -> u32 {
+        self.pos_slot_encoder[len_state].price(slot as u32)
+    }
+
+    /// Price of the bits following the slot for a distance below 128, which
+    /// are encoded with the adaptive `pos_encoders`.
+    pub(crate) fn dist_special_price(&self, dist: usize) -> u32 {
+        let pos_slot = dist_slot(dist);
+        let num_direct_bits = (pos_slot >> 1) - 1;
+        let base = (2 ^ (pos_slot & 1)) << num_direct_bits;
+        reverse_bit_tree_price(
+            num_direct_bits,
+            &self.pos_encoders,
+            base - pos_slot,
+            (dist - base) as u32,
+        )
+    }
+
+    /// Price of the 4 low bits of a large distance.
+    pub(crate) fn align_price(&self, value: u32) -> u32 {
+        self.align_encoder.reverse_price(value)
+    }
+
+    /// Encode the end-of-stream marker, a match of minimal length at
+    /// distance `0xFFFF_FFFF`.
+    fn encode_end_marker<W: io::Write>(
+        &mut self,
+        rangecoder: &mut RangeEncoder<W>,
+        position: u64,
+    ) -> io::Result<()> {
+        let pos_state = self.pos_state(position);
+        rangecoder.encode_bit(&mut self.is_match[(self.state << 4) + pos_state], true)?;
+        rangecoder.encode_bit(&mut self.is_rep[self.state], false)?;
+        self.len_encoder.encode(rangecoder, pos_state, 0)?;
+        self.encode_distance(rangecoder, 0, 0xFFFF_FFFF)
+    }
+}
+
+/// LZMA encoder independent of the output, which LZMA2 drives chunk by
+/// chunk.
+pub(crate) struct LzmaEncoder {
+    pub(crate) state: EncoderState,
+    match_finder: MatchFinder,
+    /// Matches found at the current position, if the match finder's
+    /// `read_ahead` is 1.
+    matches: Vec<Match>,
+    /// State of the optimal parser, in the normal mode.
+    optimum: Option<Optimum>,
+    /// Number of bytes encoded so far.
+    pub(crate) position: u64,
+}
+
+impl LzmaEncoder {
+    /// Create an encoder, keeping at least `keep_behind` encoded bytes in
+    /// the window in addition to the dictionary.
+    pub fn new(options: &Options, keep_behind: usize) -> Self {
+        let state = EncoderState::new(LzmaProperties {
+            lc: options.lc,
+            lp: options.lp,
+            pb: options.pb,
+        });
+        let match_finder = MatchFinder::new(options, KEEP_AHEAD + keep_behind);
+        let optimum = match options.mode {
+            Mode::Fast => None,
+            Mode::Normal => Some(Optimum::new(
+                &state,
+                options.dict_size,
+                match_finder.nice_len,
+            )),
+        };
+        Self {
+            state,
+            match_finder,
+            matches: Vec::new(),
+            optimum,
+            position: 0,
+        }
+    }
+
+    /// Append input data to encode.
+    pub fn append(&mut self, data: &[u8]) {
+        self.match_finder.append(data);
+    }
+
+    /// The last `len` bytes encoded.
+    pub fn encoded_bytes(&self, len: usize) -> &[u8] {
+        let mf = &self.match_finder;
+        let end = mf.read_pos - mf.read_ahead;
+        &mf.buf[end - len..end]
+    }
+
+    /// Reset the probabilities and the LZ state, as done by the decoder on
+    /// an LZMA2 state reset.
+    pub fn reset_state(&mut self) {
+        self.state.reset();
+        if let Some(optimum) = self.optimum.as_mut() {
+            optimum.reset(&self.state);
+        }
+    }
+
+    /// Encode the next packet and return its length. Returns `None` if all
+    /// the input has been encoded, or if more input is needed to encode the
+    /// next position and `finishing` isn't set.
+    ///
+    /// Unless `finishing` is set, enough bytes are kept past the match
+    /// finder's position to find full-length matches at all the positions
+    /// that the parser looks ahead.
+    pub fn encode_next<W: io::Write>(
+        &mut self,
+        rangecoder: &mut RangeEncoder<W>,
+        finishing: bool,
+    ) -> io::Result<Option<usize>> {
+        let mf = &self.match_finder;
+        if mf.avail() == 0 && mf.read_ahead == 0 {
+            return Ok(None);
+        }
+        if !finishing && mf.avail() < KEEP_AHEAD {
+            return Ok(None);
+        }
+
+        let packet = if self.position == 0 {
+            // The first byte is always a literal, so that the distances in
+            // `rep` can be used from the second byte onwards.
+            self.match_finder.skip(1);
+            Packet::Literal
+        } else {
+            self.next_packet()
+        };
+        self.encode_packet(rangecoder, packet)?;
+        Ok(Some(packet.len()))
+    }
+
+    fn next_packet(&mut self) -> Packet {
+        let mut matches = std::mem::take(&mut self.matches);
+        let packet = match self.optimum.as_mut() {
+            Some(optimum) => optimum.next_packet(
+                &self.state,
+                &mut self.match_finder,
+                &mut matches,
+                self.position,
+            ),
+            None => {
+                let mut len_main = if self.match_finder.read_ahead == 0 {
+                    self.match_finder.find(&mut matches)
+                } else {
+                    matches.last().map_or(0, |m| m.len)
+                };
+                self.select_packet(&mut matches, &mut len_main)
+            }
+        };
+        self.matches = matches;
+        packet
+    }
+
+    /// Select the next packet with a greedy heuristic, only looking one byte
+    /// ahead for a better match.
+    fn select_packet(&mut self, matches: &mut Vec<Match>, len_main: &mut usize) -> Packet {
+        let mf = &self.match_finder;
+        let cur = mf.read_pos - 1;
+        let buf_avail = std::cmp::min(mf.avail() + 1, MATCH_LEN_MAX);
+        if buf_avail < MATCH_LEN_MIN {
+            return self.literal_or_short_rep(cur);
+        }
+
+        // Look for the longest match among the repeated distances.
+        let mut rep_len = 0;
+        let mut rep_idx = 0;
+        for (idx, &rep) in self.state.rep.iter().enumerate() {
+            let back = cur - rep - 1;
+            let len = common_prefix(&mf.buf[back..], &mf.buf[cur..cur + buf_avail]);
+            if len < MATCH_LEN_MIN {
+                continue;
+            }
+            if len >= mf.nice_len {
+                self.match_finder.skip(len - 1);
+                return Packet::Rep { idx, len };
+            }
+            if len > rep_len {
+                rep_idx = idx;
+                rep_len = len;
+            }
+        }
+
+        if *len_main >= mf.nice_len {
+            let len = *len_main;
+            let dist = matches.last().unwrap().dist;
+            self.match_finder.skip(len - 1);
+            return Packet::Match { len, dist };
+        }
+
+        // Prefer a shorter match if its distance is much smaller.
+        let mut back_main = 0;
+        if *len_main >= MATCH_LEN_MIN {
+            back_main = matches.last().unwrap().dist;
+            while matches.len() > 1 && *len_main == matches[matches.len() - 2].len + 1 {
+                if !change_pair(matches[matches.len() - 2].dist, back_main) {
+                    break;
+                }
+                matches.pop();
+                *len_main = matches.last().unwrap().len;
+                back_main = matches.last().unwrap().dist;
+            }
+            if *len_main == MATCH_LEN_MIN && back_main >= 0x80 {
+                *len_main = 1;
+            }
+        }
+
+        if rep_len >= MATCH_LEN_MIN
+            && (rep_len + 1 >= *len_main
+                || (rep_len + 2 >= *len_main && back_main > (1 << 9))
+                || (rep_len + 3 >= *len_main && back_main > (1 << 15)))
+        {
+            self.match_finder.skip(rep_len - 1);
+            return Packet::Rep {
+                idx: rep_idx,
+                len: rep_len,
+            };
+        }
+
+        if *len_main < MATCH_LEN_MIN || buf_avail <= MATCH_LEN_MIN {
+            return self.literal_or_short_rep(cur);
+        }
+
+        // Encode the current byte as a literal if the next position has a
+        // better match.
+        let new_len = self.match_finder.find(matches);
+        if new_len >= MATCH_LEN_MIN {
+            let new_dist = matches.last().unwrap().dist;
+            if (new_len >= *len_main && new_dist < back_main)
+                || (new_len == *len_main + 1 && !change_pair(back_main, new_dist))
+                || new_len > *len_main + 1
+                || (new_len + 1 >= *len_main && *len_main >= 3 && change_pair(new_dist, back_main))
+            {
+                return self.literal_or_short_rep(cur);
+            }
+        }
+
+        let mf = &self.match_finder;
+        let next = cur + 1;
+        let limit = std::cmp::max(MATCH_LEN_MIN, *len_main - 1);
+        for &rep in self.state.rep.iter() {
+            let back = next - rep - 1;
+            if mf.buf[back..back + limit] == mf.buf[next..next + limit] {
+                return self.literal_or_short_rep(cur);
+            }
+        }
+
+        self.match_finder.skip(*len_main - 2);
+        Packet::Match {
+            len: *len_main,
+            dist: back_main,
+        }
+    }
+
+    /// Encode a single byte, as a short rep if it matches the byte at the
+    /// last distance.
+    fn literal_or_short_rep(&self, cur: usize) -> Packet {
+        let buf = &self.match_finder.buf;
+        if buf[cur] == buf[cur - self.state.rep[0] - 1] {
+            Packet::ShortRep
+        } else {
+            Packet::Literal
+        }
+    }
+
+    fn encode_packet<W: io::Write>(
+        &mut self,
+        rangecoder: &mut RangeEncoder<W>,
+        packet: Packet,
+    ) -> io::Result<()> {
+        lzma_debug!("Packet: {:?}", packet);
+        let mf = &self.match_finder;
+        let cur = mf.read_pos - mf.read_ahead;
+        let position = self.position;
+
+        match packet {
+            Packet::Literal => {
+                let byte = mf.buf[cur];
+                let (prev_byte, match_byte) = if self.position == 0 {
+                    (0, 0)
+                } else {
+                    (mf.buf[cur - 1], mf.buf[cur - self.state.rep[0] - 1])
+                };
+                self.state
+                    .encode_literal(rangecoder, position, byte, prev_byte, match_byte)?;
+            }
+            Packet::Match { len, dist } => {
+                self.state.encode_match(rangecoder, position, len, dist)?;
+            }
+            Packet::Rep { idx, len } => {
+                self.state.encode_rep(rangecoder, position, idx, len)?;
+            }
+            Packet::ShortRep => {
+                self.state.encode_short_rep(rangecoder, position)?;
+            }
+        }
+
+        if let Some(optimum) = self.optimum.as_mut() {
+            optimum.packet_encoded(&self.state, packet, position);
+        }
+
+        let len = packet.len();
+        self.match_finder.read_ahead -= len;
+        self.position += len as u64;
+        Ok(())
+    }
+}
+
+/// LZMA encoder writing the header and the compressed data to an output
+/// sink.
+pub struct Encoder<W>
+where
+    W: io::Write,
+{
+    rangecoder: RangeEncoder<W>,
+    lzma: LzmaEncoder,
+    unpacked_size: UnpackedSize,
+}
+
+impl<W> Encoder<W>
+where
+    W: io::Write,
+{
+    pub fn from_stream(mut stream: W, options: &Options) -> io::Result<Self> {
+        options.validate()?;
+        let lzma_props = LzmaProperties {
+            lc: options.lc,
+            lp: options.lp,
+            pb: options.pb,
+        };
+        let dict_size = options.dict_size;
+
+        // Properties
+        let props = (lzma_props.lc + 9 * (lzma_props.lp + 5 * lzma_props.pb)) as u8;
+        lzma_info!(
+            "Properties {{ lc: {}, lp: {}, pb: {} }}",
+            lzma_props.lc,
+            lzma_props.lp,
+            lzma_props.pb
+        );
+        stream.write_u8(props)?;
+
+        // Dictionary
+        lzma_info!("Dict size: {}", dict_size);
+        stream.write_u32::<LittleEndian>(dict_size)?;
+
+        // Unpacked size
+        match &options.unpacked_size {
+            UnpackedSize::WriteToHeader(unpacked_size) => {
+                let value: u64 = match unpacked_size {
+                    None => {
+                        lzma_info!("Unpacked size: unknown");
+                        0xFFFF_FFFF_FFFF_FFFF
+                    }
+                    Some(x) => {
+                        lzma_info!("Unpacked size: {}", x);
+                        *x
+                    }
+                };
+                stream.write_u64::<LittleEndian>(value)?;
+            }
+            UnpackedSize::SkipWritingToHeader => {}
+        };
+
+        let encoder = Encoder {
+            rangecoder: RangeEncoder::new(stream),
+            lzma: LzmaEncoder::new(options, 0),
+            unpacked_size: options.unpacked_size,
+        };
+
+        Ok(encoder)
+    }
+
+    /// Gets a reference to the output sink.
+    pub fn get_ref(&self) -> &W {
+        self.rangecoder.get_ref()
+    }
+
+    /// Gets a mutable reference to the output sink.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.rangecoder.get_mut()
+    }
+
+    pub fn process<R>(mut self, mut input: R) -> io::Result<()>
+    where
+        R: io::Read,
+    {
+        let mut buf = vec![0; READ_CHUNK_SIZE];
+        loop {
+            let n = match input.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.append(&buf[..n])?;
+        }
+        self.finish()?;
+        Ok(())
+    }
+
+    /// Encode a prefix of the data, at most `READ_CHUNK_SIZE` bytes so that
+    /// the window doesn't grow further. Returns the number of bytes
+    /// consumed.
+    pub fn append(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = std::cmp::min(data.len(), READ_CHUNK_SIZE);
+        self.lzma.append(&data[..n]);
+        self.encode(false)?;
+        Ok(n)
+    }
+
+    /// Encode the buffered input.
+    fn encode(&mut self, finishing: bool) -> io::Result<()> {
+        while self
+            .lzma
+            .encode_next(&mut self.rangecoder, finishing)?
+            .is_some()
+        {}
+        Ok(())
+    }
+
+    /// Encode the remaining input and the end marker if needed, and return
+    /// the output sink.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.encode(true)?;
+        match self.unpacked_size {
+            UnpackedSize::SkipWritingToHeader | UnpackedSize::WriteToHeader(Some(_)) => {}
+            UnpackedSize::WriteToHeader(None) => {
+                self.lzma
+                    .state
+                    .encode_end_marker(&mut self.rangecoder, self.lzma.position)?;
+            }
+        }
+
+        // Flush range coder
+        self.rangecoder.finish()?;
+        Ok(self.rangecoder.into_inner())
+    }
+}
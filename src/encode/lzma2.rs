@@ -1,26 +1,219 @@
+use crate::encode::lz::MATCH_LEN_MAX;
+use crate::encode::lzma::LzmaEncoder;
+use crate::encode::optimum::OPTS;
+use crate::encode::options::Options;
+use crate::encode::rangecoder::RangeEncoder;
 use byteorder::{BigEndian, WriteBytesExt};
 use std::io;
 
-pub fn encode_stream<R, W>(input: &mut R, output: &mut W) -> io::Result<()>
+/// Maximum unpacked size of a chunk.
+const UNPACKED_SIZE_MAX: usize = 1 << 21;
+
+/// Maximum packed size of a chunk.
+const PACKED_SIZE_MAX: usize = 1 << 16;
+
+/// No packet is encoded in an LZMA chunk once its packed size reaches
+/// `PACKED_SIZE_MAX` minus this margin, which is the same as liblzma's.
+const PACKED_SIZE_MARGIN: usize = OPTS + 1;
+
+/// Size of the chunks read from the input, and maximum size of the input
+/// appended at once.
+const READ_CHUNK_SIZE: usize = 1 << 16;
+
+pub fn encode_stream<R, W>(input: &mut R, output: &mut W, options: &Options) -> io::Result<()>
 where
-    R: io::BufRead,
+    R: io::Read,
     W: io::Write,
 {
-    let mut buf = vec![0u8; 0x10000];
+    let mut encoder = Encoder::new(options)?;
+    let mut buf = vec![0; READ_CHUNK_SIZE];
     loop {
-        let n = input.read(&mut buf)?;
-        if n == 0 {
-            // status = EOF
-            output.write_u8(0)?;
-            break;
+        let n = match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        encoder.append(&buf[..n], output)?;
+    }
+    encoder.finish(output)
+}
+
+/// LZMA2 encoder, splitting the input into chunks as it is appended.
+pub(crate) struct Encoder {
+    lzma: LzmaEncoder,
+    /// LZMA properties, written after a properties reset.
+    props: u8,
+    /// Range coder of the chunk being encoded.
+    rangecoder: RangeEncoder<Vec<u8>>,
+    /// Position of the start of the chunk being encoded.
+    start: u64,
+    need_dict_reset: bool,
+    need_props: bool,
+    need_state_reset: bool,
+}
+
+impl Encoder {
+    pub(crate) fn new(options: &Options) -> io::Result<Self> {
+        options.validate()?;
+        if options.lc + options.lp > 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid properties {{ lc: {}, lp: {} }}, LZMA2 requires lc + lp <= 4",
+                    options.lc, options.lp
+                ),
+            ));
+        }
+
+        Ok(Self {
+            // An uncompressed chunk is at most as large as a packed chunk,
+            // and is copied from the window.
+            lzma: LzmaEncoder::new(options, PACKED_SIZE_MAX),
+            props: (options.lc + 9 * (options.lp + 5 * options.pb)) as u8,
+            rangecoder: RangeEncoder::new(Vec::new()),
+            start: 0,
+            need_dict_reset: true,
+            need_props: true,
+            need_state_reset: false,
+        })
+    }
+
+    /// Encode a prefix of the data, at most `READ_CHUNK_SIZE` bytes so that
+    /// the window doesn't grow further, writing the chunks completed to the
+    /// output. Returns the number of bytes consumed.
+    pub(crate) fn append<W>(&mut self, data: &[u8], output: &mut W) -> io::Result<usize>
+    where
+        W: io::Write,
+    {
+        let n = std::cmp::min(data.len(), READ_CHUNK_SIZE);
+        self.lzma.append(&data[..n]);
+        self.encode(output, false)?;
+        Ok(n)
+    }
+
+    /// Encode the remaining input, and write the end marker.
+    pub(crate) fn finish<W>(&mut self, output: &mut W) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        self.encode(output, true)?;
+        // status = EOF
+        output.write_u8(0)
+    }
+
+    /// Encode the buffered input, writing each chunk once it is full. Unless
+    /// `finishing` is set, the last chunk is kept open for more input.
+    fn encode<W>(&mut self, output: &mut W, finishing: bool) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        loop {
+            let unpacked_size = (self.lzma.position - self.start) as usize;
+            let packed_size = self.rangecoder.get_ref().len() + self.rangecoder.pending_len();
+            if unpacked_size >= UNPACKED_SIZE_MAX - MATCH_LEN_MAX
+                || packed_size >= PACKED_SIZE_MAX - PACKED_SIZE_MARGIN
+            {
+                self.write_chunk(output)?;
+                continue;
+            }
+
+            if self
+                .lzma
+                .encode_next(&mut self.rangecoder, finishing)?
+                .is_none()
+            {
+                if finishing {
+                    self.write_chunk(output)?;
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    /// Write the chunk being encoded, as LZMA if that makes it smaller.
+    fn write_chunk<W>(&mut self, output: &mut W) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        let unpacked_size = (self.lzma.position - self.start) as usize;
+        if unpacked_size == 0 {
+            return Ok(());
         }
+        let mut rangecoder = std::mem::replace(&mut self.rangecoder, RangeEncoder::new(Vec::new()));
+        rangecoder.finish()?;
+        let packed = rangecoder.into_inner();
+        self.start = self.lzma.position;
+        lzma_info!(
+            "LZMA2 chunk {{ unpacked_size: {}, packed_size: {} }}",
+            unpacked_size,
+            packed.len()
+        );
+
+        if packed.len() >= unpacked_size {
+            // status = uncompressed, with or without dict reset
+            output.write_u8(if self.need_dict_reset { 1 } else { 2 })?;
+            output.write_u16::<BigEndian>((unpacked_size - 1) as u16)?;
+            output.write_all(self.lzma.encoded_bytes(unpacked_size))?;
+
+            // The probabilities were updated by encoding the chunk, so the
+            // next LZMA chunk starts from fresh ones.
+            self.lzma.reset_state();
+            self.need_dict_reset = false;
+            self.need_state_reset = true;
+        } else {
+            let status = if self.need_props {
+                if self.need_dict_reset {
+                    0xE0
+                } else {
+                    0xC0
+                }
+            } else if self.need_state_reset {
+                0xA0
+            } else {
+                0x80
+            };
+            output.write_u8(status | ((unpacked_size - 1) >> 16) as u8)?;
+            output.write_u16::<BigEndian>((unpacked_size - 1) as u16)?;
+            output.write_u16::<BigEndian>((packed.len() - 1) as u16)?;
+            if self.need_props {
+                output.write_u8(self.props)?;
+            }
+            output.write_all(&packed)?;
+
+            self.need_dict_reset = false;
+            self.need_props = false;
+            self.need_state_reset = false;
+        }
+        Ok(())
+    }
+}
+
+/// Encode the dictionary size as the LZMA2 property byte used by container
+/// formats, rounding it up to the nearest representable size.
+pub fn dict_size_property(dict_size: u32) -> u8 {
+    // Property values encode sizes of the form 2^n or 3 * 2^(n-1), starting
+    // from 4 KiB, and 40 encodes 4 GiB - 1.
+    (0..40u8)
+        .find(|&prop| (2 | (prop as u64 & 1)) << (prop / 2 + 11) >= dict_size as u64)
+        .unwrap_or(40)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
 
-        // status = uncompressed reset dict
-        output.write_u8(1)?;
-        // unpacked size
-        output.write_u16::<BigEndian>((n - 1) as u16)?;
-        // contents
-        output.write_all(&buf[..n])?;
+    #[test]
+    fn test_dict_size_property() {
+        assert_eq!(dict_size_property(0), 0);
+        assert_eq!(dict_size_property(0x1000), 0);
+        assert_eq!(dict_size_property(0x1001), 1);
+        assert_eq!(dict_size_property(0x1800), 1);
+        assert_eq!(dict_size_property(0x1801), 2);
+        assert_eq!(dict_size_property(0x80_0000), 22);
+        assert_eq!(dict_size_property(0xC0_0000), 23);
+        assert_eq!(dict_size_property(0x400_0000), 28);
+        assert_eq!(dict_size_property(0x6000_0000), 37);
+        assert_eq!(dict_size_property(0xFFFF_FFFF), 40);
     }
-    Ok(())
 }
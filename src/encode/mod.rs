@@ -1,8 +1,12 @@
 //! Encoding logic.
 
-pub mod dumbencoder;
+mod lz;
+pub mod lzma;
 pub mod lzma2;
+mod optimum;
 pub mod options;
 mod rangecoder;
+pub mod reader;
 mod util;
+pub mod writer;
 pub mod xz;
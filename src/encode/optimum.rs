@@ -0,0 +1,866 @@
+//! Price-based optimal parsing, following liblzma's
+//! `lzma_lzma_optimum_normal`.
+//!
+//! Starting from the current position, the parser computes the cheapest known
+//! way to reach each of the following positions, one position at a time, until
+//! no packet crosses the last position reached. The cheapest path to that last
+//! position is then encoded packet by packet.
+
+use crate::encode::lz::{common_prefix, Match, MatchFinder, MATCH_LEN_MAX, MATCH_LEN_MIN};
+use crate::encode::lzma::{
+    dist_slot, state_after_literal, state_after_match, state_after_rep, state_after_short_rep,
+    EncoderState, Packet,
+};
+use crate::encode::rangecoder::{direct_bits_price, INFINITY_PRICE};
+use std::cmp;
+use std::ops::ControlFlow;
+
+/// Number of positions in the lookahead window of the parser.
+pub(crate) const OPTS: usize = 1 << 12;
+
+/// Number of repeated distances.
+const REPS: usize = 4;
+
+/// Number of length states for the distance slots.
+const DIST_STATES: usize = 4;
+
+/// First distance slot with extra bits.
+const DIST_MODEL_START: usize = 4;
+
+/// First distance slot whose low bits are encoded with the align encoder.
+const DIST_MODEL_END: usize = 14;
+
+/// Distances below this one are fully encoded with adaptive probabilities.
+const FULL_DISTANCES: usize = 128;
+
+/// Number of values of the low bits of the distance encoded with the align
+/// encoder.
+const ALIGN_SIZE: usize = 16;
+
+/// Number of matches to encode before updating the distance prices.
+const DIST_PRICE_UPDATE: usize = 1 << 7;
+
+/// Value of `back` for a literal. Other values are the index of a rep match
+/// if smaller than `REPS`, or the distance of a match plus `REPS`.
+const LITERAL: usize = usize::MAX;
+
+/// Cheapest known path to a position of the lookahead window.
+#[derive(Clone, Copy, Debug, Default)]
+struct Optimal {
+    /// LZMA state at this position.
+    state: usize,
+    /// Whether the path ends with a literal followed by the rep match given
+    /// by `pos_prev` and `back_prev`.
+    prev_1_is_literal: bool,
+    /// Whether the path ends with a match given by `pos_prev_2` and
+    /// `back_prev_2`, a literal, and a rep match.
+    prev_2: bool,
+    pos_prev_2: usize,
+    back_prev_2: usize,
+    /// Total price of the path.
+    price: u32,
+    /// Position before the last packet of the path.
+    pos_prev: usize,
+    /// Kind of the last packet of the path, see `LITERAL`.
+    back_prev: usize,
+    /// Repeated distances at this position.
+    backs: [usize; REPS],
+}
+
+impl Optimal {
+    fn make_literal(&mut self) {
+        self.back_prev = LITERAL;
+        self.prev_1_is_literal = false;
+    }
+
+    fn make_short_rep(&mut self) {
+        self.back_prev = 0;
+        self.prev_1_is_literal = false;
+    }
+
+    fn is_short_rep(&self) -> bool {
+        self.back_prev == 0
+    }
+}
+
+/// Convert the kind of a packet and its length into a [`Packet`].
+fn packet(back: usize, len: usize) -> Packet {
+    if back == LITERAL {
+        Packet::Literal
+    } else if back < REPS {
+        if len == 1 {
+            Packet::ShortRep
+        } else {
+            Packet::Rep { idx: back, len }
+        }
+    } else {
+        Packet::Match {
+            len,
+            dist: back - REPS,
+        }
+    }
+}
+
+/// Cached prices of the lengths, which are updated after a number of lengths
+/// have been encoded.
+struct LenPrices {
+    /// Whether these are the prices of rep match lengths.
+    rep: bool,
+    /// Prices of the lengths from `MATCH_LEN_MIN`, for each position state.
+    prices: Vec<Vec<u32>>,
+    /// Number of lengths to encode before updating the prices, for each
+    /// position state.
+    counters: [usize; 16],
+}
+
+impl LenPrices {
+    fn new(rep: bool, table_size: usize) -> Self {
+        Self {
+            rep,
+            prices: vec![vec![0; table_size]; 16],
+            counters: [0; 16],
+        }
+    }
+
+    fn update(&mut self, state: &EncoderState, pos_state: usize) {
+        let prices = &mut self.prices[pos_state];
+        for (i, price) in prices.iter_mut().enumerate() {
+            *price = state.len_price(self.rep, pos_state, i + MATCH_LEN_MIN);
+        }
+        self.counters[pos_state] = prices.len();
+    }
+
+    /// Count a length encoded with the given position state.
+    fn decrement(&mut self, state: &EncoderState, pos_state: usize) {
+        self.counters[pos_state] -= 1;
+        if self.counters[pos_state] == 0 {
+            self.update(state, pos_state);
+        }
+    }
+
+    fn price(&self, len: usize, pos_state: usize) -> u32 {
+        self.prices[pos_state][len - MATCH_LEN_MIN]
+    }
+}
+
+/// State of the optimal parser.
+pub(crate) struct Optimum {
+    opts: Vec<Optimal>,
+    /// Last position of the selected path.
+    opts_end_index: usize,
+    /// Position of the next packet of the selected path to return.
+    opts_current_index: usize,
+    match_len_prices: LenPrices,
+    rep_len_prices: LenPrices,
+    /// Number of distance slots used by the dictionary size.
+    dist_table_size: usize,
+    /// Prices of the distance slots, including the direct bits, for each
+    /// length state.
+    dist_slot_prices: [[u32; 64]; DIST_STATES],
+    /// Prices of the distances below `FULL_DISTANCES`, for each length state.
+    dist_prices: [[u32; FULL_DISTANCES]; DIST_STATES],
+    /// Prices of the low bits of the larger distances.
+    align_prices: [u32; ALIGN_SIZE],
+    /// Number of matches encoded since the distance prices were updated.
+    match_price_count: usize,
+    /// Number of align values encoded since the align prices were updated.
+    align_price_count: usize,
+}
+
+impl Optimum {
+    pub fn new(state: &EncoderState, dict_size: u32, nice_len: usize) -> Self {
+        let table_size = nice_len + 1 - MATCH_LEN_MIN;
+        let mut optimum = Self {
+            opts: vec![Optimal::default(); OPTS],
+            opts_end_index: 0,
+            opts_current_index: 0,
+            match_len_prices: LenPrices::new(false, table_size),
+            rep_len_prices: LenPrices::new(true, table_size),
+            dist_table_size: dist_slot(dict_size as usize - 1) + 1,
+            dist_slot_prices: [[0; 64]; DIST_STATES],
+            dist_prices: [[0; FULL_DISTANCES]; DIST_STATES],
+            align_prices: [0; ALIGN_SIZE],
+            match_price_count: 0,
+            align_price_count: 0,
+        };
+        optimum.reset(state);
+        optimum
+    }
+
+    /// Approximate memory usage in bytes of the optimal parser.
+    pub fn memusage(nice_len: usize) -> u64 {
+        let table_size = (nice_len + 1 - MATCH_LEN_MIN) as u64;
+        (std::mem::size_of::<Self>() + OPTS * std::mem::size_of::<Optimal>()) as u64
+            + 2 * 16 * table_size * 4
+    }
+
+    /// Recompute all the prices after a reset of the probabilities.
+    pub fn reset(&mut self, state: &EncoderState) {
+        for pos_state in 0..16 {
+            self.match_len_prices.update(state, pos_state);
+            self.rep_len_prices.update(state, pos_state);
+        }
+        self.fill_dist_prices(state);
+        self.fill_align_prices(state);
+
+        // The rest of the selected path was found by the match finder, but
+        // may refer to repeated distances that were just reset, so encode it
+        // with literals.
+        for i in self.opts_current_index..self.opts_end_index {
+            self.opts[i].pos_prev = i + 1;
+            self.opts[i].back_prev = LITERAL;
+        }
+    }
+
+    fn fill_dist_prices(&mut self, state: &EncoderState) {
+        for dist_state in 0..DIST_STATES {
+            let dist_slot_prices = &mut self.dist_slot_prices[dist_state];
+            for (slot, price) in dist_slot_prices[..self.dist_table_size]
+                .iter_mut()
+                .enumerate()
+            {
+                *price = state.dist_slot_price(dist_state, slot);
+                // The low 4 bits of the larger distances are priced
+                // separately, see `fill_align_prices`.
+                if slot >= DIST_MODEL_END {
+                    *price += direct_bits_price((slot >> 1) - 1 - 4);
+                }
+            }
+
+            // Distances below `DIST_MODEL_START` are fully encoded with their
+            // slot.
+            self.dist_prices[dist_state][..DIST_MODEL_START]
+                .copy_from_slice(&dist_slot_prices[..DIST_MODEL_START]);
+        }
+
+        for dist in DIST_MODEL_START..FULL_DISTANCES {
+            let slot = dist_slot(dist);
+            let price = state.dist_special_price(dist);
+            for dist_state in 0..DIST_STATES {
+                self.dist_prices[dist_state][dist] =
+                    price + self.dist_slot_prices[dist_state][slot];
+            }
+        }
+
+        self.match_price_count = 0;
+    }
+
+    fn fill_align_prices(&mut self, state: &EncoderState) {
+        for (value, price) in self.align_prices.iter_mut().enumerate() {
+            *price = state.align_price(value as u32);
+        }
+        self.align_price_count = 0;
+    }
+
+    /// Update the price counters after a packet has been encoded at the
+    /// given position.
+    pub fn packet_encoded(&mut self, state: &EncoderState, packet: Packet, position: u64) {
+        let pos_state = state.pos_state(position);
+        match packet {
+            Packet::Literal | Packet::ShortRep => (),
+            Packet::Match { dist, .. } => {
+                self.match_len_prices.decrement(state, pos_state);
+                self.match_price_count += 1;
+                if dist_slot(dist) >= DIST_MODEL_END {
+                    self.align_price_count += 1;
+                }
+            }
+            Packet::Rep { .. } => self.rep_len_prices.decrement(state, pos_state),
+        }
+    }
+
+    /// Price of a match at distance `dist`, excluding the `is_match` and
+    /// `is_rep` bits.
+    fn dist_len_price(&self, dist: usize, len: usize, pos_state: usize) -> u32 {
+        let dist_state = cmp::min(len - MATCH_LEN_MIN, DIST_STATES - 1);
+        let price = if dist < FULL_DISTANCES {
+            self.dist_prices[dist_state][dist]
+        } else {
+            self.dist_slot_prices[dist_state][dist_slot(dist)]
+                + self.align_prices[dist & (ALIGN_SIZE - 1)]
+        };
+        price + self.match_len_prices.price(len, pos_state)
+    }
+
+    /// Price of a rep match, excluding the `is_match` and `is_rep` bits.
+    fn rep_price(
+        &self,
+        state: &EncoderState,
+        idx: usize,
+        len: usize,
+        lzma_state: usize,
+        pos_state: usize,
+    ) -> u32 {
+        self.rep_len_prices.price(len, pos_state) + state.pure_rep_price(idx, lzma_state, pos_state)
+    }
+
+    /// Select the next packet to encode at the given position, which is
+    /// either the next packet of the path selected previously, or the first
+    /// packet of a new path.
+    pub fn next_packet(
+        &mut self,
+        state: &EncoderState,
+        mf: &mut MatchFinder,
+        matches: &mut Vec<Match>,
+        position: u64,
+    ) -> Packet {
+        if self.opts_end_index != self.opts_current_index {
+            let cur = self.opts_current_index;
+            let opt = self.opts[cur];
+            self.opts_current_index = opt.pos_prev;
+            return packet(opt.back_prev, opt.pos_prev - cur);
+        }
+
+        if mf.read_ahead == 0 {
+            if self.match_price_count >= DIST_PRICE_UPDATE {
+                self.fill_dist_prices(state);
+            }
+            if self.align_price_count >= ALIGN_SIZE {
+                self.fill_align_prices(state);
+            }
+        }
+
+        let mut len_end = match self.init_opts(state, mf, matches, position) {
+            ControlFlow::Break(packet) => return packet,
+            ControlFlow::Continue(len_end) => len_end,
+        };
+
+        let mut reps = state.rep;
+        let mut cur = 1;
+        while cur < len_end {
+            let new_len = mf.find(matches);
+            if new_len >= mf.nice_len {
+                break;
+            }
+
+            let buf_avail = cmp::min(mf.avail() + 1, OPTS - 1 - cur);
+            len_end = self.update_opts(
+                state,
+                &mut reps,
+                mf,
+                matches,
+                new_len,
+                len_end,
+                position + cur as u64,
+                cur,
+                buf_avail,
+            );
+            cur += 1;
+        }
+
+        self.backward(cur)
+    }
+
+    /// Compute the paths reaching the positions covered by the packets at the
+    /// current position. Returns the last position reached, or the packet to
+    /// encode directly if there is no choice to make.
+    fn init_opts(
+        &mut self,
+        state: &EncoderState,
+        mf: &mut MatchFinder,
+        matches: &mut Vec<Match>,
+        position: u64,
+    ) -> ControlFlow<Packet, usize> {
+        let nice_len = mf.nice_len;
+        let len_main = if mf.read_ahead == 0 {
+            mf.find(matches)
+        } else {
+            matches.last().map_or(0, |m| m.len)
+        };
+
+        let buf_avail = cmp::min(mf.avail() + 1, MATCH_LEN_MAX);
+        if buf_avail < MATCH_LEN_MIN {
+            return ControlFlow::Break(Packet::Literal);
+        }
+
+        let buf = &mf.buf;
+        let cur = mf.read_pos - 1;
+
+        let mut rep_lens = [0; REPS];
+        let mut rep_max_index = 0;
+        for i in 0..REPS {
+            let back = cur - state.rep[i] - 1;
+            if buf[back..back + 2] != buf[cur..cur + 2] {
+                continue;
+            }
+            rep_lens[i] = 2 + common_prefix(&buf[back + 2..], &buf[cur + 2..cur + buf_avail]);
+            if rep_lens[i] > rep_lens[rep_max_index] {
+                rep_max_index = i;
+            }
+        }
+
+        if rep_lens[rep_max_index] >= nice_len {
+            let len = rep_lens[rep_max_index];
+            mf.skip(len - 1);
+            return ControlFlow::Break(Packet::Rep {
+                idx: rep_max_index,
+                len,
+            });
+        }
+
+        if len_main >= nice_len {
+            let dist = matches.last().unwrap().dist;
+            mf.skip(len_main - 1);
+            return ControlFlow::Break(Packet::Match {
+                len: len_main,
+                dist,
+            });
+        }
+
+        let buf = &mf.buf;
+        let current_byte = buf[cur];
+        let match_byte = buf[cur - state.rep[0] - 1];
+        if len_main < MATCH_LEN_MIN
+            && current_byte != match_byte
+            && rep_lens[rep_max_index] < MATCH_LEN_MIN
+        {
+            return ControlFlow::Break(Packet::Literal);
+        }
+
+        let lzma_state = state.state;
+        let pos_state = state.pos_state(position);
+        self.opts[0].state = lzma_state;
+
+        self.opts[1].price = state.is_match_price(lzma_state, pos_state, false)
+            + state.literal_price(lzma_state, position, current_byte, buf[cur - 1], match_byte);
+        self.opts[1].make_literal();
+
+        let match_price = state.is_match_price(lzma_state, pos_state, true);
+        let rep_match_price = match_price + state.is_rep_price(lzma_state, true);
+
+        if match_byte == current_byte {
+            let short_rep_price = rep_match_price + state.short_rep_price(lzma_state, pos_state);
+            if short_rep_price < self.opts[1].price {
+                self.opts[1].price = short_rep_price;
+                self.opts[1].make_short_rep();
+            }
+        }
+
+        let len_end = cmp::max(len_main, rep_lens[rep_max_index]);
+        if len_end < MATCH_LEN_MIN {
+            return ControlFlow::Break(packet(self.opts[1].back_prev, 1));
+        }
+
+        self.opts[1].pos_prev = 0;
+        self.opts[0].backs = state.rep;
+        for opt in &mut self.opts[MATCH_LEN_MIN..=len_end] {
+            opt.price = INFINITY_PRICE;
+        }
+
+        for (i, &rep_len) in rep_lens.iter().enumerate() {
+            if rep_len < MATCH_LEN_MIN {
+                continue;
+            }
+
+            let price = rep_match_price + state.pure_rep_price(i, lzma_state, pos_state);
+            for len in MATCH_LEN_MIN..=rep_len {
+                let cur_and_len_price = price + self.rep_len_prices.price(len, pos_state);
+                let opt = &mut self.opts[len];
+                if cur_and_len_price < opt.price {
+                    opt.price = cur_and_len_price;
+                    opt.pos_prev = 0;
+                    opt.back_prev = i;
+                    opt.prev_1_is_literal = false;
+                }
+            }
+        }
+
+        let normal_match_price = match_price + state.is_rep_price(lzma_state, false);
+        let mut len = if rep_lens[0] >= MATCH_LEN_MIN {
+            rep_lens[0] + 1
+        } else {
+            MATCH_LEN_MIN
+        };
+        if len <= len_main {
+            let mut i = 0;
+            while len > matches[i].len {
+                i += 1;
+            }
+
+            loop {
+                let dist = matches[i].dist;
+                let cur_and_len_price =
+                    normal_match_price + self.dist_len_price(dist, len, pos_state);
+                let opt = &mut self.opts[len];
+                if cur_and_len_price < opt.price {
+                    opt.price = cur_and_len_price;
+                    opt.pos_prev = 0;
+                    opt.back_prev = dist + REPS;
+                    opt.prev_1_is_literal = false;
+                }
+
+                if len == matches[i].len {
+                    i += 1;
+                    if i == matches.len() {
+                        break;
+                    }
+                }
+                len += 1;
+            }
+        }
+
+        ControlFlow::Continue(len_end)
+    }
+
+    /// Extend the paths from the position `cur` of the window, given the
+    /// matches found at that position. Returns the new last position reached.
+    #[allow(clippy::too_many_arguments)]
+    fn update_opts(
+        &mut self,
+        state: &EncoderState,
+        reps: &mut [usize; REPS],
+        mf: &MatchFinder,
+        matches: &mut Vec<Match>,
+        mut new_len: usize,
+        mut len_end: usize,
+        position: u64,
+        cur: usize,
+        buf_avail_full: usize,
+    ) -> usize {
+        let nice_len = mf.nice_len;
+        let buf = &mf.buf;
+        let p = mf.read_pos - 1;
+
+        // Compute the LZMA state and the repeated distances at the current
+        // position, following the last packets of the path.
+        let opt = self.opts[cur];
+        let mut pos_prev = opt.pos_prev;
+        let mut lzma_state;
+        if opt.prev_1_is_literal {
+            pos_prev -= 1;
+            if opt.prev_2 {
+                lzma_state = self.opts[opt.pos_prev_2].state;
+                lzma_state = if opt.back_prev_2 < REPS {
+                    state_after_rep(lzma_state)
+                } else {
+                    state_after_match(lzma_state)
+                };
+            } else {
+                lzma_state = self.opts[pos_prev].state;
+            }
+            lzma_state = state_after_literal(lzma_state);
+        } else {
+            lzma_state = self.opts[pos_prev].state;
+        }
+
+        if pos_prev == cur - 1 {
+            lzma_state = if opt.is_short_rep() {
+                state_after_short_rep(lzma_state)
+            } else {
+                state_after_literal(lzma_state)
+            };
+        } else {
+            let back;
+            if opt.prev_1_is_literal && opt.prev_2 {
+                pos_prev = opt.pos_prev_2;
+                back = opt.back_prev_2;
+                lzma_state = state_after_rep(lzma_state);
+            } else {
+                back = opt.back_prev;
+                lzma_state = if back < REPS {
+                    state_after_rep(lzma_state)
+                } else {
+                    state_after_match(lzma_state)
+                };
+            }
+
+            let backs = self.opts[pos_prev].backs;
+            if back < REPS {
+                reps[0] = backs[back];
+                reps[1..=back].copy_from_slice(&backs[..back]);
+                reps[back + 1..].copy_from_slice(&backs[back + 1..]);
+            } else {
+                reps[0] = back - REPS;
+                reps[1..].copy_from_slice(&backs[..REPS - 1]);
+            }
+        }
+
+        self.opts[cur].state = lzma_state;
+        self.opts[cur].backs = *reps;
+
+        let cur_price = opt.price;
+        let current_byte = buf[p];
+        let match_byte = buf[p - reps[0] - 1];
+        let pos_state = state.pos_state(position);
+
+        // Literal.
+        let cur_and_1_price = cur_price
+            + state.is_match_price(lzma_state, pos_state, false)
+            + state.literal_price(lzma_state, position, current_byte, buf[p - 1], match_byte);
+
+        let mut next_is_literal = false;
+        let next = &mut self.opts[cur + 1];
+        if cur_and_1_price < next.price {
+            next.price = cur_and_1_price;
+            next.pos_prev = cur;
+            next.make_literal();
+            next_is_literal = true;
+        }
+
+        // Short rep.
+        let match_price = cur_price + state.is_match_price(lzma_state, pos_state, true);
+        let rep_match_price = match_price + state.is_rep_price(lzma_state, true);
+
+        if match_byte == current_byte && !(next.pos_prev < cur && next.back_prev == 0) {
+            let short_rep_price = rep_match_price + state.short_rep_price(lzma_state, pos_state);
+            if short_rep_price <= next.price {
+                next.price = short_rep_price;
+                next.pos_prev = cur;
+                next.make_short_rep();
+                next_is_literal = true;
+            }
+        }
+
+        if buf_avail_full < MATCH_LEN_MIN {
+            return len_end;
+        }
+        let buf_avail = cmp::min(buf_avail_full, nice_len);
+
+        // Literal followed by a rep match with the distance `reps[0]`.
+        if !next_is_literal && match_byte != current_byte {
+            let back = p - reps[0] - 1;
+            let limit = cmp::min(buf_avail_full, nice_len + 1);
+            let len_test = common_prefix(&buf[back + 1..], &buf[p + 1..p + limit]);
+
+            if len_test >= MATCH_LEN_MIN {
+                let state_2 = state_after_literal(lzma_state);
+                let pos_state_next = state.pos_state(position + 1);
+                let next_rep_match_price = cur_and_1_price
+                    + state.is_match_price(state_2, pos_state_next, true)
+                    + state.is_rep_price(state_2, true);
+
+                let offset = cur + 1 + len_test;
+                len_end = self.extend(len_end, offset);
+
+                let cur_and_len_price = next_rep_match_price
+                    + self.rep_price(state, 0, len_test, state_2, pos_state_next);
+                let opt = &mut self.opts[offset];
+                if cur_and_len_price < opt.price {
+                    opt.price = cur_and_len_price;
+                    opt.pos_prev = cur + 1;
+                    opt.back_prev = 0;
+                    opt.prev_1_is_literal = true;
+                    opt.prev_2 = false;
+                }
+            }
+        }
+
+        // Rep matches.
+        let mut start_len = MATCH_LEN_MIN;
+        for (rep_index, &rep) in reps.iter().enumerate() {
+            let back = p - rep - 1;
+            if buf[back..back + 2] != buf[p..p + 2] {
+                continue;
+            }
+
+            let len_test = 2 + common_prefix(&buf[back + 2..], &buf[p + 2..p + buf_avail]);
+            len_end = self.extend(len_end, cur + len_test);
+
+            let price = rep_match_price + state.pure_rep_price(rep_index, lzma_state, pos_state);
+            for len in MATCH_LEN_MIN..=len_test {
+                let cur_and_len_price = price + self.rep_len_prices.price(len, pos_state);
+                let opt = &mut self.opts[cur + len];
+                if cur_and_len_price < opt.price {
+                    opt.price = cur_and_len_price;
+                    opt.pos_prev = cur;
+                    opt.back_prev = rep_index;
+                    opt.prev_1_is_literal = false;
+                }
+            }
+
+            if rep_index == 0 {
+                start_len = len_test + 1;
+            }
+
+            // Rep match, literal, and rep match with the distance `rep`.
+            let len_test_2 =
+                literal_then_rep0_len(buf, p, back, len_test, buf_avail_full, nice_len);
+            if len_test_2 >= MATCH_LEN_MIN {
+                let state_2 = state_after_rep(lzma_state);
+                let pos_state_next = state.pos_state(position + len_test as u64);
+                let cur_and_len_literal_price = price
+                    + self.rep_len_prices.price(len_test, pos_state)
+                    + state.is_match_price(state_2, pos_state_next, false)
+                    + state.literal_price(
+                        state_2,
+                        position + len_test as u64,
+                        buf[p + len_test],
+                        buf[p + len_test - 1],
+                        buf[back + len_test],
+                    );
+
+                let state_2 = state_after_literal(state_2);
+                let pos_state_next = state.pos_state(position + len_test as u64 + 1);
+                let next_rep_match_price = cur_and_len_literal_price
+                    + state.is_match_price(state_2, pos_state_next, true)
+                    + state.is_rep_price(state_2, true);
+
+                let offset = cur + len_test + 1 + len_test_2;
+                len_end = self.extend(len_end, offset);
+
+                let cur_and_len_price = next_rep_match_price
+                    + self.rep_price(state, 0, len_test_2, state_2, pos_state_next);
+                let opt = &mut self.opts[offset];
+                if cur_and_len_price < opt.price {
+                    opt.price = cur_and_len_price;
+                    opt.pos_prev = cur + len_test + 1;
+                    opt.back_prev = 0;
+                    opt.prev_1_is_literal = true;
+                    opt.prev_2 = true;
+                    opt.pos_prev_2 = cur;
+                    opt.back_prev_2 = rep_index;
+                }
+            }
+        }
+
+        // Matches, only considering the lengths not covered by the rep match
+        // with the distance `reps[0]`.
+        if new_len > buf_avail {
+            new_len = buf_avail;
+            let count = matches.iter().position(|m| m.len >= new_len).unwrap();
+            matches[count].len = new_len;
+            matches.truncate(count + 1);
+        }
+
+        if new_len >= start_len {
+            let normal_match_price = match_price + state.is_rep_price(lzma_state, false);
+            len_end = self.extend(len_end, cur + new_len);
+
+            let mut i = 0;
+            while start_len > matches[i].len {
+                i += 1;
+            }
+
+            let mut len_test = start_len;
+            loop {
+                let cur_back = matches[i].dist;
+                let cur_and_len_price =
+                    normal_match_price + self.dist_len_price(cur_back, len_test, pos_state);
+                let opt = &mut self.opts[cur + len_test];
+                if cur_and_len_price < opt.price {
+                    opt.price = cur_and_len_price;
+                    opt.pos_prev = cur;
+                    opt.back_prev = cur_back + REPS;
+                    opt.prev_1_is_literal = false;
+                }
+
+                if len_test == matches[i].len {
+                    // Match, literal, and rep match with the same distance.
+                    let back = p - cur_back - 1;
+                    let len_test_2 =
+                        literal_then_rep0_len(buf, p, back, len_test, buf_avail_full, nice_len);
+                    if len_test_2 >= MATCH_LEN_MIN {
+                        let state_2 = state_after_match(lzma_state);
+                        let pos_state_next = state.pos_state(position + len_test as u64);
+                        let cur_and_len_literal_price = cur_and_len_price
+                            + state.is_match_price(state_2, pos_state_next, false)
+                            + state.literal_price(
+                                state_2,
+                                position + len_test as u64,
+                                buf[p + len_test],
+                                buf[p + len_test - 1],
+                                buf[back + len_test],
+                            );
+
+                        let state_2 = state_after_literal(state_2);
+                        let pos_state_next = state.pos_state(position + len_test as u64 + 1);
+                        let next_rep_match_price = cur_and_len_literal_price
+                            + state.is_match_price(state_2, pos_state_next, true)
+                            + state.is_rep_price(state_2, true);
+
+                        let offset = cur + len_test + 1 + len_test_2;
+                        len_end = self.extend(len_end, offset);
+
+                        let cur_and_len_price = next_rep_match_price
+                            + self.rep_price(state, 0, len_test_2, state_2, pos_state_next);
+                        let opt = &mut self.opts[offset];
+                        if cur_and_len_price < opt.price {
+                            opt.price = cur_and_len_price;
+                            opt.pos_prev = cur + len_test + 1;
+                            opt.back_prev = 0;
+                            opt.prev_1_is_literal = true;
+                            opt.prev_2 = true;
+                            opt.pos_prev_2 = cur;
+                            opt.back_prev_2 = cur_back + REPS;
+                        }
+                    }
+
+                    i += 1;
+                    if i == matches.len() {
+                        break;
+                    }
+                }
+                len_test += 1;
+            }
+        }
+
+        len_end
+    }
+
+    /// Make the positions up to `offset` reachable, initializing the new
+    /// positions with an infinite price.
+    fn extend(&mut self, len_end: usize, offset: usize) -> usize {
+        if len_end < offset {
+            for opt in &mut self.opts[len_end + 1..=offset] {
+                opt.price = INFINITY_PRICE;
+            }
+            offset
+        } else {
+            len_end
+        }
+    }
+
+    /// Follow the cheapest path ending at the position `cur` backwards,
+    /// linking each position to the next one, and return the first packet.
+    fn backward(&mut self, mut cur: usize) -> Packet {
+        self.opts_end_index = cur;
+        let mut pos_mem = self.opts[cur].pos_prev;
+        let mut back_mem = self.opts[cur].back_prev;
+        loop {
+            if self.opts[cur].prev_1_is_literal {
+                self.opts[pos_mem].make_literal();
+                self.opts[pos_mem].pos_prev = pos_mem - 1;
+                if self.opts[cur].prev_2 {
+                    let opt = self.opts[cur];
+                    let prev = &mut self.opts[pos_mem - 1];
+                    prev.prev_1_is_literal = false;
+                    prev.pos_prev = opt.pos_prev_2;
+                    prev.back_prev = opt.back_prev_2;
+                }
+            }
+
+            let pos_prev = pos_mem;
+            let back_cur = back_mem;
+            back_mem = self.opts[pos_prev].back_prev;
+            pos_mem = self.opts[pos_prev].pos_prev;
+            self.opts[pos_prev].back_prev = back_cur;
+            self.opts[pos_prev].pos_prev = cur;
+            cur = pos_prev;
+            if cur == 0 {
+                break;
+            }
+        }
+
+        self.opts_current_index = self.opts[0].pos_prev;
+        packet(self.opts[0].back_prev, self.opts[0].pos_prev)
+    }
+}
+
+/// Length of the rep match following a packet of length `len` at
+/// distance `p - back` and a literal, reusing that distance.
+fn literal_then_rep0_len(
+    buf: &[u8],
+    p: usize,
+    back: usize,
+    len: usize,
+    buf_avail_full: usize,
+    nice_len: usize,
+) -> usize {
+    let start = len + 1;
+    let limit = cmp::min(buf_avail_full, start + nice_len);
+    if start < limit {
+        common_prefix(&buf[back + start..], &buf[p + start..p + limit])
+    } else {
+        0
+    }
+}
@@ -1,9 +1,506 @@
-/// Options for the `lzma_compress` function.
-#[derive(Clone, Copy, Debug, Default)]
+use crate::xz::bcj::{Bcj, BcjArch};
+use crate::xz::delta::Delta;
+use crate::xz::filter::Converter;
+pub use crate::xz::CheckMethod;
+use std::io;
+
+/// Options for the `lzma_compress` and `lzma2_compress` functions, and for
+/// the LZMA2 filter of `xz_compress`.
+///
+/// The default options correspond to the preset level 6, see
+/// [`Options::preset`].
+#[derive(Clone, Copy, Debug)]
 pub struct Options {
     /// Defines whether the unpacked size should be written to the header.
     /// The default is [`UnpackedSize::WriteToHeader(None)`].
+    ///
+    /// This option only applies to the LZMA format.
     pub unpacked_size: UnpackedSize,
+    /// The number of literal context bits, between 0 and 8.
+    ///
+    /// The most `lc` significant bits of the previous byte are part of the
+    /// literal context. The default is 3.
+    pub lc: u32,
+    /// The number of literal position bits, between 0 and 4.
+    ///
+    /// Note that liblzma only supports `lc + lp <= 4`. The default is 0.
+    pub lp: u32,
+    /// The number of position bits, between 0 and 4.
+    ///
+    /// The context for literal/match is plaintext offset modulo `2^pb`. The
+    /// default is 2.
+    pub pb: u32,
+    /// The dictionary size, i.e. the maximum distance of a match, between
+    /// 4 KiB and 1.5 GiB.
+    ///
+    /// The decoder needs a buffer of this size. The default is 8 MiB.
+    pub dict_size: u32,
+    /// How the encoder selects packets, see [`Mode`]. The default is
+    /// [`Mode::Normal`].
+    pub mode: Mode,
+    /// The match finder, see [`MatchFinder`]. The default is
+    /// [`MatchFinder::Bt4`].
+    pub match_finder: MatchFinder,
+    /// Matches of at least this length are selected without looking for
+    /// longer ones, between 2 and 273.
+    ///
+    /// Higher values improve the compression ratio at the expense of speed.
+    /// The default is 64.
+    pub nice_len: u32,
+    /// Maximum number of candidates visited by the match finder at each
+    /// position, or 0 to derive it from `nice_len` and `match_finder`.
+    ///
+    /// Higher values improve the compression ratio at the expense of speed.
+    /// The default is 0.
+    pub depth: u32,
+}
+
+/// Minimum dictionary size, see [`Options::dict_size`].
+const DICT_SIZE_MIN: u32 = 0x1000;
+
+/// Maximum dictionary size, see [`Options::dict_size`].
+const DICT_SIZE_MAX: u32 = 0x6000_0000;
+
+/// Minimum value of [`Options::nice_len`].
+const NICE_LEN_MIN: u32 = 2;
+
+/// Maximum value of [`Options::nice_len`].
+const NICE_LEN_MAX: u32 = 273;
+
+/// Default preset level.
+const PRESET_DEFAULT: u32 = 6;
+
+impl Default for Options {
+    fn default() -> Options {
+        Options::preset(PRESET_DEFAULT, false).unwrap()
+    }
+}
+
+impl Options {
+    /// Options equivalent to the `xz -0` ... `xz -9` presets, or to
+    /// `xz -0e` ... `xz -9e` if `extreme` is set.
+    ///
+    /// Higher levels compress better but more slowly, and use a larger
+    /// dictionary: from 256 KiB at level 0 to 64 MiB at level 9. The extreme
+    /// variants search longer for matches without changing the dictionary
+    /// size. Returns an error if `level` is greater than 9.
+    pub fn preset(level: u32, extreme: bool) -> io::Result<Options> {
+        const DICT_POW2: [u32; 10] = [18, 20, 21, 22, 22, 23, 23, 24, 25, 26];
+        const FAST_DEPTH: [u32; 4] = [4, 8, 24, 48];
+
+        if level > 9 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid preset level {}, must be at most 9", level),
+            ));
+        }
+
+        let mut options = Options {
+            unpacked_size: UnpackedSize::default(),
+            lc: 3,
+            lp: 0,
+            pb: 2,
+            dict_size: 1 << DICT_POW2[level as usize],
+            mode: Mode::Normal,
+            match_finder: MatchFinder::Bt4,
+            nice_len: 0,
+            depth: 0,
+        };
+
+        if level <= 3 {
+            options.mode = Mode::Fast;
+            options.match_finder = if level == 0 {
+                MatchFinder::Hc3
+            } else {
+                MatchFinder::Hc4
+            };
+            options.nice_len = if level <= 1 { 128 } else { 273 };
+            options.depth = FAST_DEPTH[level as usize];
+        } else {
+            options.nice_len = match level {
+                4 => 16,
+                5 => 32,
+                _ => 64,
+            };
+        }
+
+        if extreme {
+            options.mode = Mode::Normal;
+            options.match_finder = MatchFinder::Bt4;
+            if level == 3 || level == 5 {
+                options.nice_len = 192;
+                options.depth = 0;
+            } else {
+                options.nice_len = 273;
+                options.depth = 512;
+            }
+        }
+
+        Ok(options)
+    }
+
+    /// Approximate amount of memory in bytes used by the encoder with these
+    /// options.
+    ///
+    /// This is dominated by the match finder: the window and the hash tables
+    /// grow with the dictionary size, and binary trees use twice as much
+    /// memory as hash chains.
+    pub fn memusage(&self) -> u64 {
+        crate::encode::lzma::memusage(self)
+    }
+
+    /// Check that the options are within their valid ranges.
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        if self.lc > 8 || self.lp > 4 || self.pb > 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid properties {{ lc: {}, lp: {}, pb: {} }}, must be at most {{ lc: 8, lp: 4, pb: 4 }}",
+                    self.lc, self.lp, self.pb
+                ),
+            ));
+        }
+
+        if !(DICT_SIZE_MIN..=DICT_SIZE_MAX).contains(&self.dict_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid dictionary size {}, must be between {} and {}",
+                    self.dict_size, DICT_SIZE_MIN, DICT_SIZE_MAX
+                ),
+            ));
+        }
+
+        if !(NICE_LEN_MIN..=NICE_LEN_MAX).contains(&self.nice_len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid nice length {}, must be between {} and {}",
+                    self.nice_len, NICE_LEN_MIN, NICE_LEN_MAX
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Options for the `xz_compress` function.
+#[derive(Clone, Debug)]
+pub struct XzOptions {
+    /// Options of the LZMA2 filter. The default is [`Options::default`].
+    pub lzma2: Options,
+    /// The integrity check computed on the uncompressed data of each block.
+    /// The default is [`CheckMethod::Crc64`], like `xz`.
+    pub check: CheckMethod,
+    /// Filters applied to the data before LZMA2, in this order, at most
+    /// [`FILTERS_MAX`]. The default is no filter.
+    ///
+    /// For example, `xz --x86 --lzma2` corresponds to
+    /// `vec![Filter::X86(BcjOptions::default())]`.
+    pub filters: Vec<Filter>,
+    /// Maximum uncompressed size of each block, like `xz --block-size`.
+    ///
+    /// Splitting the data into blocks records their sizes in the Block
+    /// Headers and in the Index, so that they can be located without
+    /// decompressing the data before them. The default is [`None`], which
+    /// encodes the data as a single block streamed without recording its
+    /// sizes in its header.
+    pub block_size: Option<u64>,
+    /// Uncompressed sizes of the successive blocks, like `xz --block-list`.
+    ///
+    /// The last size is repeated until the end of the data, unless it is 0
+    /// which puts the rest of the data in a single block, streamed without
+    /// recording its sizes in its header. Blocks larger than
+    /// [`XzOptions::block_size`] are split further. The default is an empty
+    /// list.
+    pub block_list: Vec<u64>,
+    /// Number of threads compressing blocks in parallel, like
+    /// `xz --threads`, or 0 for the number of available CPUs. The default is
+    /// 1.
+    ///
+    /// Only the blocks of a bounded size, set by [`XzOptions::block_size`] or
+    /// [`XzOptions::block_list`], are compressed on other threads, so the
+    /// output is the same for any number of threads. Each thread holds a
+    /// whole block, both uncompressed and compressed, and uses as much
+    /// memory as the LZMA2 encoder, see [`Options::memusage`].
+    pub threads: u32,
+}
+
+impl Default for XzOptions {
+    fn default() -> XzOptions {
+        XzOptions {
+            lzma2: Options::default(),
+            check: CheckMethod::Crc64,
+            filters: Vec::new(),
+            block_size: None,
+            block_list: Vec::new(),
+            threads: 1,
+        }
+    }
+}
+
+impl XzOptions {
+    /// Check that the filter chain and the block sizes are valid. The LZMA2
+    /// options are checked by its encoder.
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        if self.filters.len() > FILTERS_MAX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid filter chain of {} filters, must be at most {}",
+                    self.filters.len(),
+                    FILTERS_MAX
+                ),
+            ));
+        }
+        for filter in &self.filters {
+            filter.validate()?;
+        }
+        if self.block_size == Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid block size 0",
+            ));
+        }
+        let list_len = self.block_list.len();
+        if list_len > 1 && self.block_list[..list_len - 1].contains(&0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid block list, only its last size can be 0",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Number of threads to compress with, resolving 0.
+    pub(crate) fn thread_count(&self) -> usize {
+        match self.threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            threads => threads as usize,
+        }
+    }
+
+    /// Whether the data is split into blocks whose sizes are recorded in
+    /// their headers.
+    pub(crate) fn has_blocks(&self) -> bool {
+        self.block_size.is_some() || !self.block_list.is_empty()
+    }
+}
+
+/// Maximum number of filters before LZMA2 in [`XzOptions::filters`].
+pub const FILTERS_MAX: usize = 3;
+
+/// Filter converting the data before LZMA2 in an XZ block, see
+/// [`XzOptions::filters`].
+///
+/// The Branch/Call/Jump (BCJ) filters make executable code for an
+/// instruction set more compressible, by converting the relative addresses of
+/// its branch instructions into absolute addresses. They are harmless on
+/// other data, beside a small slowdown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    /// Delta filter, like `xz --delta`.
+    Delta(DeltaOptions),
+    /// BCJ filter for x86 and x86-64 code, like `xz --x86`.
+    X86(BcjOptions),
+    /// BCJ filter for big-endian PowerPC code, like `xz --powerpc`.
+    PowerPc(BcjOptions),
+    /// BCJ filter for IA-64 (Itanium) code, like `xz --ia64`.
+    Ia64(BcjOptions),
+    /// BCJ filter for 32-bit ARM code, like `xz --arm`.
+    Arm(BcjOptions),
+    /// BCJ filter for ARM-Thumb code, like `xz --armthumb`.
+    ArmThumb(BcjOptions),
+    /// BCJ filter for SPARC code, like `xz --sparc`.
+    Sparc(BcjOptions),
+    /// BCJ filter for ARM64 code, like `xz --arm64`.
+    Arm64(BcjOptions),
+    /// BCJ filter for RISC-V code, like `xz --riscv`.
+    RiscV(BcjOptions),
+}
+
+/// Options of the Delta filter, see [`Filter::Delta`] and
+/// [`DeltaEncoder`](crate::compress::DeltaEncoder).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeltaOptions {
+    /// Distance between the bytes subtracted from each other, between 1 and
+    /// 256. For example, this is 2 for 16-bit mono audio samples, or 4 for
+    /// 32-bit RGBA pixels. The default is 1.
+    pub dist: u32,
+}
+
+impl Default for DeltaOptions {
+    fn default() -> DeltaOptions {
+        DeltaOptions { dist: 1 }
+    }
+}
+
+impl DeltaOptions {
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        if !(1..=256).contains(&self.dist) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid delta distance {}, must be between 1 and 256",
+                    self.dist
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Options of the BCJ filters, see [`Filter`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BcjOptions {
+    /// Address of the first byte of the data, used to convert the addresses.
+    /// This must be a multiple of the instruction alignment of the filter,
+    /// e.g. 4 for ARM64 or 16 for IA-64. The default is 0.
+    pub start_offset: u32,
+}
+
+impl Filter {
+    /// Filter ID, see sect. 5.3 of the XZ spec.
+    pub(crate) fn id(&self) -> u64 {
+        match self {
+            Filter::Delta(_) => 0x03,
+            Filter::X86(_) => 0x04,
+            Filter::PowerPc(_) => 0x05,
+            Filter::Ia64(_) => 0x06,
+            Filter::Arm(_) => 0x07,
+            Filter::ArmThumb(_) => 0x08,
+            Filter::Sparc(_) => 0x09,
+            Filter::Arm64(_) => 0x0A,
+            Filter::RiscV(_) => 0x0B,
+        }
+    }
+
+    /// Filter Properties, as written in the Block Header.
+    pub(crate) fn props(&self) -> Vec<u8> {
+        match self.kind() {
+            FilterKind::Delta(options) => vec![(options.dist - 1) as u8],
+            FilterKind::Bcj(_, options) if options.start_offset == 0 => Vec::new(),
+            FilterKind::Bcj(_, options) => options.start_offset.to_le_bytes().to_vec(),
+        }
+    }
+
+    /// Converter applying this filter if `encoding`, or reverting it.
+    pub(crate) fn converter(&self, encoding: bool) -> Converter {
+        match self.kind() {
+            FilterKind::Delta(options) => {
+                Converter::Delta(Box::new(Delta::new(&options, encoding)))
+            }
+            FilterKind::Bcj(arch, options) => {
+                Converter::Bcj(Bcj::new(arch, options.start_offset, encoding))
+            }
+        }
+    }
+
+    /// BCJ filter for the given instruction set.
+    pub(crate) fn bcj(arch: BcjArch, options: BcjOptions) -> Filter {
+        match arch {
+            BcjArch::X86 => Filter::X86(options),
+            BcjArch::PowerPc => Filter::PowerPc(options),
+            BcjArch::Ia64 => Filter::Ia64(options),
+            BcjArch::Arm => Filter::Arm(options),
+            BcjArch::ArmThumb => Filter::ArmThumb(options),
+            BcjArch::Sparc => Filter::Sparc(options),
+            BcjArch::Arm64 => Filter::Arm64(options),
+            BcjArch::RiscV => Filter::RiscV(options),
+        }
+    }
+
+    fn kind(&self) -> FilterKind {
+        match *self {
+            Filter::Delta(options) => FilterKind::Delta(options),
+            Filter::X86(options) => FilterKind::Bcj(BcjArch::X86, options),
+            Filter::PowerPc(options) => FilterKind::Bcj(BcjArch::PowerPc, options),
+            Filter::Ia64(options) => FilterKind::Bcj(BcjArch::Ia64, options),
+            Filter::Arm(options) => FilterKind::Bcj(BcjArch::Arm, options),
+            Filter::ArmThumb(options) => FilterKind::Bcj(BcjArch::ArmThumb, options),
+            Filter::Sparc(options) => FilterKind::Bcj(BcjArch::Sparc, options),
+            Filter::Arm64(options) => FilterKind::Bcj(BcjArch::Arm64, options),
+            Filter::RiscV(options) => FilterKind::Bcj(BcjArch::RiscV, options),
+        }
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        match self.kind() {
+            FilterKind::Delta(options) => options.validate(),
+            FilterKind::Bcj(arch, options) => {
+                let alignment = arch.alignment();
+                if options.start_offset % alignment != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "Invalid start offset {} for filter {:?}, must be a multiple of {}",
+                            options.start_offset, arch, alignment
+                        ),
+                    ));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Options of a [`Filter`], grouped by kind of filter.
+enum FilterKind {
+    Delta(DeltaOptions),
+    Bcj(BcjArch, BcjOptions),
+}
+
+/// Strategy of the encoder to select the packets encoding the input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Greedy selection of the longest match, with a one-byte lookahead for
+    /// a better one.
+    Fast,
+    /// Selection of the cheapest sequence of packets over a lookahead window,
+    /// using bit prices derived from the adaptive probabilities. This is
+    /// slower but compresses better.
+    Normal,
+}
+
+/// Match finder used by the encoder to find repeated sequences in the
+/// dictionary.
+///
+/// Hash chains (`Hc*`) are faster, while binary trees (`Bt*`) find longer
+/// matches and yield a better compression ratio. The number is how many bytes
+/// are hashed to look up match candidates. Finders hashing more bytes are
+/// faster but miss some short matches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MatchFinder {
+    /// Hash chain with 2- and 3-byte hashing.
+    Hc3,
+    /// Hash chain with 2-, 3- and 4-byte hashing.
+    Hc4,
+    /// Binary tree with 2-byte hashing.
+    Bt2,
+    /// Binary tree with 2- and 3-byte hashing.
+    Bt3,
+    /// Binary tree with 2-, 3- and 4-byte hashing.
+    Bt4,
+}
+
+impl MatchFinder {
+    /// Number of bytes hashed to look up match candidates.
+    pub(crate) fn hash_bytes(self) -> usize {
+        match self {
+            MatchFinder::Bt2 => 2,
+            MatchFinder::Hc3 | MatchFinder::Bt3 => 3,
+            MatchFinder::Hc4 | MatchFinder::Bt4 => 4,
+        }
+    }
+
+    /// Whether this match finder uses binary trees rather than hash chains.
+    pub(crate) fn is_bt(self) -> bool {
+        match self {
+            MatchFinder::Hc3 | MatchFinder::Hc4 => false,
+            MatchFinder::Bt2 | MatchFinder::Bt3 | MatchFinder::Bt4 => true,
+        }
+    }
 }
 
 /// Alternatives for handling unpacked size.
@@ -27,3 +524,162 @@ impl Default for UnpackedSize {
         UnpackedSize::WriteToHeader(None)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(Options::default().validate().is_ok());
+        for (lc, lp, pb) in [(9, 0, 0), (0, 5, 0), (0, 0, 5)] {
+            let options = Options {
+                lc,
+                lp,
+                pb,
+                ..Default::default()
+            };
+            assert_eq!(
+                options.validate().unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+        for dict_size in [0, DICT_SIZE_MIN - 1, DICT_SIZE_MAX + 1] {
+            let options = Options {
+                dict_size,
+                ..Default::default()
+            };
+            assert_eq!(
+                options.validate().unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+        for nice_len in [0, NICE_LEN_MIN - 1, NICE_LEN_MAX + 1] {
+            let options = Options {
+                nice_len,
+                ..Default::default()
+            };
+            assert_eq!(
+                options.validate().unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_filters() {
+        let bcj = BcjOptions::default();
+        let options = XzOptions {
+            filters: vec![Filter::X86(bcj), Filter::Arm64(bcj), Filter::RiscV(bcj)],
+            ..Default::default()
+        };
+        assert!(options.validate().is_ok());
+
+        let options = XzOptions {
+            filters: vec![Filter::X86(bcj); FILTERS_MAX + 1],
+            ..Default::default()
+        };
+        assert_eq!(
+            options.validate().unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        let unaligned = BcjOptions { start_offset: 8 };
+        for (filter, valid) in [
+            (Filter::X86(unaligned), true),
+            (Filter::Arm64(unaligned), true),
+            (Filter::Ia64(unaligned), false),
+            (Filter::Delta(DeltaOptions { dist: 256 }), true),
+            (Filter::Delta(DeltaOptions { dist: 0 }), false),
+            (Filter::Delta(DeltaOptions { dist: 257 }), false),
+        ] {
+            let options = XzOptions {
+                filters: vec![filter],
+                ..Default::default()
+            };
+            assert_eq!(options.validate().is_ok(), valid, "{:?}", filter);
+        }
+    }
+
+    #[test]
+    fn test_validate_blocks() {
+        for (block_size, block_list, valid) in [
+            (Some(1), vec![], true),
+            (Some(0), vec![], false),
+            (None, vec![0], true),
+            (None, vec![10, 0], true),
+            (Some(4), vec![0, 10], false),
+        ] {
+            let options = XzOptions {
+                block_size,
+                block_list: block_list.clone(),
+                ..Default::default()
+            };
+            assert_eq!(
+                options.validate().is_ok(),
+                valid,
+                "{:?} {:?}",
+                block_size,
+                block_list
+            );
+        }
+    }
+
+    #[test]
+    fn test_presets() {
+        for level in 0..=9 {
+            for extreme in [false, true] {
+                let options = Options::preset(level, extreme).unwrap();
+                assert!(options.validate().is_ok());
+            }
+        }
+        assert_eq!(
+            Options::preset(10, false).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        let options = Options::preset(0, false).unwrap();
+        assert_eq!(options.mode, Mode::Fast);
+        assert_eq!(options.dict_size, 0x4_0000);
+        assert_eq!(options.match_finder, MatchFinder::Hc3);
+        assert_eq!(options.nice_len, 128);
+        assert_eq!(options.depth, 4);
+
+        let options = Options::default();
+        assert_eq!(options.mode, Mode::Normal);
+        assert_eq!(options.dict_size, 0x80_0000);
+        assert_eq!(options.match_finder, MatchFinder::Bt4);
+        assert_eq!(options.nice_len, 64);
+        assert_eq!(options.depth, 0);
+
+        let options = Options::preset(3, true).unwrap();
+        assert_eq!(options.mode, Mode::Normal);
+        assert_eq!(options.match_finder, MatchFinder::Bt4);
+        assert_eq!(options.nice_len, 192);
+
+        let options = Options::preset(9, true).unwrap();
+        assert_eq!(options.dict_size, 0x400_0000);
+        assert_eq!(options.nice_len, 273);
+        assert_eq!(options.depth, 512);
+    }
+
+    #[test]
+    fn test_memusage() {
+        for level in 1..=9 {
+            let smaller = Options::preset(level - 1, false).unwrap();
+            let larger = Options::preset(level, false).unwrap();
+            assert!(smaller.memusage() <= larger.memusage());
+        }
+
+        let hc4 = Options {
+            match_finder: MatchFinder::Hc4,
+            ..Default::default()
+        };
+        let bt4 = Options {
+            match_finder: MatchFinder::Bt4,
+            ..Default::default()
+        };
+        assert!(hc4.memusage() < bt4.memusage());
+        assert!(bt4.memusage() > 4 * bt4.dict_size as u64);
+    }
+}
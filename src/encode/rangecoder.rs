@@ -1,26 +1,103 @@
+use crate::util::const_assert;
 use byteorder::WriteBytesExt;
 use std::io;
 
-#[cfg(test)]
-use crate::util::const_assert;
+/// Prices are expressed in fractions of bits, with this many fractional
+/// bits.
+const PRICE_SHIFT_BITS: u32 = 4;
+
+/// Number of low bits of the probabilities ignored when computing prices.
+const PRICE_REDUCING_BITS: u32 = 4;
+
+/// Price larger than the price of any sequence of packets.
+pub const INFINITY_PRICE: u32 = 1 << 30;
+
+/// Price of encoding a 0 bit, indexed by the probability of a 0 bit
+/// without its low `PRICE_REDUCING_BITS` bits, i.e. an approximation of
+/// `-log2(prob / 0x800)` in fixed point.
+const PRICES: [u32; 0x800 >> PRICE_REDUCING_BITS] = price_table();
+
+const fn price_table() -> [u32; 0x800 >> PRICE_REDUCING_BITS] {
+    let mut table = [0; 0x800 >> PRICE_REDUCING_BITS];
+    let mut i = (1 << PRICE_REDUCING_BITS) / 2;
+    while i < 0x800 {
+        // Compute the logarithm by repeated squaring, counting the bits
+        // shifted out to keep `w` within 16 bits.
+        let mut w: u32 = i;
+        let mut bit_count = 0;
+        let mut j = 0;
+        while j < PRICE_SHIFT_BITS {
+            w *= w;
+            bit_count <<= 1;
+            while w >= 1 << 16 {
+                w >>= 1;
+                bit_count += 1;
+            }
+            j += 1;
+        }
+        table[(i >> PRICE_REDUCING_BITS) as usize] = (11 << PRICE_SHIFT_BITS) - 15 - bit_count;
+        i += 1 << PRICE_REDUCING_BITS;
+    }
+    table
+}
+
+/// Price of encoding `bit` with the probability `prob`.
+pub fn bit_price(prob: u16, bit: bool) -> u32 {
+    let prob = if bit { prob ^ 0x7FF } else { prob };
+    PRICES[(prob >> PRICE_REDUCING_BITS) as usize]
+}
+
+/// Price of encoding `count` bits with [`RangeEncoder::encode_direct_bits`].
+pub fn direct_bits_price(count: usize) -> u32 {
+    (count as u32) << PRICE_SHIFT_BITS
+}
+
+/// Price of encoding `value` with [`RangeEncoder::encode_reverse_bit_tree`].
+pub fn reverse_bit_tree_price(
+    num_bits: usize,
+    probs: &[u16],
+    offset: usize,
+    mut value: u32,
+) -> u32 {
+    let mut price = 0;
+    let mut tmp: usize = 1;
+    for _ in 0..num_bits {
+        let bit = (value & 1) != 0;
+        value >>= 1;
+        price += bit_price(probs[offset + tmp], bit);
+        tmp = (tmp << 1) ^ (bit as usize);
+    }
+    price
+}
+
+fn bit_tree_price(num_bits: usize, probs: &[u16], value: u32) -> u32 {
+    let mut price = 0;
+    let mut tmp: usize = 1;
+    for i in 0..num_bits {
+        let bit = ((value >> (num_bits - i - 1)) & 1) != 0;
+        price += bit_price(probs[tmp], bit);
+        tmp = (tmp << 1) ^ (bit as usize);
+    }
+    price
+}
 
-pub struct RangeEncoder<'a, W>
+pub struct RangeEncoder<W>
 where
-    W: 'a + io::Write,
+    W: io::Write,
 {
-    stream: &'a mut W,
+    stream: W,
     range: u32,
     low: u64,
     cache: u8,
     cachesz: u32,
 }
 
-impl<'a, W> RangeEncoder<'a, W>
+impl<W> RangeEncoder<W>
 where
     W: io::Write,
 {
     #[allow(clippy::let_and_return)]
-    pub fn new(stream: &'a mut W) -> Self {
+    pub fn new(stream: W) -> Self {
         let enc = Self {
             stream,
             range: 0xFFFF_FFFF,
@@ -32,6 +109,26 @@ where
         enc
     }
 
+    /// Gets a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.stream
+    }
+
+    /// Gets a mutable reference to the underlying writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.stream
+    }
+
+    /// Unwraps the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.stream
+    }
+
+    /// Maximum number of bytes that remain to be written by `finish`.
+    pub fn pending_len(&self) -> usize {
+        self.cachesz as usize + 4
+    }
+
     fn write_low(&mut self) -> io::Result<()> {
         if self.low < 0xFF00_0000 || self.low > 0xFFFF_FFFF {
             let mut tmp = self.cache;
@@ -106,7 +203,18 @@ where
         self.normalize()
     }
 
-    #[cfg(test)]
+    pub fn encode_direct_bits(&mut self, count: usize, value: u32) -> io::Result<()> {
+        debug_assert!(value.leading_zeros() as usize + count >= 32);
+        for i in (0..count).rev() {
+            self.range >>= 1;
+            if (value >> i) & 1 != 0 {
+                self.low += self.range as u64;
+            }
+            self.normalize()?;
+        }
+        Ok(())
+    }
+
     fn encode_bit_tree(
         &mut self,
         num_bits: usize,
@@ -123,7 +231,6 @@ where
         Ok(())
     }
 
-    #[cfg(test)]
     pub fn encode_reverse_bit_tree(
         &mut self,
         num_bits: usize,
@@ -143,13 +250,11 @@ where
     }
 }
 
-#[cfg(test)]
 #[derive(Debug, Clone)]
 pub struct BitTree<const PROBS_ARRAY_LEN: usize> {
     probs: [u16; PROBS_ARRAY_LEN],
 }
 
-#[cfg(test)]
 impl<const PROBS_ARRAY_LEN: usize> BitTree<PROBS_ARRAY_LEN> {
     pub fn new() -> Self {
         // The validity of PROBS_ARRAY_LEN is checked at compile-time with a macro
@@ -194,9 +299,16 @@ impl<const PROBS_ARRAY_LEN: usize> BitTree<PROBS_ARRAY_LEN> {
     ) -> io::Result<()> {
         rangecoder.encode_reverse_bit_tree(Self::NUM_BITS, &mut self.probs, 0, value)
     }
+
+    pub fn price(&self, value: u32) -> u32 {
+        bit_tree_price(Self::NUM_BITS, &self.probs, value)
+    }
+
+    pub fn reverse_price(&self, value: u32) -> u32 {
+        reverse_bit_tree_price(Self::NUM_BITS, &self.probs, 0, value)
+    }
 }
 
-#[cfg(test)]
 pub struct LenEncoder {
     choice: u16,
     choice2: u16,
@@ -205,7 +317,6 @@ pub struct LenEncoder {
     high_coder: BitTree<{ 1 << 8 }>,
 }
 
-#[cfg(test)]
 impl LenEncoder {
     pub fn new() -> Self {
         LenEncoder {
@@ -271,6 +382,21 @@ impl LenEncoder {
 
         self.high_coder.encode(rangecoder, value - 16)
     }
+
+    pub fn price(&self, pos_state: usize, value: u32) -> u32 {
+        if value < 8 {
+            return bit_price(self.choice, false) + self.low_coder[pos_state].price(value);
+        }
+
+        let price = bit_price(self.choice, true);
+        if value < 16 {
+            return price
+                + bit_price(self.choice2, false)
+                + self.mid_coder[pos_state].price(value - 8);
+        }
+
+        price + bit_price(self.choice2, true) + self.high_coder.price(value - 16)
+    }
 }
 
 #[cfg(test)]
@@ -433,4 +559,18 @@ mod test {
             encode_decode_length(pos_state, &values);
         }
     }
+    #[test]
+    fn test_bit_price() {
+        for prob in 1..0x800 {
+            // Prices approximate the information content in 1/16 of bits, for
+            // the probability at the middle of the range sharing the price.
+            let rounded = (prob & !0xF) | 0x8;
+            let expected = -(rounded as f64 / 2048.0).log2() * 16.0;
+            let price = bit_price(prob, false) as f64;
+            assert!((price - expected).abs() <= 1.0, "{} {}", price, expected);
+
+            assert_eq!(bit_price(prob, true), bit_price(prob ^ 0x7FF, false));
+        }
+        assert_eq!(bit_price(0x400, false), 1 << PRICE_SHIFT_BITS);
+    }
 }
@@ -0,0 +1,359 @@
+use crate::encode::options::{Options, XzOptions};
+use crate::encode::{lzma, xz};
+use std::fmt::Debug;
+use std::io::{self, BufRead, Read};
+
+/// Encoder writing its compressed bytes into a [`Vec`], which readers drive.
+pub(crate) trait StreamEncoder {
+    fn append(&mut self, data: &[u8]) -> io::Result<usize>;
+    fn output_mut(&mut self) -> &mut Vec<u8>;
+    fn finish(self) -> io::Result<Vec<u8>>;
+}
+
+impl StreamEncoder for lzma::Encoder<Vec<u8>> {
+    fn append(&mut self, data: &[u8]) -> io::Result<usize> {
+        lzma::Encoder::append(self, data)
+    }
+
+    fn output_mut(&mut self) -> &mut Vec<u8> {
+        self.get_mut()
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        lzma::Encoder::finish(self)
+    }
+}
+
+impl StreamEncoder for xz::Encoder<Vec<u8>> {
+    fn append(&mut self, data: &[u8]) -> io::Result<usize> {
+        xz::Encoder::append(self, data)
+    }
+
+    fn output_mut(&mut self) -> &mut Vec<u8> {
+        self.get_mut()
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        xz::Encoder::finish(self)
+    }
+}
+
+/// Adapter feeding the input of a [`BufRead`] to an encoder as the
+/// compressed bytes are read.
+struct EncoderReader<R, E> {
+    input: R,
+    /// Encoder, or [`None`] once it is finished or failed.
+    encoder: Option<E>,
+    /// Whether encoding failed, in which case reading keeps failing instead
+    /// of reporting the end of the data.
+    failed: bool,
+    /// Compressed bytes not yet read.
+    output: Vec<u8>,
+    /// Position of the next byte to read in `output`.
+    pos: usize,
+}
+
+impl<R, E> EncoderReader<R, E>
+where
+    R: BufRead,
+    E: StreamEncoder,
+{
+    fn new(input: R, mut encoder: E) -> Self {
+        // The encoder may already have written a header.
+        let output = std::mem::take(encoder.output_mut());
+        Self {
+            input,
+            encoder: Some(encoder),
+            failed: false,
+            output,
+            pos: 0,
+        }
+    }
+
+    /// Feed the encoder until it produces some output or is finished.
+    fn fill_output(&mut self) -> io::Result<()> {
+        self.output.clear();
+        self.pos = 0;
+        while self.output.is_empty() {
+            if self.failed {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "can't read from stream because of previous encoding error",
+                ));
+            }
+            let encoder = match self.encoder.as_mut() {
+                Some(encoder) => encoder,
+                None => return Ok(()),
+            };
+            let data = self.input.fill_buf()?;
+            if data.is_empty() {
+                let encoder = self.encoder.take().unwrap();
+                match encoder.finish() {
+                    Ok(output) => self.output = output,
+                    Err(e) => {
+                        self.failed = true;
+                        return Err(e);
+                    }
+                }
+            } else {
+                let n = match encoder.append(data) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        self.failed = true;
+                        return Err(e);
+                    }
+                };
+                self.input.consume(n);
+                std::mem::swap(&mut self.output, encoder.output_mut());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R, E> Read for EncoderReader<R, E>
+where
+    R: BufRead,
+    E: StreamEncoder,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos == self.output.len() {
+            self.fill_output()?;
+        }
+        let n = (&self.output[self.pos..]).read(buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl<R, E> Debug for EncoderReader<R, E>
+where
+    R: Debug,
+{
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("EncoderReader")
+            .field("input", &self.input)
+            .field("finished", &self.encoder.is_none())
+            .field("failed", &self.failed)
+            .field("pending", &(self.output.len() - self.pos))
+            .finish()
+    }
+}
+
+/// LZMA compressor implementing [`io::Read`], compressing the data of an
+/// underlying [`BufRead`] as it is read.
+#[derive(Debug)]
+pub struct LzmaEncoderReader<R> {
+    inner: EncoderReader<R, lzma::Encoder<Vec<u8>>>,
+}
+
+impl<R> LzmaEncoderReader<R>
+where
+    R: BufRead,
+{
+    /// Create a reader compressing the data read from `input` with default
+    /// [`Options`].
+    pub fn new(input: R) -> io::Result<Self> {
+        Self::new_with_options(input, &Options::default())
+    }
+
+    /// Create a reader compressing the data read from `input` with the given
+    /// `options`.
+    pub fn new_with_options(input: R, options: &Options) -> io::Result<Self> {
+        let encoder = lzma::Encoder::from_stream(Vec::new(), options)?;
+        Ok(Self {
+            inner: EncoderReader::new(input, encoder),
+        })
+    }
+
+    /// Get a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner.input
+    }
+
+    /// Get a mutable reference to the underlying reader. Reading from it
+    /// skips these bytes from the compressed data.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner.input
+    }
+
+    /// Consume this encoder, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner.input
+    }
+}
+
+impl<R> Read for LzmaEncoderReader<R>
+where
+    R: BufRead,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+/// XZ compressor implementing [`io::Read`], compressing the data of an
+/// underlying [`BufRead`] as it is read.
+#[derive(Debug)]
+pub struct XzEncoderReader<R> {
+    inner: EncoderReader<R, xz::Encoder<Vec<u8>>>,
+}
+
+impl<R> XzEncoderReader<R>
+where
+    R: BufRead,
+{
+    /// Create a reader compressing the data read from `input` with default
+    /// [`XzOptions`].
+    pub fn new(input: R) -> io::Result<Self> {
+        Self::new_with_options(input, &XzOptions::default())
+    }
+
+    /// Create a reader compressing the data read from `input` with the given
+    /// `options`.
+    pub fn new_with_options(input: R, options: &XzOptions) -> io::Result<Self> {
+        let encoder = xz::Encoder::new(Vec::new(), options)?;
+        Ok(Self {
+            inner: EncoderReader::new(input, encoder),
+        })
+    }
+
+    /// Get a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner.input
+    }
+
+    /// Get a mutable reference to the underlying reader. Reading from it
+    /// skips these bytes from the compressed data.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner.input
+    }
+
+    /// Consume this encoder, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner.input
+    }
+}
+
+impl<R> Read for XzEncoderReader<R>
+where
+    R: BufRead,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_chunked<T: Read>(mut reader: T, chunk: usize) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        let mut buf = vec![0; chunk];
+        loop {
+            match reader.read(&mut buf)? {
+                0 => return Ok(output),
+                n => output.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    #[test]
+    fn test_lzma_encoder_reader() {
+        let input = include_bytes!("../../tests/files/foo.txt");
+        for &chunk in &[1, 7, 4096] {
+            let reader = io::BufReader::with_capacity(chunk, &input[..]);
+            let compressed = read_chunked(LzmaEncoderReader::new(reader).unwrap(), chunk).unwrap();
+
+            let mut output = Vec::new();
+            crate::lzma_decompress(&mut compressed.as_slice(), &mut output).unwrap();
+            assert_eq!(&output[..], &input[..]);
+        }
+    }
+
+    #[test]
+    fn test_xz_encoder_reader() {
+        let input = include_bytes!("../../tests/files/foo.txt");
+        for &chunk in &[1, 7, 4096] {
+            let reader = io::BufReader::with_capacity(chunk, &input[..]);
+            let compressed = read_chunked(XzEncoderReader::new(reader).unwrap(), chunk).unwrap();
+
+            let mut output = Vec::new();
+            crate::xz_decompress(&mut compressed.as_slice(), &mut output).unwrap();
+            assert_eq!(&output[..], &input[..]);
+        }
+    }
+
+    /// Encoder failing once it has been given `limit` bytes.
+    struct FailingEncoder {
+        output: Vec<u8>,
+        limit: usize,
+    }
+
+    impl StreamEncoder for FailingEncoder {
+        fn append(&mut self, data: &[u8]) -> io::Result<usize> {
+            if data.len() > self.limit {
+                return Err(io::Error::new(io::ErrorKind::Other, "too much data"));
+            }
+            self.limit -= data.len();
+            self.output.extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn output_mut(&mut self) -> &mut Vec<u8> {
+            &mut self.output
+        }
+
+        fn finish(self) -> io::Result<Vec<u8>> {
+            Err(io::Error::new(io::ErrorKind::Other, "can't finish"))
+        }
+    }
+
+    #[test]
+    fn test_encoder_reader_failed() {
+        // Failing to append.
+        let encoder = FailingEncoder {
+            output: Vec::new(),
+            limit: 5,
+        };
+        let mut reader =
+            EncoderReader::new(io::BufReader::with_capacity(4, &b"abcdefgh"[..]), encoder);
+        let mut buf = [0; 16];
+        assert_eq!(reader.read(&mut buf).unwrap(), 4);
+        reader.read(&mut buf).unwrap_err();
+        let err = reader.read(&mut buf).unwrap_err();
+        assert!(
+            err.to_string().contains("previous encoding error"),
+            "{}",
+            err
+        );
+
+        // Failing to finish.
+        let encoder = FailingEncoder {
+            output: Vec::new(),
+            limit: 5,
+        };
+        let mut reader = EncoderReader::new(&b"abc"[..], encoder);
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap_err();
+        reader.read_to_end(&mut output).unwrap_err();
+        assert_eq!(output, b"abc");
+    }
+
+    #[test]
+    fn test_xz_encoder_reader_empty() {
+        let mut compressed = Vec::new();
+        XzEncoderReader::new(&b""[..])
+            .unwrap()
+            .read_to_end(&mut compressed)
+            .unwrap();
+
+        let mut expected = Vec::new();
+        crate::xz_compress(&mut &b""[..], &mut expected).unwrap();
+        assert_eq!(compressed, expected);
+    }
+}
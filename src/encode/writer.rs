@@ -0,0 +1,339 @@
+use crate::encode::options::{Options, XzOptions};
+use crate::encode::{lzma, xz};
+use std::fmt::Debug;
+use std::io::{self, Write};
+
+/// LZMA compressor implementing [`io::Write`], compressing the data as it is
+/// written.
+///
+/// The header is written when the encoder is created, and the end of the
+/// compressed data when calling [`Self::finish()`].
+pub struct LzmaEncoder<W>
+where
+    W: Write,
+{
+    encoder: lzma::Encoder<W>,
+    /// Whether writing failed, leaving incomplete compressed data.
+    failed: bool,
+}
+
+impl<W> LzmaEncoder<W>
+where
+    W: Write,
+{
+    /// Initialize the encoder with default [`Options`]. This will consume
+    /// the `output` which is the sink implementing [`io::Write`] that will
+    /// receive compressed bytes.
+    pub fn new(output: W) -> io::Result<Self> {
+        Self::new_with_options(&Options::default(), output)
+    }
+
+    /// Initialize the encoder with the given `options`. This will consume
+    /// the `output` which is the sink implementing [`io::Write`] that will
+    /// receive compressed bytes.
+    pub fn new_with_options(options: &Options, output: W) -> io::Result<Self> {
+        Ok(Self {
+            encoder: lzma::Encoder::from_stream(output, options)?,
+            failed: false,
+        })
+    }
+
+    /// Get a reference to the output sink.
+    pub fn get_ref(&self) -> &W {
+        self.encoder.get_ref()
+    }
+
+    /// Get a mutable reference to the output sink. Writing to it corrupts the
+    /// compressed data.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.encoder.get_mut()
+    }
+
+    /// Compress the remaining data, write the end of the compressed data and
+    /// return the output sink.
+    pub fn finish(self) -> io::Result<W> {
+        check_failed(self.failed, "finish")?;
+        let mut output = self.encoder.finish()?;
+        output.flush()?;
+        Ok(output)
+    }
+}
+
+impl<W> Debug for LzmaEncoder<W>
+where
+    W: Write + Debug,
+{
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("LzmaEncoder")
+            .field("output", self.get_ref())
+            .field("failed", &self.failed)
+            .finish()
+    }
+}
+
+impl<W> Write for LzmaEncoder<W>
+where
+    W: Write,
+{
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        check_failed(self.failed, "write to")?;
+        let result = self.encoder.append(data);
+        self.failed = result.is_err();
+        result
+    }
+
+    /// Flushes the output sink. The data buffered by the encoder isn't
+    /// flushed, as that would end the compressed data. Instead, call
+    /// [`Self::finish()`].
+    fn flush(&mut self) -> io::Result<()> {
+        self.get_mut().flush()
+    }
+}
+
+/// XZ compressor implementing [`io::Write`], compressing the data as it is
+/// written.
+///
+/// The Stream Header is written when the encoder is created, and the end of
+/// the stream when calling [`Self::finish()`].
+pub struct XzEncoder<W>
+where
+    W: Write,
+{
+    encoder: xz::Encoder<W>,
+    /// Whether writing failed, leaving incomplete compressed data.
+    failed: bool,
+}
+
+impl<W> XzEncoder<W>
+where
+    W: Write,
+{
+    /// Initialize the encoder with default [`XzOptions`]. This will consume
+    /// the `output` which is the sink implementing [`io::Write`] that will
+    /// receive compressed bytes.
+    pub fn new(output: W) -> io::Result<Self> {
+        Self::new_with_options(&XzOptions::default(), output)
+    }
+
+    /// Initialize the encoder with the given `options`. This will consume
+    /// the `output` which is the sink implementing [`io::Write`] that will
+    /// receive compressed bytes.
+    pub fn new_with_options(options: &XzOptions, output: W) -> io::Result<Self> {
+        Ok(Self {
+            encoder: xz::Encoder::new(output, options)?,
+            failed: false,
+        })
+    }
+
+    /// Get a reference to the output sink.
+    pub fn get_ref(&self) -> &W {
+        self.encoder.get_ref()
+    }
+
+    /// Get a mutable reference to the output sink. Writing to it corrupts the
+    /// compressed data.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.encoder.get_mut()
+    }
+
+    /// Compress the remaining data, write the end of the stream and return
+    /// the output sink.
+    pub fn finish(self) -> io::Result<W> {
+        check_failed(self.failed, "finish")?;
+        let mut output = self.encoder.finish()?;
+        output.flush()?;
+        Ok(output)
+    }
+}
+
+impl<W> Debug for XzEncoder<W>
+where
+    W: Write + Debug,
+{
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("XzEncoder")
+            .field("output", self.get_ref())
+            .field("failed", &self.failed)
+            .finish()
+    }
+}
+
+impl<W> Write for XzEncoder<W>
+where
+    W: Write,
+{
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        check_failed(self.failed, "write to")?;
+        let result = self.encoder.append(data);
+        self.failed = result.is_err();
+        result
+    }
+
+    /// Flushes the output sink. The data buffered by the encoder isn't
+    /// flushed, as that would end the block. Instead, call
+    /// [`Self::finish()`].
+    fn flush(&mut self) -> io::Result<()> {
+        self.get_mut().flush()
+    }
+}
+
+/// Fail if a previous write failed, as the encoder can't go on after it.
+fn check_failed(failed: bool, action: &str) -> io::Result<()> {
+    if failed {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("can't {} stream because of previous write error", action),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lzma_encoder() {
+        let input = include_bytes!("../../tests/files/foo.txt");
+
+        for &chunk in &[1, 7, 4096, input.len()] {
+            let mut encoder = LzmaEncoder::new(Vec::new()).unwrap();
+            for data in input.chunks(chunk) {
+                encoder.write_all(data).unwrap();
+            }
+            let compressed = encoder.finish().unwrap();
+
+            let mut output = Vec::new();
+            crate::lzma_decompress(&mut compressed.as_slice(), &mut output).unwrap();
+            assert_eq!(&output[..], &input[..]);
+        }
+    }
+
+    #[test]
+    fn test_xz_encoder() {
+        let input = include_bytes!("../../tests/files/foo.txt");
+        for &chunk in &[1, 7, 4096, input.len()] {
+            let mut encoder = XzEncoder::new(Vec::new()).unwrap();
+            for data in input.chunks(chunk) {
+                encoder.write_all(data).unwrap();
+            }
+            let compressed = encoder.finish().unwrap();
+
+            let mut output = Vec::new();
+            crate::xz_decompress(&mut compressed.as_slice(), &mut output).unwrap();
+            assert_eq!(&output[..], &input[..]);
+        }
+    }
+
+    #[test]
+    fn test_xz_encoder_filters() {
+        use crate::encode::options::{BcjOptions, Filter};
+
+        let input = include_bytes!("../../tests/files/bcj.bin");
+        let options = XzOptions {
+            filters: vec![Filter::X86(BcjOptions::default())],
+            ..Default::default()
+        };
+        for &chunk in &[1, 7, 4096, input.len()] {
+            let mut encoder = XzEncoder::new_with_options(&options, Vec::new()).unwrap();
+            for data in input.chunks(chunk) {
+                encoder.write_all(data).unwrap();
+            }
+            let compressed = encoder.finish().unwrap();
+
+            let mut output = Vec::new();
+            crate::xz_decompress(&mut compressed.as_slice(), &mut output).unwrap();
+            assert_eq!(&output[..], &input[..]);
+        }
+    }
+
+    /// Output failing once `limit` bytes are written to it.
+    #[derive(Debug)]
+    struct FailingWrite {
+        limit: usize,
+    }
+
+    impl Write for FailingWrite {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf.len() > self.limit {
+                return Err(io::Error::new(io::ErrorKind::Other, "output full"));
+            }
+            self.limit -= buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_failed_write() {
+        let input = include_bytes!("../../tests/files/foo.txt");
+
+        let mut encoder = LzmaEncoder::new(FailingWrite { limit: 100 }).unwrap();
+        encoder.write_all(input).unwrap_err();
+        let err = encoder.write(b"abc").unwrap_err();
+        assert!(err.to_string().contains("previous write error"), "{}", err);
+        encoder.get_mut().limit = usize::MAX;
+        encoder.finish().unwrap_err();
+
+        let options = XzOptions {
+            block_size: Some(1000),
+            ..Default::default()
+        };
+        let mut encoder =
+            XzEncoder::new_with_options(&options, FailingWrite { limit: 100 }).unwrap();
+        encoder.write_all(input).unwrap_err();
+        let err = encoder.write(b"abc").unwrap_err();
+        assert!(err.to_string().contains("previous write error"), "{}", err);
+        encoder.get_mut().limit = usize::MAX;
+        encoder.finish().unwrap_err();
+    }
+
+    #[test]
+    fn test_xz_encoder_last_block() {
+        // More than an LZMA2 chunk, in a single block extending to the end of
+        // the data.
+        let input = include_bytes!("../../tests/files/foo.txt").repeat(16);
+        let options = XzOptions {
+            block_list: vec![0],
+            ..Default::default()
+        };
+        let mut encoder = XzEncoder::new_with_options(&options, Vec::new()).unwrap();
+        let header_len = encoder.get_ref().len();
+        encoder.write_all(&input).unwrap();
+        // The block is written as it is compressed, not buffered until
+        // finished.
+        assert!(encoder.get_ref().len() > header_len);
+        let compressed = encoder.finish().unwrap();
+
+        let mut output = Vec::new();
+        crate::xz_decompress(&mut compressed.as_slice(), &mut output).unwrap();
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn test_xz_encoder_empty() {
+        let encoder = XzEncoder::new(Vec::new()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut expected = Vec::new();
+        crate::xz_compress(&mut &b""[..], &mut expected).unwrap();
+        assert_eq!(compressed, expected);
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let options = XzOptions {
+            lzma2: Options {
+                lc: 4,
+                lp: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let err = XzEncoder::new_with_options(&options, Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -1,31 +1,442 @@
-use crate::decode;
+use crate::decode::xz::Record;
+use crate::encode::options::XzOptions;
 use crate::encode::{lzma2, util};
 use crate::xz::crc::CRC32;
-use crate::xz::{footer, header, CheckMethod, StreamFlags};
+use crate::xz::filter::FilterChain;
+use crate::xz::pool::Pool;
+use crate::xz::{footer, header, Check, StreamFlags};
 use byteorder::{LittleEndian, WriteBytesExt};
 use std::io;
 use std::io::Write;
 
-pub fn encode_stream<R, W>(input: &mut R, output: &mut W) -> io::Result<()>
+pub fn encode_stream<R, W>(input: &mut R, output: &mut W, options: &XzOptions) -> io::Result<()>
 where
     R: io::BufRead,
     W: io::Write,
 {
-    let stream_flags = StreamFlags {
-        check_method: CheckMethod::None,
-    };
+    let mut encoder = Encoder::new(output, options)?;
+    loop {
+        let data = input.fill_buf()?;
+        if data.is_empty() {
+            break;
+        }
+        let n = encoder.append(data)?;
+        input.consume(n);
+    }
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Maximum number of bytes run through the filters at each append, to bound
+/// the memory they use.
+const FILTER_CHUNK_SIZE: usize = 1 << 16;
+
+/// Encoder of the Compressed Data of a block, with its filter chain and its
+/// Block Check.
+pub(crate) struct BlockEncoder {
+    /// Filters applied before LZMA2.
+    filters: FilterChain,
+    /// Filtered bytes, reused across appends.
+    filtered: Vec<u8>,
+    lzma2: lzma2::Encoder,
+    /// Block Check of the data appended so far.
+    check: Check,
+    /// Number of bytes appended so far.
+    unpacked_size: u64,
+    /// Number of compressed bytes written so far.
+    packed_size: u64,
+}
+
+impl BlockEncoder {
+    /// Create an encoder with validated `options`.
+    pub(crate) fn new(options: &XzOptions) -> io::Result<Self> {
+        Ok(Self {
+            filters: FilterChain::new(options.filters.iter().map(|f| f.converter(true)).collect()),
+            filtered: Vec::new(),
+            lzma2: lzma2::Encoder::new(&options.lzma2)?,
+            check: Check::new(options.check),
+            unpacked_size: 0,
+            packed_size: 0,
+        })
+    }
+
+    pub(crate) fn unpacked_size(&self) -> u64 {
+        self.unpacked_size
+    }
+
+    /// Encode a prefix of the data. Returns the number of bytes consumed.
+    pub(crate) fn append<W>(&mut self, data: &[u8], output: &mut W) -> io::Result<usize>
+    where
+        W: io::Write,
+    {
+        let mut count_output = util::CountWrite::new(output);
+        let n = if self.filters.is_empty() {
+            self.lzma2.append(data, &mut count_output)?
+        } else {
+            let n = std::cmp::min(data.len(), FILTER_CHUNK_SIZE);
+            self.filtered.clear();
+            self.filters.process(&data[..n], &mut self.filtered);
+            append_all(&mut self.lzma2, &self.filtered, &mut count_output)?;
+            n
+        };
+        self.packed_size += count_output.count() as u64;
+        self.check.update(&data[..n]);
+        self.unpacked_size += n as u64;
+        Ok(n)
+    }
+
+    /// Write the end of the Compressed Data, and return the sizes of the
+    /// block and its Block Check.
+    pub(crate) fn finish<W>(mut self, output: &mut W) -> io::Result<FinishedBlock>
+    where
+        W: io::Write,
+    {
+        let mut count_output = util::CountWrite::new(output);
+        self.filtered.clear();
+        self.filters.finish(&mut self.filtered);
+        append_all(&mut self.lzma2, &self.filtered, &mut count_output)?;
+        self.lzma2.finish(&mut count_output)?;
+        self.packed_size += count_output.count() as u64;
+        Ok(FinishedBlock {
+            packed_size: self.packed_size,
+            unpacked_size: self.unpacked_size,
+            check: self.check.finalize(),
+        })
+    }
+}
+
+/// Block whose Compressed Data is complete.
+pub(crate) struct FinishedBlock {
+    pub(crate) packed_size: u64,
+    pub(crate) unpacked_size: u64,
+    pub(crate) check: Vec<u8>,
+}
+
+/// Uncompressed sizes of the successive blocks, following
+/// [`XzOptions::block_size`] and [`XzOptions::block_list`].
+pub(crate) struct BlockSizes {
+    block_size: Option<u64>,
+    block_list: Vec<u64>,
+    /// Index of the next entry of the list.
+    index: usize,
+    /// Size left in the current entry of the list, split by `block_size`.
+    entry_remaining: u64,
+}
+
+impl BlockSizes {
+    pub(crate) fn new(options: &XzOptions) -> Self {
+        Self {
+            block_size: options.block_size,
+            block_list: options.block_list.clone(),
+            index: 0,
+            entry_remaining: 0,
+        }
+    }
+
+    /// Size of the next block, or [`u64::MAX`] if it extends to the end of
+    /// the data.
+    pub(crate) fn next_size(&mut self) -> u64 {
+        if self.entry_remaining == 0 {
+            // The last entry repeats, and an entry of 0 covers the rest.
+            let entry = match self.block_list.get(self.index).or(self.block_list.last()) {
+                Some(&0) | None => u64::MAX,
+                Some(&entry) => entry,
+            };
+            self.index += 1;
+            self.entry_remaining = entry;
+        }
+        let size = std::cmp::min(self.entry_remaining, self.block_size.unwrap_or(u64::MAX));
+        if self.entry_remaining != u64::MAX {
+            self.entry_remaining -= size;
+        }
+        size
+    }
+}
 
-    // Header
-    write_header(output, stream_flags)?;
+/// Block encoded on its own, with its Compressed Data.
+pub(crate) struct EncodedBlock {
+    block: FinishedBlock,
+    packed: Vec<u8>,
+}
 
-    // Block
-    let (unpadded_size, unpacked_size) = write_block(input, output)?;
+/// Encode all of `data` as a block with validated `options`.
+pub(crate) fn encode_block(options: &XzOptions, mut data: &[u8]) -> io::Result<EncodedBlock> {
+    let mut encoder = BlockEncoder::new(options)?;
+    let mut packed = Vec::new();
+    while !data.is_empty() {
+        let n = encoder.append(data, &mut packed)?;
+        data = &data[n..];
+    }
+    let block = encoder.finish(&mut packed)?;
+    Ok(EncodedBlock { block, packed })
+}
 
-    // Index
-    let index_size = write_index(output, unpadded_size, unpacked_size)?;
+/// Block being encoded by an [`Encoder`].
+enum CurrentBlock {
+    /// Block extending to the end of the data, whose header is written
+    /// without sizes, encoded to the output as the data is appended.
+    Streaming(Box<BlockEncoder>),
+    /// Block of a bounded size encoded into a buffer as the data is
+    /// appended, to write its sizes in its header once complete.
+    Encoding(Box<BlockEncoder>),
+    /// Data of a block to encode on a thread of the pool once complete.
+    Gathering(Vec<u8>),
+}
 
-    // Footer
-    write_footer(output, stream_flags, index_size)
+impl CurrentBlock {
+    fn unpacked_size(&self) -> u64 {
+        match self {
+            CurrentBlock::Streaming(encoder) | CurrentBlock::Encoding(encoder) => {
+                encoder.unpacked_size()
+            }
+            CurrentBlock::Gathering(data) => data.len() as u64,
+        }
+    }
+}
+
+/// XZ encoder writing a stream to an output sink, as the input is appended.
+///
+/// A block extending to the end of the data, like the single block written
+/// without block sizes in the options, is streamed with a header without
+/// sizes. Blocks of a bounded size are buffered until complete, to record
+/// their sizes in their headers, and are encoded on a pool of threads if the
+/// options ask for several.
+pub(crate) struct Encoder<W>
+where
+    W: io::Write,
+{
+    output: W,
+    options: XzOptions,
+    block_sizes: BlockSizes,
+    /// Block being encoded, with the uncompressed size it ends at.
+    block: Option<(CurrentBlock, u64)>,
+    /// Compressed Data of the current block, if it is buffered.
+    packed: Vec<u8>,
+    /// Size of the header of the current block, if already written.
+    header_size: u64,
+    records: Vec<Record>,
+    /// Threads encoding the blocks, whose output follows the records.
+    pool: Option<Pool<Vec<u8>, io::Result<EncodedBlock>>>,
+}
+
+impl<W> Encoder<W>
+where
+    W: io::Write,
+{
+    /// Write the Stream Header, and the Block Header if the data is streamed
+    /// as a single block.
+    pub(crate) fn new(mut output: W, options: &XzOptions) -> io::Result<Self> {
+        options.validate()?;
+        let stream_flags = StreamFlags {
+            check_method: options.check,
+        };
+
+        // Header
+        write_header(&mut output, stream_flags)?;
+
+        let threads = options.thread_count();
+        let pool = if options.has_blocks() && threads > 1 {
+            let options = options.clone();
+            Some(Pool::new(threads, move |data: Vec<u8>| {
+                encode_block(&options, &data)
+            }))
+        } else {
+            None
+        };
+        let mut encoder = Self {
+            output,
+            options: options.clone(),
+            block_sizes: BlockSizes::new(options),
+            block: None,
+            packed: Vec::new(),
+            header_size: 0,
+            records: Vec::new(),
+            pool,
+        };
+        if !options.has_blocks() {
+            encoder.start_block()?;
+        }
+        Ok(encoder)
+    }
+
+    pub(crate) fn get_ref(&self) -> &W {
+        &self.output
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut W {
+        &mut self.output
+    }
+
+    /// Encode a prefix of the data. Returns the number of bytes consumed.
+    pub(crate) fn append(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        if self.block.is_none() {
+            self.start_block()?;
+        }
+        let (block, end) = self.block.as_mut().unwrap();
+        let remaining = *end - block.unpacked_size();
+        let data = &data[..std::cmp::min(data.len() as u64, remaining) as usize];
+        let n = match block {
+            CurrentBlock::Streaming(encoder) => encoder.append(data, &mut self.output)?,
+            CurrentBlock::Encoding(encoder) => encoder.append(data, &mut self.packed)?,
+            CurrentBlock::Gathering(buf) => {
+                buf.extend_from_slice(data);
+                data.len()
+            }
+        };
+        if block.unpacked_size() == *end {
+            self.finish_block()?;
+        }
+        Ok(n)
+    }
+
+    /// Write the end of the last block, the Index and the Stream Footer, and
+    /// return the output sink.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        if self.block.is_some() {
+            self.finish_block()?;
+        }
+        self.write_pool_blocks(false)?;
+
+        // Index
+        let index_size = write_index(&mut self.output, &self.records)?;
+
+        // Footer
+        let stream_flags = StreamFlags {
+            check_method: self.options.check,
+        };
+        write_footer(&mut self.output, stream_flags, index_size)?;
+        Ok(self.output)
+    }
+
+    /// Start a block, writing its header unless the block is buffered.
+    fn start_block(&mut self) -> io::Result<()> {
+        let end = if self.options.has_blocks() {
+            self.block_sizes.next_size()
+        } else {
+            u64::MAX
+        };
+        // A block extending to the end of the data is streamed, as its size
+        // isn't bounded.
+        let block = if end == u64::MAX {
+            let encoder = BlockEncoder::new(&self.options)?;
+            // The blocks in the pool come first.
+            self.write_pool_blocks(false)?;
+            self.header_size = write_block_header(&mut self.output, &self.options, None)? as u64;
+            CurrentBlock::Streaming(Box::new(encoder))
+        } else if self.pool.is_some() {
+            CurrentBlock::Gathering(Vec::new())
+        } else {
+            CurrentBlock::Encoding(Box::new(BlockEncoder::new(&self.options)?))
+        };
+        self.block = Some((block, end));
+        Ok(())
+    }
+
+    /// Write the end of the current block, preceded by its header and its
+    /// Compressed Data if it is buffered, or submit it to the pool.
+    fn finish_block(&mut self) -> io::Result<()> {
+        let (block, _) = self.block.take().unwrap();
+        match block {
+            CurrentBlock::Streaming(encoder) => {
+                let block = encoder.finish(&mut self.output)?;
+                let record = write_block_end(&mut self.output, self.header_size, &block)?;
+                self.records.push(record);
+            }
+            CurrentBlock::Encoding(encoder) => {
+                let block = encoder.finish(&mut self.packed)?;
+                // The blocks in the pool come first.
+                self.write_pool_blocks(false)?;
+                let record =
+                    write_buffered_block(&mut self.output, &self.options, &block, &self.packed)?;
+                self.packed.clear();
+                self.records.push(record);
+            }
+            CurrentBlock::Gathering(data) => {
+                self.write_pool_blocks(true)?;
+                self.pool.as_mut().unwrap().submit(data);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the blocks encoded by the pool, in order, waiting for them. If
+    /// `while_full` is set, stop once another block can be submitted.
+    fn write_pool_blocks(&mut self, while_full: bool) -> io::Result<()> {
+        if let Some(pool) = self.pool.as_mut() {
+            while !while_full || pool.is_full() {
+                let encoded = match pool.next() {
+                    Some(encoded) => encoded?,
+                    None => break,
+                };
+                let record = write_buffered_block(
+                    &mut self.output,
+                    &self.options,
+                    &encoded.block,
+                    &encoded.packed,
+                )?;
+                self.records.push(record);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write a whole block, with its sizes in its header, and return its Index
+/// Record.
+fn write_buffered_block<W>(
+    output: &mut W,
+    options: &XzOptions,
+    block: &FinishedBlock,
+    packed: &[u8],
+) -> io::Result<Record>
+where
+    W: io::Write,
+{
+    let sizes = (block.packed_size, block.unpacked_size);
+    let header_size = write_block_header(output, options, Some(sizes))? as u64;
+    output.write_all(packed)?;
+    write_block_end(output, header_size, block)
+}
+
+/// Write the Block Padding and the Block Check after the Compressed Data, and
+/// return the Index Record of the block.
+fn write_block_end<W>(output: &mut W, header_size: u64, block: &FinishedBlock) -> io::Result<Record>
+where
+    W: io::Write,
+{
+    // The unpadded size includes the Block Check, but not the padding
+    // before it.
+    let unpadded_size = header_size + block.packed_size + block.check.len() as u64;
+    let unpacked_size = block.unpacked_size;
+    lzma_info!(
+        "Unpadded size = {}, unpacked_size = {}",
+        unpadded_size,
+        unpacked_size
+    );
+
+    let padding_size = ((block.packed_size ^ 0x03) + 1) & 0x03;
+    let padding = vec![0; padding_size as usize];
+    output.write_all(padding.as_slice())?;
+    output.write_all(&block.check)?;
+    Ok(Record {
+        unpadded_size,
+        unpacked_size,
+    })
+}
+
+/// Encode all the data with LZMA2.
+fn append_all<W>(lzma2: &mut lzma2::Encoder, mut data: &[u8], output: &mut W) -> io::Result<()>
+where
+    W: io::Write,
+{
+    while !data.is_empty() {
+        let n = lzma2.append(data, output)?;
+        data = &data[n..];
+    }
+    Ok(())
 }
 
 fn write_header<W>(output: &mut W, stream_flags: StreamFlags) -> io::Result<()>
@@ -64,54 +475,63 @@ where
     Ok(())
 }
 
-fn write_block<R, W>(input: &mut R, output: &mut W) -> io::Result<(usize, usize)>
+/// Write the Block Header, with the compressed and uncompressed sizes of the
+/// block if given, and return its size.
+fn write_block_header<W>(
+    output: &mut W,
+    options: &XzOptions,
+    sizes: Option<(u64, u64)>,
+) -> io::Result<usize>
 where
-    R: io::BufRead,
     W: io::Write,
 {
-    let (unpadded_size, unpacked_size) = {
-        let mut count_output = util::CountWrite::new(output);
-
-        // Block header
-        let mut digest = CRC32.digest();
-        {
-            let mut digested = util::CrcDigestWrite::new(&mut count_output, &mut digest);
-            let header_size = 8;
-            digested.write_u8((header_size >> 2) as u8)?;
-            let flags = 0x00; // 1 filter, no (un)packed size provided
-            digested.write_u8(flags)?;
-            let filter_id = 0x21; // LZMA2
-            digested.write_u8(filter_id)?;
-            let size_of_properties = 1;
-            digested.write_u8(size_of_properties)?;
-            let properties = 22; // TODO
-            digested.write_u8(properties)?;
-            let padding = [0, 0, 0];
-            digested.write_all(&padding)?;
-        }
-        let crc32 = digest.finalize();
-        count_output.write_u32::<LittleEndian>(crc32)?;
-
-        // Block
-        let mut count_input = decode::util::CountBufRead::new(input);
-        lzma2::encode_stream(&mut count_input, &mut count_output)?;
-        (count_output.count(), count_input.count())
-    };
-    lzma_info!(
-        "Unpadded size = {}, unpacked_size = {}",
-        unpadded_size,
-        unpacked_size
-    );
+    // Block Flags, optional sizes and List of Filter Flags, see sect. 3.1.
+    let mut fields: Vec<u8> = Vec::new();
+    let mut flags = options.filters.len() as u8;
+    if sizes.is_some() {
+        flags |= 0xC0;
+    }
+    fields.write_u8(flags)?;
+    if let Some((packed_size, unpacked_size)) = sizes {
+        write_multibyte(&mut fields, packed_size)?;
+        write_multibyte(&mut fields, unpacked_size)?;
+    }
+    for filter in &options.filters {
+        let props = filter.props();
+        write_multibyte(&mut fields, filter.id())?;
+        write_multibyte(&mut fields, props.len() as u64)?;
+        fields.write_all(&props)?;
+    }
+    let filter_id = 0x21; // LZMA2
+    fields.write_u8(filter_id)?;
+    let size_of_properties = 1;
+    fields.write_u8(size_of_properties)?;
+    let properties = lzma2::dict_size_property(options.lzma2.dict_size);
+    fields.write_u8(properties)?;
 
+    // The header size byte and the fields, padded to a multiple of 4 bytes,
+    // followed by the CRC32.
+    let unpadded_size = 1 + fields.len();
     let padding_size = ((unpadded_size ^ 0x03) + 1) & 0x03;
-    let padding = vec![0; padding_size];
-    output.write_all(padding.as_slice())?;
-    // Checksum = None (cf. above)
+    let header_size = unpadded_size + padding_size + 4;
+
+    let mut count_output = util::CountWrite::new(output);
+
+    let mut digest = CRC32.digest();
+    {
+        let mut digested = util::CrcDigestWrite::new(&mut count_output, &mut digest);
+        digested.write_u8(((header_size >> 2) - 1) as u8)?;
+        digested.write_all(&fields)?;
+        let padding = vec![0; padding_size];
+        digested.write_all(&padding)?;
+    }
+    let crc32 = digest.finalize();
+    count_output.write_u32::<LittleEndian>(crc32)?;
 
-    Ok((unpadded_size, unpacked_size))
+    Ok(count_output.count())
 }
 
-fn write_index<W>(output: &mut W, unpadded_size: usize, unpacked_size: usize) -> io::Result<usize>
+fn write_index<W>(output: &mut W, records: &[Record]) -> io::Result<usize>
 where
     W: io::Write,
 {
@@ -121,11 +541,11 @@ where
     {
         let mut digested = util::CrcDigestWrite::new(&mut count_output, &mut digest);
         digested.write_u8(0)?; // No more block
-        let num_records = 1;
-        write_multibyte(&mut digested, num_records)?;
-
-        write_multibyte(&mut digested, unpadded_size as u64)?;
-        write_multibyte(&mut digested, unpacked_size as u64)?;
+        write_multibyte(&mut digested, records.len() as u64)?;
+        for record in records {
+            write_multibyte(&mut digested, record.unpadded_size)?;
+            write_multibyte(&mut digested, record.unpacked_size)?;
+        }
     }
 
     // Padding
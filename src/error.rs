@@ -14,6 +14,8 @@ pub enum Error {
     LzmaError(String),
     /// XZ error.
     XzError(String),
+    /// Decoding would need more memory than the configured limit, in bytes.
+    MemLimitExceeded(usize),
 }
 
 /// Library result alias.
@@ -25,6 +27,12 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<Error> for io::Error {
+    fn from(error: Error) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, error)
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -32,6 +40,9 @@ impl Display for Error {
             Error::HeaderTooShort(e) => write!(fmt, "header too short: {}", e),
             Error::LzmaError(e) => write!(fmt, "lzma error: {}", e),
             Error::XzError(e) => write!(fmt, "xz error: {}", e),
+            Error::MemLimitExceeded(memlimit) => {
+                write!(fmt, "exceeded memory limit of {}", memlimit)
+            }
         }
     }
 }
@@ -40,7 +51,7 @@ impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             Error::IoError(e) | Error::HeaderTooShort(e) => Some(e),
-            Error::LzmaError(_) | Error::XzError(_) => None,
+            Error::LzmaError(_) | Error::XzError(_) | Error::MemLimitExceeded(_) => None,
         }
     }
 }
@@ -67,5 +78,18 @@ mod test {
             Error::XzError("this is an error".to_string()).to_string(),
             "xz error: this is an error"
         );
+        assert_eq!(
+            Error::MemLimitExceeded(1024).to_string(),
+            "exceeded memory limit of 1024"
+        );
+    }
+
+    #[test]
+    fn test_into_io_error() {
+        let error: std::io::Error = Error::XzError("this is an error".to_string()).into();
+        assert_eq!(error.kind(), std::io::ErrorKind::Other);
+        assert_eq!(error.to_string(), "xz error: this is an error");
+        let error: std::io::Error = Error::MemLimitExceeded(1024).into();
+        assert_eq!(error.to_string(), "exceeded memory limit of 1024");
     }
 }
@@ -7,24 +7,38 @@
 #[macro_use]
 mod macros;
 
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+mod async_io;
 mod decode;
 mod encode;
 
 pub mod error;
 
 mod util;
-mod xz;
+pub mod xz;
 
 use std::io;
 
+#[cfg(feature = "futures-io")]
+#[cfg_attr(docsrs, doc(cfg(feature = "futures-io")))]
+pub use crate::async_io::futures;
+#[cfg(feature = "tokio")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tokio")))]
+pub use crate::async_io::tokio;
+
 /// Compression helpers.
 pub mod compress {
     pub use crate::encode::options::*;
+    pub use crate::encode::reader::{LzmaEncoderReader, XzEncoderReader};
+    pub use crate::encode::writer::{LzmaEncoder, XzEncoder};
+    pub use crate::xz::delta::DeltaEncoder;
 }
 
 /// Decompression helpers.
 pub mod decompress {
     pub use crate::decode::options::*;
+    pub use crate::encode::options::DeltaOptions;
+    pub use crate::xz::delta::DeltaDecoder;
 
     #[cfg(feature = "raw_decoder")]
     #[cfg_attr(docsrs, doc(cfg(raw_decoder)))]
@@ -34,9 +48,21 @@ pub mod decompress {
         pub use crate::decode::lzma2::Lzma2Decoder;
     }
 
+    #[cfg(feature = "stream")]
+    #[cfg_attr(docsrs, doc(cfg(stream)))]
+    pub use crate::decode::lzma2_stream::Lzma2Stream;
+    #[cfg(feature = "stream")]
+    #[cfg_attr(docsrs, doc(cfg(stream)))]
+    pub use crate::decode::reader::{Lzma2Reader, LzmaReader, XzReader};
     #[cfg(feature = "stream")]
     #[cfg_attr(docsrs, doc(cfg(stream)))]
     pub use crate::decode::stream::Stream;
+    #[cfg(feature = "stream")]
+    #[cfg_attr(docsrs, doc(cfg(stream)))]
+    pub use crate::decode::xz_seekable::XzSeekableReader;
+    #[cfg(feature = "stream")]
+    #[cfg_attr(docsrs, doc(cfg(stream)))]
+    pub use crate::decode::xz_stream::XzStream;
 }
 
 /// Decompress LZMA data with default
@@ -74,7 +100,7 @@ pub fn lzma_compress_with_options<R: io::BufRead, W: io::Write>(
     output: &mut W,
     options: &compress::Options,
 ) -> io::Result<()> {
-    let encoder = encode::dumbencoder::Encoder::from_stream(output, options)?;
+    let encoder = encode::lzma::Encoder::from_stream(output, options)?;
     encoder.process(input)
 }
 
@@ -84,7 +110,19 @@ pub fn lzma2_decompress<R: io::BufRead, W: io::Write>(
     input: &mut R,
     output: &mut W,
 ) -> error::Result<()> {
-    decode::lzma2::Lzma2Decoder::new().decompress(input, output)
+    lzma2_decompress_with_options(input, output, &decompress::Options::default())
+}
+
+/// Decompress LZMA2 data with the provided options.
+pub fn lzma2_decompress_with_options<R: io::BufRead, W: io::Write>(
+    input: &mut R,
+    output: &mut W,
+    options: &decompress::Options,
+) -> error::Result<()> {
+    // Raw LZMA2 data doesn't record its dictionary size.
+    let mut decoder =
+        decode::lzma2::Lzma2Decoder::with_dict_size(decode::lzma2::DICT_SIZE_MAX, options.memlimit);
+    decoder.decompress(input, output)
 }
 
 /// Compress data with LZMA2 and default
@@ -93,7 +131,16 @@ pub fn lzma2_compress<R: io::BufRead, W: io::Write>(
     input: &mut R,
     output: &mut W,
 ) -> io::Result<()> {
-    encode::lzma2::encode_stream(input, output)
+    lzma2_compress_with_options(input, output, &compress::Options::default())
+}
+
+/// Compress data with LZMA2 and the provided options.
+pub fn lzma2_compress_with_options<R: io::BufRead, W: io::Write>(
+    input: &mut R,
+    output: &mut W,
+    options: &compress::Options,
+) -> io::Result<()> {
+    encode::lzma2::encode_stream(input, output, options)
 }
 
 /// Decompress XZ data with default [`Options`](decompress/struct.Options.html).
@@ -101,10 +148,67 @@ pub fn xz_decompress<R: io::BufRead, W: io::Write>(
     input: &mut R,
     output: &mut W,
 ) -> error::Result<()> {
-    decode::xz::decode_stream(input, output)
+    xz_decompress_with_options(input, output, &decompress::Options::default())
 }
 
-/// Compress data with XZ and default [`Options`](compress/struct.Options.html).
+/// Decompress XZ data with the provided options.
+///
+/// The [`memlimit`](decompress::Options::memlimit) and
+/// [`single_stream`](decompress::Options::single_stream) options apply to the
+/// XZ format, and the other ones are ignored.
+pub fn xz_decompress_with_options<R: io::BufRead, W: io::Write>(
+    input: &mut R,
+    output: &mut W,
+    options: &decompress::Options,
+) -> error::Result<()> {
+    decode::xz::decode_stream(input, output, options)
+}
+
+/// Decompress XZ data from a seekable input, decoding its blocks in parallel
+/// on [`threads`](decompress::Options::threads) threads.
+///
+/// The Indexes at the end of the streams locate their blocks, so the input
+/// must end after the last stream and its Stream Padding, and is left at its
+/// end. Inputs that aren't seekable, that have at most one block, or whose
+/// blocks don't fit within the [`memlimit`](decompress::Options::memlimit)
+/// on every thread are decoded on the calling thread, like
+/// [`xz_decompress_with_options`].
+pub fn xz_decompress_parallel<R: io::BufRead + io::Seek, W: io::Write>(
+    input: &mut R,
+    output: &mut W,
+    options: &decompress::Options,
+) -> error::Result<()> {
+    decode::xz_parallel::decode_stream(input, output, options)
+}
+
+/// Compress data with XZ and default
+/// [`XzOptions`](compress/struct.XzOptions.html).
 pub fn xz_compress<R: io::BufRead, W: io::Write>(input: &mut R, output: &mut W) -> io::Result<()> {
-    encode::xz::encode_stream(input, output)
+    xz_compress_with_options(input, output, &compress::XzOptions::default())
+}
+
+/// Compress data with XZ and the provided options.
+pub fn xz_compress_with_options<R: io::BufRead, W: io::Write>(
+    input: &mut R,
+    output: &mut W,
+    options: &compress::XzOptions,
+) -> io::Result<()> {
+    encode::xz::encode_stream(input, output, options)
+}
+
+/// Encode the data in place with the Delta filter and the provided options.
+///
+/// This is the Delta filter of the XZ format on its own, and is reverted by
+/// [`delta_decode`] with the same options.
+pub fn delta_encode(buf: &mut [u8], options: &compress::DeltaOptions) -> io::Result<()> {
+    options.validate()?;
+    xz::delta::Delta::new(options, true).code(buf);
+    Ok(())
+}
+
+/// Decode the data in place with the Delta filter and the provided options.
+pub fn delta_decode(buf: &mut [u8], options: &decompress::DeltaOptions) -> io::Result<()> {
+    options.validate()?;
+    xz::delta::Delta::new(options, false).code(buf);
+    Ok(())
 }
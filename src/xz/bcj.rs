@@ -0,0 +1,538 @@
+//! Branch/Call/Jump (BCJ) filters, see sect. 5.3.2 of the spec.
+//!
+//! These filters convert the relative addresses of branch instructions into
+//! absolute addresses when encoding, which makes repeated calls to the same
+//! function compress better, and back when decoding. The conversions follow
+//! the reference implementation in liblzma.
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+/// Instruction set converted by a BCJ filter.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum BcjArch {
+    X86,
+    PowerPc,
+    Ia64,
+    Arm,
+    ArmThumb,
+    Sparc,
+    Arm64,
+    RiscV,
+}
+
+impl BcjArch {
+    /// Alignment of the instructions, in bytes.
+    pub(crate) fn alignment(self) -> u32 {
+        match self {
+            BcjArch::X86 => 1,
+            BcjArch::ArmThumb | BcjArch::RiscV => 2,
+            BcjArch::PowerPc | BcjArch::Arm | BcjArch::Sparc | BcjArch::Arm64 => 4,
+            BcjArch::Ia64 => 16,
+        }
+    }
+}
+
+/// State of a BCJ filter over the uncompressed data of a block.
+#[derive(Debug)]
+pub(crate) struct Bcj {
+    arch: BcjArch,
+    encoding: bool,
+    /// Position of the next byte to convert, starting at the start offset.
+    pos: u32,
+    /// Recently seen x86 opcode bytes, as a bitmask.
+    x86_prev_mask: u32,
+    /// Position of the last x86 CALL or JMP opcode.
+    x86_prev_pos: u32,
+}
+
+impl Bcj {
+    pub(crate) fn new(arch: BcjArch, start_offset: u32, encoding: bool) -> Self {
+        Self {
+            arch,
+            encoding,
+            pos: start_offset,
+            x86_prev_mask: 0,
+            x86_prev_pos: start_offset.wrapping_sub(5),
+        }
+    }
+
+    /// Convert a prefix of `buf` in place, and return its length. The
+    /// remaining bytes may start an instruction, so they must be given again
+    /// followed by the next bytes, or left unconverted at the end of the data.
+    pub(crate) fn code(&mut self, buf: &mut [u8]) -> usize {
+        let n = match self.arch {
+            BcjArch::X86 => self.x86(buf),
+            BcjArch::PowerPc => powerpc(self.pos, self.encoding, buf),
+            BcjArch::Ia64 => ia64(self.pos, self.encoding, buf),
+            BcjArch::Arm => arm(self.pos, self.encoding, buf),
+            BcjArch::ArmThumb => arm_thumb(self.pos, self.encoding, buf),
+            BcjArch::Sparc => sparc(self.pos, self.encoding, buf),
+            BcjArch::Arm64 => arm64(self.pos, self.encoding, buf),
+            BcjArch::RiscV if self.encoding => riscv_encode(self.pos, buf),
+            BcjArch::RiscV => riscv_decode(self.pos, buf),
+        };
+        self.pos = self.pos.wrapping_add(n as u32);
+        n
+    }
+
+    fn x86(&mut self, buf: &mut [u8]) -> usize {
+        const MASK_TO_BIT_NUMBER: [u32; 5] = [0, 1, 2, 2, 3];
+
+        /// Whether the byte is the most significant byte of a near address,
+        /// i.e. 0x00 or 0xFF.
+        fn is_ms_byte(b: u8) -> bool {
+            b.wrapping_add(1) & 0xFE == 0
+        }
+
+        if buf.len() < 5 {
+            return 0;
+        }
+
+        let now_pos = self.pos;
+        let mut prev_mask = self.x86_prev_mask;
+        let mut prev_pos = self.x86_prev_pos;
+        if now_pos.wrapping_sub(prev_pos) > 5 {
+            prev_pos = now_pos.wrapping_sub(5);
+        }
+
+        let limit = buf.len() - 5;
+        let mut i = 0;
+        while i <= limit {
+            // CALL and JMP opcodes.
+            if buf[i] != 0xE8 && buf[i] != 0xE9 {
+                i += 1;
+                continue;
+            }
+
+            let pc = now_pos.wrapping_add(i as u32);
+            let offset = pc.wrapping_sub(prev_pos);
+            prev_pos = pc;
+            if offset > 5 {
+                prev_mask = 0;
+            } else {
+                for _ in 0..offset {
+                    prev_mask &= 0x77;
+                    prev_mask <<= 1;
+                }
+            }
+
+            let b = buf[i + 4];
+            if is_ms_byte(b) && (prev_mask >> 1) <= 4 && (prev_mask >> 1) != 3 {
+                let mut src = LittleEndian::read_u32(&buf[i + 1..]);
+                let mut dest;
+                loop {
+                    dest = if self.encoding {
+                        src.wrapping_add(pc.wrapping_add(5))
+                    } else {
+                        src.wrapping_sub(pc.wrapping_add(5))
+                    };
+                    if prev_mask == 0 {
+                        break;
+                    }
+                    let index = MASK_TO_BIT_NUMBER[(prev_mask >> 1) as usize];
+                    if !is_ms_byte((dest >> (24 - index * 8)) as u8) {
+                        break;
+                    }
+                    src = dest ^ ((1 << (32 - index * 8)) - 1);
+                }
+
+                // Sign-extend the 25-bit address.
+                let dest = (dest & 0x01FF_FFFF) | (0u32.wrapping_sub((dest >> 24) & 1) << 25);
+                LittleEndian::write_u32(&mut buf[i + 1..], dest);
+                i += 5;
+                prev_mask = 0;
+            } else {
+                i += 1;
+                prev_mask |= 1;
+                if is_ms_byte(b) {
+                    prev_mask |= 0x10;
+                }
+            }
+        }
+
+        self.x86_prev_mask = prev_mask;
+        self.x86_prev_pos = prev_pos;
+        i
+    }
+}
+
+/// Convert the address `src` found at position `pc`.
+fn convert(src: u32, pc: u32, encoding: bool) -> u32 {
+    if encoding {
+        src.wrapping_add(pc)
+    } else {
+        src.wrapping_sub(pc)
+    }
+}
+
+fn powerpc(now_pos: u32, encoding: bool, buf: &mut [u8]) -> usize {
+    let size = buf.len() & !3;
+    for i in (0..size).step_by(4) {
+        // Branch with the link bit set and the absolute bit unset.
+        if buf[i] >> 2 != 0x12 || buf[i + 3] & 3 != 1 {
+            continue;
+        }
+        let src = BigEndian::read_u32(&buf[i..]) & 0x03FF_FFFC;
+        let dest = convert(src, now_pos.wrapping_add(i as u32), encoding);
+        let instr = 0x4800_0000 | (dest & 0x03FF_FFFF) | (buf[i + 3] & 3) as u32;
+        BigEndian::write_u32(&mut buf[i..], instr);
+    }
+    size
+}
+
+fn ia64(now_pos: u32, encoding: bool, buf: &mut [u8]) -> usize {
+    /// Slots holding a branch, as a bitmask, for each bundle template.
+    const BRANCH_TABLE: [u32; 32] = [
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, //
+        4, 4, 6, 6, 0, 0, 7, 7, 4, 4, 0, 0, 4, 4, 0, 0,
+    ];
+
+    let size = buf.len() & !15;
+    for i in (0..size).step_by(16) {
+        let mask = BRANCH_TABLE[(buf[i] & 0x1F) as usize];
+        for slot in 0..3 {
+            if (mask >> slot) & 1 == 0 {
+                continue;
+            }
+            let bit_pos = 5 + 41 * slot;
+            let byte_pos = i + bit_pos / 8;
+            let bit_res = bit_pos % 8;
+
+            let mut instruction = 0u64;
+            for j in 0..6 {
+                instruction |= (buf[byte_pos + j] as u64) << (8 * j);
+            }
+            let mut inst_norm = instruction >> bit_res;
+            if (inst_norm >> 37) & 0xF != 0x5 || (inst_norm >> 9) & 0x7 != 0 {
+                continue;
+            }
+
+            let mut src = ((inst_norm >> 13) & 0xF_FFFF) as u32;
+            src |= (((inst_norm >> 36) & 1) as u32) << 20;
+            src <<= 4;
+            let dest = convert(src, now_pos.wrapping_add(i as u32), encoding) >> 4;
+
+            inst_norm &= !(0x8F_FFFF << 13);
+            inst_norm |= ((dest & 0xF_FFFF) as u64) << 13;
+            inst_norm |= ((dest & 0x10_0000) as u64) << (36 - 20);
+            instruction &= (1 << bit_res) - 1;
+            instruction |= inst_norm << bit_res;
+            for j in 0..6 {
+                buf[byte_pos + j] = (instruction >> (8 * j)) as u8;
+            }
+        }
+    }
+    size
+}
+
+fn arm(now_pos: u32, encoding: bool, buf: &mut [u8]) -> usize {
+    let size = buf.len() & !3;
+    for i in (0..size).step_by(4) {
+        // BL instruction.
+        if buf[i + 3] != 0xEB {
+            continue;
+        }
+        let src = LittleEndian::read_u24(&buf[i..]) << 2;
+        let dest = convert(src, now_pos.wrapping_add(i as u32 + 8), encoding) >> 2;
+        LittleEndian::write_u24(&mut buf[i..], dest & 0xFF_FFFF);
+    }
+    size
+}
+
+fn arm_thumb(now_pos: u32, encoding: bool, buf: &mut [u8]) -> usize {
+    let mut i = 0;
+    while i + 4 <= buf.len() {
+        // BL instruction, as a pair of 16-bit halves.
+        if buf[i + 1] & 0xF8 != 0xF0 || buf[i + 3] & 0xF8 != 0xF8 {
+            i += 2;
+            continue;
+        }
+        let src = ((buf[i + 1] as u32 & 7) << 19)
+            | ((buf[i] as u32) << 11)
+            | ((buf[i + 3] as u32 & 7) << 8)
+            | buf[i + 2] as u32;
+        let dest = convert(src << 1, now_pos.wrapping_add(i as u32 + 4), encoding) >> 1;
+        buf[i + 1] = 0xF0 | ((dest >> 19) & 7) as u8;
+        buf[i] = (dest >> 11) as u8;
+        buf[i + 3] = 0xF8 | ((dest >> 8) & 7) as u8;
+        buf[i + 2] = dest as u8;
+        i += 4;
+    }
+    i
+}
+
+fn sparc(now_pos: u32, encoding: bool, buf: &mut [u8]) -> usize {
+    let size = buf.len() & !3;
+    for i in (0..size).step_by(4) {
+        // CALL instruction with a displacement of at most 22 bits.
+        let is_call = (buf[i] == 0x40 && buf[i + 1] & 0xC0 == 0x00)
+            || (buf[i] == 0x7F && buf[i + 1] & 0xC0 == 0xC0);
+        if !is_call {
+            continue;
+        }
+        let src = BigEndian::read_u32(&buf[i..]) << 2;
+        let dest = convert(src, now_pos.wrapping_add(i as u32), encoding) >> 2;
+        let dest = ((0u32.wrapping_sub((dest >> 22) & 1) << 22) & 0x3FFF_FFFF)
+            | (dest & 0x3F_FFFF)
+            | 0x4000_0000;
+        BigEndian::write_u32(&mut buf[i..], dest);
+    }
+    size
+}
+
+fn arm64(now_pos: u32, encoding: bool, buf: &mut [u8]) -> usize {
+    let size = buf.len() & !3;
+    for i in (0..size).step_by(4) {
+        let pc = now_pos.wrapping_add(i as u32);
+        let instr = LittleEndian::read_u32(&buf[i..]);
+        if instr >> 26 == 0x25 {
+            // BL instruction.
+            let dest = convert(instr, pc >> 2, encoding);
+            LittleEndian::write_u32(&mut buf[i..], 0x9400_0000 | (dest & 0x03FF_FFFF));
+        } else if instr & 0x9F00_0000 == 0x9000_0000 {
+            // ADRP instruction, only converted within +/-512 MiB so that
+            // other uses of the immediate are left untouched.
+            let src = ((instr >> 29) & 3) | ((instr >> 3) & 0x001F_FFFC);
+            if src.wrapping_add(0x0002_0000) & 0x001C_0000 != 0 {
+                continue;
+            }
+            let dest = convert(src, pc >> 12, encoding);
+            let instr = (instr & 0x9000_001F)
+                | ((dest & 3) << 29)
+                | ((dest & 0x0003_FFFC) << 3)
+                | (0u32.wrapping_sub(dest & 0x0002_0000) & 0x00E0_0000);
+            LittleEndian::write_u32(&mut buf[i..], instr);
+        }
+    }
+    size
+}
+
+/// Whether the AUIPC instruction and the next instruction don't form a pair,
+/// i.e. the rd of AUIPC differs from the rs1 of `inst2`, or `inst2` is not a
+/// 32-bit instruction.
+fn riscv_not_auipc_pair(auipc: u32, inst2: u32) -> bool {
+    ((auipc << 8) ^ inst2.wrapping_sub(3)) & 0xF_8003 != 0
+}
+
+/// Whether the AUIPC instruction, whose rd is x0 or x2, doesn't have the
+/// special format used by the encoder for a converted pair.
+fn riscv_not_special_auipc(auipc: u32, inst2_rs1: u32) -> bool {
+    (auipc.wrapping_sub(0x3117) << 18) >= (inst2_rs1 & 0x1D)
+}
+
+fn riscv_encode(now_pos: u32, buf: &mut [u8]) -> usize {
+    if buf.len() < 8 {
+        return 0;
+    }
+    let limit = buf.len() - 8;
+    let mut i = 0;
+    while i <= limit {
+        let pc = now_pos.wrapping_add(i as u32);
+        let inst = buf[i] as u32;
+        if inst == 0xEF {
+            // JAL instruction, only converted with rd = x1 or x5.
+            let b1 = buf[i + 1] as u32;
+            if b1 & 0x0D != 0 {
+                i += 2;
+                continue;
+            }
+            let b2 = buf[i + 2] as u32;
+            let b3 = buf[i + 3] as u32;
+            let addr = ((b1 & 0xF0) << 8)
+                | ((b2 & 0x0F) << 16)
+                | ((b2 & 0x10) << 7)
+                | ((b2 & 0xE0) >> 4)
+                | ((b3 & 0x7F) << 4)
+                | ((b3 & 0x80) << 13);
+            let addr = addr.wrapping_add(pc);
+            // The address is stored in big-endian order.
+            buf[i + 1] = ((b1 & 0x0F) | ((addr >> 13) & 0xF0)) as u8;
+            buf[i + 2] = (addr >> 9) as u8;
+            buf[i + 3] = (addr >> 1) as u8;
+            i += 4;
+        } else if inst & 0x7F == 0x17 {
+            // AUIPC instruction.
+            let inst = LittleEndian::read_u32(&buf[i..]);
+            if inst & 0xE80 != 0 {
+                // The rd of AUIPC is neither x0 nor x2.
+                let inst2 = LittleEndian::read_u32(&buf[i + 4..]);
+                if riscv_not_auipc_pair(inst, inst2) {
+                    i += 6;
+                    continue;
+                }
+                // Store the lowest 20 bits of the second instruction in
+                // place of the immediate of AUIPC with rd = x2, followed by
+                // the absolute address in big-endian order.
+                let addr = (inst & 0xFFFF_F000)
+                    .wrapping_add(inst2 >> 20)
+                    .wrapping_sub((inst2 >> 19) & 0x1000)
+                    .wrapping_add(pc);
+                LittleEndian::write_u32(&mut buf[i..], 0x17 | (2 << 7) | (inst2 << 12));
+                BigEndian::write_u32(&mut buf[i + 4..], addr);
+            } else {
+                // The rd of AUIPC is x0 or x2, which would be mistaken for
+                // a converted pair if it has the special format, so swap
+                // its fields in a way the decoder reverts.
+                let fake_rs1 = inst >> 27;
+                if riscv_not_special_auipc(inst, fake_rs1) {
+                    i += 4;
+                    continue;
+                }
+                let fake_addr = LittleEndian::read_u32(&buf[i + 4..]);
+                let fake_inst2 = (inst >> 12) | (fake_addr << 20);
+                let inst = 0x17 | (fake_rs1 << 7) | (fake_addr & 0xFFFF_F000);
+                LittleEndian::write_u32(&mut buf[i..], inst);
+                LittleEndian::write_u32(&mut buf[i + 4..], fake_inst2);
+            }
+            i += 8;
+        } else {
+            i += 2;
+        }
+    }
+    i
+}
+
+fn riscv_decode(now_pos: u32, buf: &mut [u8]) -> usize {
+    if buf.len() < 8 {
+        return 0;
+    }
+    let limit = buf.len() - 8;
+    let mut i = 0;
+    while i <= limit {
+        let pc = now_pos.wrapping_add(i as u32);
+        let inst = buf[i] as u32;
+        if inst == 0xEF {
+            // JAL instruction, only converted with rd = x1 or x5.
+            let b1 = buf[i + 1] as u32;
+            if b1 & 0x0D != 0 {
+                i += 2;
+                continue;
+            }
+            let b2 = buf[i + 2] as u32;
+            let b3 = buf[i + 3] as u32;
+            let addr = ((b1 & 0xF0) << 13) | (b2 << 9) | (b3 << 1);
+            let addr = addr.wrapping_sub(pc);
+            buf[i + 1] = ((b1 & 0x0F) | ((addr >> 8) & 0xF0)) as u8;
+            buf[i + 2] =
+                (((addr >> 16) & 0x0F) | ((addr >> 7) & 0x10) | ((addr << 4) & 0xE0)) as u8;
+            buf[i + 3] = (((addr >> 4) & 0x7F) | ((addr >> 13) & 0x80)) as u8;
+            i += 4;
+        } else if inst & 0x7F == 0x17 {
+            // AUIPC instruction.
+            let inst = LittleEndian::read_u32(&buf[i..]);
+            let (inst, inst2) = if inst & 0xE80 != 0 {
+                // The rd of AUIPC is neither x0 nor x2, so this may be an
+                // AUIPC with the special format whose fields were swapped.
+                let inst2 = LittleEndian::read_u32(&buf[i + 4..]);
+                if riscv_not_auipc_pair(inst, inst2) {
+                    i += 6;
+                    continue;
+                }
+                let addr = (inst & 0xFFFF_F000).wrapping_add(inst2 >> 20);
+                (0x17 | (2 << 7) | (inst2 << 12), addr)
+            } else {
+                // The rd of AUIPC is x0 or x2, so this may be a converted
+                // pair.
+                let inst2_rs1 = inst >> 27;
+                if riscv_not_special_auipc(inst, inst2_rs1) {
+                    i += 4;
+                    continue;
+                }
+                let addr = BigEndian::read_u32(&buf[i + 4..]).wrapping_sub(pc);
+                let inst2 = (inst >> 12) | (addr << 20);
+                // The immediate of the second instruction is sign-extended.
+                let inst = 0x17 | (inst2_rs1 << 7) | (addr.wrapping_add(0x800) & 0xFFFF_F000);
+                (inst, inst2)
+            };
+            LittleEndian::write_u32(&mut buf[i..], inst);
+            LittleEndian::write_u32(&mut buf[i + 4..], inst2);
+            i += 8;
+        } else {
+            i += 2;
+        }
+    }
+    i
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ARCHS: [BcjArch; 8] = [
+        BcjArch::X86,
+        BcjArch::PowerPc,
+        BcjArch::Ia64,
+        BcjArch::Arm,
+        BcjArch::ArmThumb,
+        BcjArch::Sparc,
+        BcjArch::Arm64,
+        BcjArch::RiscV,
+    ];
+
+    /// Pseudo-random bytes, which contain enough instruction patterns for
+    /// every filter.
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut state = 0x1234_5678u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    /// Convert the data in chunks of the given size, as a filter chain does.
+    fn code_chunked(mut bcj: Bcj, data: &[u8], chunk: usize) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut pending = Vec::new();
+        for data in data.chunks(chunk) {
+            pending.extend_from_slice(data);
+            let n = bcj.code(&mut pending);
+            output.extend(pending.drain(..n));
+        }
+        output.append(&mut pending);
+        output
+    }
+
+    #[test]
+    fn test_x86_known_call() {
+        // CALL with a relative address of 0x10 at position 0x100.
+        let mut buf = [0xE8, 0x10, 0x00, 0x00, 0x00, 0x90, 0x90, 0x90];
+        let mut bcj = Bcj::new(BcjArch::X86, 0x100, true);
+        assert_eq!(bcj.code(&mut buf), 5);
+        assert_eq!(buf, [0xE8, 0x15, 0x01, 0x00, 0x00, 0x90, 0x90, 0x90]);
+
+        let mut bcj = Bcj::new(BcjArch::X86, 0x100, false);
+        assert_eq!(bcj.code(&mut buf), 5);
+        assert_eq!(buf, [0xE8, 0x10, 0x00, 0x00, 0x00, 0x90, 0x90, 0x90]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let input = random_bytes(0x10000);
+        for &arch in &ARCHS {
+            for &start_offset in &[0, 0x1000, 0xFFFF_FF00] {
+                let encoded = code_chunked(Bcj::new(arch, start_offset, true), &input, 4096);
+                assert_eq!(encoded.len(), input.len());
+                assert_ne!(encoded, input, "{:?} converted nothing", arch);
+
+                for &chunk in &[1, 3, 16, 1000, 4096] {
+                    let decoded =
+                        code_chunked(Bcj::new(arch, start_offset, false), &encoded, chunk);
+                    assert_eq!(decoded, input, "{:?} with chunks of {}", arch, chunk);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_short_input() {
+        for &arch in &ARCHS {
+            let mut buf = [0xE8, 0xEB, 0xEF, 0x17];
+            let n = Bcj::new(arch, 0, false).code(&mut buf[..1]);
+            assert_eq!(n, 0, "{:?}", arch);
+        }
+    }
+}
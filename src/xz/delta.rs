@@ -0,0 +1,178 @@
+//! Delta filter, see sect. 5.3.3 of the spec.
+//!
+//! Each byte is stored as its difference with the byte `dist` positions
+//! before it, which makes fixed-size samples such as audio or raster images
+//! more compressible.
+
+use crate::encode::options::DeltaOptions;
+use std::io::{self, Write};
+
+/// State of a Delta filter over the data.
+#[derive(Debug)]
+pub(crate) struct Delta {
+    encoding: bool,
+    dist: usize,
+    /// Last 256 bytes of the original data, in reverse order from `pos`.
+    history: [u8; 256],
+    pos: u8,
+}
+
+impl Delta {
+    /// Create a filter with validated `options`.
+    pub(crate) fn new(options: &DeltaOptions, encoding: bool) -> Self {
+        Self {
+            encoding,
+            dist: options.dist as usize,
+            history: [0; 256],
+            pos: 0,
+        }
+    }
+
+    /// Convert all of `buf` in place.
+    pub(crate) fn code(&mut self, buf: &mut [u8]) {
+        for byte in buf.iter_mut() {
+            let prev = self.history[(self.dist + self.pos as usize) & 0xFF];
+            if self.encoding {
+                self.history[self.pos as usize] = *byte;
+                *byte = byte.wrapping_sub(prev);
+            } else {
+                *byte = byte.wrapping_add(prev);
+                self.history[self.pos as usize] = *byte;
+            }
+            self.pos = self.pos.wrapping_sub(1);
+        }
+    }
+}
+
+macro_rules! delta_writer {
+    ($(#[$doc:meta])* $name:ident, $encoding:expr) => {
+        $(#[$doc])*
+        #[derive(Debug)]
+        pub struct $name<W>
+        where
+            W: Write,
+        {
+            output: W,
+            delta: Delta,
+            /// Converted bytes, reused across writes.
+            buf: Vec<u8>,
+        }
+
+        impl<W> $name<W>
+        where
+            W: Write,
+        {
+            /// Initialize the filter with the given `options`. This will
+            /// consume the `output` which is the sink implementing
+            /// [`io::Write`] that will receive the converted bytes.
+            pub fn new(options: &DeltaOptions, output: W) -> io::Result<Self> {
+                options.validate()?;
+                Ok(Self {
+                    output,
+                    delta: Delta::new(options, $encoding),
+                    buf: Vec::new(),
+                })
+            }
+
+            /// Get a reference to the output sink.
+            pub fn get_ref(&self) -> &W {
+                &self.output
+            }
+
+            /// Get a mutable reference to the output sink. Writing to it
+            /// inserts bytes that aren't converted.
+            pub fn get_mut(&mut self) -> &mut W {
+                &mut self.output
+            }
+
+            /// Consume the filter and return the output sink. No bytes are
+            /// buffered, so they were all written to it.
+            pub fn into_inner(self) -> W {
+                self.output
+            }
+        }
+
+        impl<W> Write for $name<W>
+        where
+            W: Write,
+        {
+            fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+                // The history already includes the bytes once converted, so
+                // they are all written.
+                self.buf.clear();
+                self.buf.extend_from_slice(data);
+                self.delta.code(&mut self.buf);
+                self.output.write_all(&self.buf)?;
+                Ok(data.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                self.output.flush()
+            }
+        }
+    };
+}
+
+delta_writer!(
+    /// Delta encoder implementing [`io::Write`], writing the differences
+    /// between the bytes given and the bytes `dist` positions before them to
+    /// an output sink.
+    ///
+    /// This is the Delta filter of the XZ format on its own, see
+    /// [`crate::delta_encode`] to convert a byte slice.
+    DeltaEncoder,
+    true
+);
+
+delta_writer!(
+    /// Delta decoder implementing [`io::Write`], writing the data restored
+    /// from the differences given to an output sink.
+    ///
+    /// This reverts [`DeltaEncoder`], see
+    /// [`crate::delta_decode`] to convert a byte slice.
+    DeltaDecoder,
+    false
+);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let options = DeltaOptions { dist: 2 };
+        let mut buf = [1, 2, 4, 6, 7, 8, 0];
+        Delta::new(&options, true).code(&mut buf);
+        assert_eq!(buf, [1, 2, 3, 4, 3, 2, 249]);
+
+        Delta::new(&options, false).code(&mut buf);
+        assert_eq!(buf, [1, 2, 4, 6, 7, 8, 0]);
+    }
+
+    #[test]
+    fn test_writers() {
+        let input = include_bytes!("../../tests/files/foo.txt");
+        for &dist in &[1, 2, 7, 256] {
+            let options = DeltaOptions { dist };
+            let mut encoder = DeltaEncoder::new(&options, Vec::new()).unwrap();
+            let mut decoder = DeltaDecoder::new(&options, Vec::new()).unwrap();
+            for data in input.chunks(1000) {
+                encoder.write_all(data).unwrap();
+            }
+            let encoded = encoder.into_inner();
+            assert_ne!(&encoded[..], &input[..]);
+            for data in encoded.chunks(77) {
+                decoder.write_all(data).unwrap();
+            }
+            assert_eq!(&decoder.into_inner()[..], &input[..]);
+        }
+    }
+
+    #[test]
+    fn test_invalid_dist() {
+        for &dist in &[0, 257] {
+            let err = DeltaEncoder::new(&DeltaOptions { dist }, Vec::new()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Filters preceding LZMA2 in the filter chain of a block, see sect. 5.3.
+
+use crate::xz::bcj::Bcj;
+use crate::xz::delta::Delta;
+
+/// Converter of a non-last filter.
+#[derive(Debug)]
+pub(crate) enum Converter {
+    Bcj(Bcj),
+    Delta(Box<Delta>),
+}
+
+impl Converter {
+    /// Convert a prefix of `buf` in place, and return its length.
+    fn code(&mut self, buf: &mut [u8]) -> usize {
+        match self {
+            Converter::Bcj(bcj) => bcj.code(buf),
+            Converter::Delta(delta) => {
+                delta.code(buf);
+                buf.len()
+            }
+        }
+    }
+}
+
+/// Filter applied to the uncompressed data, in place.
+#[derive(Debug)]
+struct Stage {
+    converter: Converter,
+    /// Bytes given to the filter but not converted yet.
+    pending: Vec<u8>,
+}
+
+/// Chain of non-last filters, in the order the data goes through them.
+#[derive(Debug, Default)]
+pub(crate) struct FilterChain {
+    stages: Vec<Stage>,
+}
+
+impl FilterChain {
+    pub(crate) fn new(filters: Vec<Converter>) -> Self {
+        Self {
+            stages: filters
+                .into_iter()
+                .map(|converter| Stage {
+                    converter,
+                    pending: Vec::new(),
+                })
+                .collect(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Run `data` through the filters, appending the bytes that went through
+    /// all of them to `output`.
+    pub(crate) fn process(&mut self, data: &[u8], output: &mut Vec<u8>) {
+        self.run(data, output, false)
+    }
+
+    /// Append the bytes still held by the filters to `output`, at the end of
+    /// the data. Each filter leaves the bytes it can't convert unchanged.
+    pub(crate) fn finish(&mut self, output: &mut Vec<u8>) {
+        self.run(&[], output, true)
+    }
+
+    fn run(&mut self, data: &[u8], output: &mut Vec<u8>, finishing: bool) {
+        let start = output.len();
+        output.extend_from_slice(data);
+        for stage in &mut self.stages {
+            stage.pending.extend_from_slice(&output[start..]);
+            output.truncate(start);
+            let n = stage.converter.code(&mut stage.pending);
+            let n = if finishing { stage.pending.len() } else { n };
+            output.extend(stage.pending.drain(..n));
+        }
+    }
+}
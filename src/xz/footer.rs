@@ -1,4 +1,54 @@
 //! XZ footer.
 
+use crate::decode::util;
+use crate::error;
+use crate::xz::crc::CRC32;
+use crate::xz::StreamFlags;
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+
 /// File format trailing terminator, see sect. 2.1.2.4.
 pub(crate) const XZ_MAGIC_FOOTER: &[u8] = &[0x59, 0x5A];
+
+/// Stream Footer, see sect. 2.1.2.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct StreamFooter {
+    /// Size of the Index, given by the Backward Size.
+    pub(crate) index_size: u64,
+    pub(crate) stream_flags: StreamFlags,
+}
+
+impl StreamFooter {
+    /// Parse a Stream Footer from a buffered reader.
+    pub(crate) fn parse<BR>(input: &mut BR) -> error::Result<Self>
+    where
+        BR: std::io::BufRead,
+    {
+        let crc32 = input.read_u32::<LittleEndian>()?;
+        let (backward_size, flags, digested) = {
+            let mut digest = CRC32.digest();
+            let mut digest_rd = util::CrcDigestRead::new(input, &mut digest);
+            let backward_size = digest_rd.read_u32::<LittleEndian>()?;
+            let flags = digest_rd.read_u16::<BigEndian>()?;
+            (backward_size, flags, digest.finalize())
+        };
+
+        if crc32 != digested {
+            return Err(error::Error::XzError(format!(
+                "Invalid footer CRC32: expected 0x{:08x} but got 0x{:08x}",
+                crc32, digested
+            )));
+        }
+
+        if !util::read_tag(input, XZ_MAGIC_FOOTER)? {
+            return Err(error::Error::XzError(format!(
+                "Invalid footer magic, expected {:?}",
+                XZ_MAGIC_FOOTER
+            )));
+        }
+
+        Ok(Self {
+            index_size: (backward_size as u64 + 1) << 2,
+            stream_flags: StreamFlags::parse(flags)?,
+        })
+    }
+}
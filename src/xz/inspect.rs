@@ -0,0 +1,285 @@
+//! Metadata of `.xz` files, read without decompressing them like
+//! `xz --list --verbose`.
+
+use crate::compress::Filter;
+use crate::decode::xz::{self as decode_xz, Record};
+use crate::decode::xz_index::{self, StreamIndex};
+use crate::error;
+use crate::xz::CheckMethod;
+use std::io;
+
+/// Metadata of the XZ streams of a file, see [`inspect`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct XzFileInfo {
+    /// The streams, in order.
+    pub streams: Vec<StreamInfo>,
+}
+
+/// Metadata of an XZ stream.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamInfo {
+    /// Position of the stream in the file.
+    pub compressed_offset: u64,
+    /// Position of the decompressed data of the stream in the decompressed
+    /// data of the file.
+    pub uncompressed_offset: u64,
+    /// Size of the stream, without the Stream Padding after it.
+    pub compressed_size: u64,
+    /// Size of the decompressed data of the stream.
+    pub uncompressed_size: u64,
+    /// Integrity check of the blocks.
+    pub check: CheckMethod,
+    /// Size of the Stream Padding after the stream.
+    pub padding: u64,
+    /// The blocks, in order.
+    pub blocks: Vec<BlockInfo>,
+}
+
+/// Metadata of a block of an XZ stream.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockInfo {
+    /// Position of the block in the file.
+    pub compressed_offset: u64,
+    /// Position of the decompressed data of the block in the decompressed
+    /// data of the file.
+    pub uncompressed_offset: u64,
+    /// Size of the block, including its header, padding and check.
+    pub total_size: u64,
+    /// Size of the decompressed data of the block.
+    pub uncompressed_size: u64,
+    /// Size of the Block Header.
+    pub header_size: u64,
+    /// Size of the compressed data, without the header, padding and check.
+    pub data_size: u64,
+    /// Filters applied before LZMA2, in encoding order.
+    pub filters: Vec<Filter>,
+    /// Dictionary size of the LZMA2 filter.
+    pub dict_size: u32,
+}
+
+impl XzFileInfo {
+    /// Number of blocks in all the streams.
+    pub fn num_blocks(&self) -> usize {
+        self.streams.iter().map(|stream| stream.blocks.len()).sum()
+    }
+
+    /// Size of the file, including the Stream Padding.
+    pub fn compressed_size(&self) -> u64 {
+        self.streams
+            .iter()
+            .map(|stream| stream.compressed_size + stream.padding)
+            .sum()
+    }
+
+    /// Size of the decompressed data.
+    pub fn uncompressed_size(&self) -> u64 {
+        self.streams
+            .iter()
+            .map(|stream| stream.uncompressed_size)
+            .sum()
+    }
+
+    /// Total size of the Stream Padding.
+    pub fn padding(&self) -> u64 {
+        self.streams.iter().map(|stream| stream.padding).sum()
+    }
+
+    /// Compressed size divided by the uncompressed size, like the ratio of
+    /// `xz --list`, or [`None`] if there is no decompressed data.
+    pub fn ratio(&self) -> Option<f64> {
+        match self.uncompressed_size() {
+            0 => None,
+            size => Some(self.compressed_size() as f64 / size as f64),
+        }
+    }
+
+    /// Integrity checks used by the streams, without duplicates.
+    pub fn checks(&self) -> Vec<CheckMethod> {
+        let mut checks = Vec::new();
+        for stream in &self.streams {
+            if !checks.contains(&stream.check) {
+                checks.push(stream.check);
+            }
+        }
+        checks
+    }
+}
+
+/// Read the metadata of the XZ streams between the current position of
+/// `input` and its end, without decompressing them.
+///
+/// The Indexes are read backwards from the end of the input, so the streams
+/// must be followed only by their Stream Padding. The headers of the blocks
+/// are read for their filters, but their data isn't checked.
+pub fn inspect<R>(input: &mut R) -> error::Result<XzFileInfo>
+where
+    R: io::Read + io::Seek,
+{
+    let start = input.stream_position()?;
+    let streams = xz_index::read_indexes(input, start)?;
+
+    let mut uncompressed_offset = 0;
+    let mut infos = Vec::with_capacity(streams.len());
+    for stream in &streams {
+        let info = inspect_stream(input, stream, uncompressed_offset)?;
+        uncompressed_offset = add_size(uncompressed_offset, info.uncompressed_size)?;
+        infos.push(info);
+    }
+    Ok(XzFileInfo { streams: infos })
+}
+
+/// Add decompressed sizes given by the Indexes, which may overflow.
+fn add_size(size: u64, other: u64) -> error::Result<u64> {
+    size.checked_add(other).ok_or_else(|| {
+        error::Error::XzError("Invalid index, too large decompressed size".to_string())
+    })
+}
+
+fn inspect_stream<R>(
+    input: &mut R,
+    stream: &StreamIndex,
+    uncompressed_offset: u64,
+) -> error::Result<StreamInfo>
+where
+    R: io::Read + io::Seek,
+{
+    let check = stream.stream_flags.check_method;
+    let mut blocks = Vec::with_capacity(stream.records.len());
+    let mut block_offset = uncompressed_offset;
+    for (offset, record) in stream.blocks() {
+        let block = inspect_block(input, offset, record, check, block_offset)?;
+        block_offset = add_size(block_offset, block.uncompressed_size)?;
+        blocks.push(block);
+    }
+
+    Ok(StreamInfo {
+        compressed_offset: stream.offset,
+        uncompressed_offset,
+        compressed_size: stream.size(),
+        uncompressed_size: block_offset - uncompressed_offset,
+        check,
+        padding: stream.padding,
+        blocks,
+    })
+}
+
+fn inspect_block<R>(
+    input: &mut R,
+    offset: u64,
+    record: &Record,
+    check: CheckMethod,
+    uncompressed_offset: u64,
+) -> error::Result<BlockInfo>
+where
+    R: io::Read + io::Seek,
+{
+    let (block_header, header_size) = decode_xz::read_block_header_at(input, offset)?;
+    let (filters, dict_size) = decode_xz::block_filters(&block_header)?;
+
+    let header_size = (header_size as u64 + 1) * 4;
+    let overhead = header_size + check.size() as u64;
+    if record.unpadded_size < overhead {
+        return Err(error::Error::XzError(format!(
+            "Invalid index, unpadded size {} smaller than the block header and check",
+            record.unpadded_size
+        )));
+    }
+
+    Ok(BlockInfo {
+        compressed_offset: offset,
+        uncompressed_offset,
+        total_size: xz_index::block_size(record),
+        uncompressed_size: record.unpacked_size,
+        header_size,
+        data_size: record.unpadded_size - overhead,
+        filters,
+        dict_size,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compress::{BcjOptions, DeltaOptions};
+
+    /// Compare with the output of `xz --list --verbose --verbose --robot`.
+    #[test]
+    fn test_xz_files() {
+        let mut input = io::Cursor::new(include_bytes!("../../tests/files/bcj-x86-blocks.bin.xz"));
+        let info = inspect(&mut input).unwrap();
+        assert_eq!(info.streams.len(), 1);
+        assert_eq!(info.num_blocks(), 3);
+        assert_eq!(info.compressed_size(), 12416);
+        assert_eq!(info.uncompressed_size(), 12288);
+        assert_eq!(info.checks(), vec![CheckMethod::Crc64]);
+        let stream = &info.streams[0];
+        let sizes: Vec<_> = stream
+            .blocks
+            .iter()
+            .map(|block| {
+                (
+                    block.compressed_offset,
+                    block.uncompressed_offset,
+                    block.total_size,
+                    block.uncompressed_size,
+                    block.header_size,
+                    block.data_size,
+                )
+            })
+            .collect();
+        assert_eq!(
+            sizes,
+            vec![
+                (12, 0, 5028, 5000, 16, 5004),
+                (5040, 5000, 5028, 5000, 16, 5004),
+                (10068, 10000, 2316, 2288, 16, 2292),
+            ]
+        );
+        for block in &stream.blocks {
+            assert_eq!(block.filters, vec![Filter::X86(BcjOptions::default())]);
+            assert_eq!(block.dict_size, 8 << 20);
+        }
+
+        let mut input = io::Cursor::new(include_bytes!("../../tests/files/delta-2.bin.xz"));
+        let info = inspect(&mut input).unwrap();
+        let block = &info.streams[0].blocks[0];
+        assert_eq!(block.filters, vec![Filter::Delta(DeltaOptions { dist: 2 })]);
+        assert_eq!((block.header_size, block.data_size), (20, 3502));
+        assert_eq!(info.ratio(), Some(3568.0 / 4096.0));
+    }
+
+    #[test]
+    fn test_streams() {
+        let mut compressed = b"head".to_vec();
+        crate::xz_compress(&mut &b"Hello"[..], &mut compressed).unwrap();
+        compressed.extend_from_slice(&[0; 8]);
+        let start = compressed.len() as u64;
+        let options = crate::compress::XzOptions {
+            check: CheckMethod::Sha256,
+            ..Default::default()
+        };
+        crate::xz_compress_with_options(&mut &b"world"[..], &mut compressed, &options).unwrap();
+        compressed.extend_from_slice(&[0; 4]);
+
+        let mut input = io::Cursor::new(&compressed);
+        input.set_position(4);
+        let info = inspect(&mut input).unwrap();
+        assert_eq!(info.streams.len(), 2);
+        assert_eq!(info.padding(), 12);
+        assert_eq!(info.compressed_size(), compressed.len() as u64 - 4);
+        assert_eq!(info.uncompressed_size(), 10);
+        assert_eq!(info.checks(), vec![CheckMethod::Crc64, CheckMethod::Sha256]);
+        assert_eq!(info.streams[0].padding, 8);
+        assert_eq!(info.streams[1].compressed_offset, start);
+        assert_eq!(info.streams[1].uncompressed_offset, 5);
+        assert_eq!(info.streams[1].blocks[0].uncompressed_offset, 5);
+
+        let mut empty = Vec::new();
+        crate::xz_compress(&mut &b""[..], &mut empty).unwrap();
+        let info = inspect(&mut io::Cursor::new(&empty)).unwrap();
+        assert_eq!(info.num_blocks(), 1);
+        assert_eq!(info.ratio(), None);
+
+        inspect(&mut io::Cursor::new(b"garbage")).unwrap_err();
+    }
+}
@@ -1,15 +1,26 @@
-//! Logic for handling `.xz` file format.
+//! The `.xz` file format, and the inspection of `.xz` files without
+//! decompressing them, see [`inspect()`].
 //!
 //! Format specifications are at [https://tukaani.org/xz/xz-file-format.txt][spec].
 //!
 //! [spec]: https://tukaani.org/xz/xz-file-format.txt
 
 use crate::error;
+use crate::xz::crc::{CRC32, CRC64};
+use crate::xz::sha256::Sha256;
 use std::io;
 
+pub(crate) mod bcj;
 pub(crate) mod crc;
+pub(crate) mod delta;
+pub(crate) mod filter;
 pub(crate) mod footer;
 pub(crate) mod header;
+mod inspect;
+pub(crate) mod pool;
+pub(crate) mod sha256;
+
+pub use inspect::{inspect, BlockInfo, StreamInfo, XzFileInfo};
 
 /// Stream flags, see sect. 2.1.1.2.
 ///
@@ -43,9 +54,7 @@ impl StreamFlags {
         W: io::Write,
     {
         // First byte is currently unused and hard-coded to null.
-        writer
-            .write(&[0x00, self.check_method as u8])
-            .map_err(Into::into)
+        writer.write(&[0x00, self.check_method as u8])
     }
 }
 
@@ -53,9 +62,13 @@ impl StreamFlags {
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[repr(u8)]
 pub enum CheckMethod {
+    /// No integrity check.
     None = 0x00,
+    /// CRC32 of the uncompressed data of each block.
     Crc32 = 0x01,
+    /// CRC64 of the uncompressed data of each block.
     Crc64 = 0x04,
+    /// SHA-256 of the uncompressed data of each block.
     Sha256 = 0x0A,
 }
 
@@ -73,6 +86,54 @@ impl CheckMethod {
             ))),
         }
     }
+
+    /// Size in bytes of the Block Check field.
+    pub(crate) fn size(self) -> usize {
+        match self {
+            CheckMethod::None => 0,
+            CheckMethod::Crc32 => 4,
+            CheckMethod::Crc64 => 8,
+            CheckMethod::Sha256 => sha256::DIGEST_SIZE,
+        }
+    }
+}
+
+/// Running Block Check over the uncompressed data of a block.
+pub(crate) enum Check {
+    None,
+    Crc32(::crc::Digest<'static, u32>),
+    Crc64(::crc::Digest<'static, u64>),
+    Sha256(Sha256),
+}
+
+impl Check {
+    pub(crate) fn new(method: CheckMethod) -> Self {
+        match method {
+            CheckMethod::None => Check::None,
+            CheckMethod::Crc32 => Check::Crc32(CRC32.digest()),
+            CheckMethod::Crc64 => Check::Crc64(CRC64.digest()),
+            CheckMethod::Sha256 => Check::Sha256(Sha256::new()),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Check::None => (),
+            Check::Crc32(digest) => digest.update(data),
+            Check::Crc64(digest) => digest.update(data),
+            Check::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Value of the Block Check field, with CRCs in little-endian.
+    pub(crate) fn finalize(self) -> Vec<u8> {
+        match self {
+            Check::None => Vec::new(),
+            Check::Crc32(digest) => digest.finalize().to_le_bytes().to_vec(),
+            Check::Crc64(digest) => digest.finalize().to_le_bytes().to_vec(),
+            Check::Sha256(hasher) => hasher.finalize().to_vec(),
+        }
+    }
 }
 
 impl From<CheckMethod> for u8 {
@@ -90,7 +151,7 @@ mod test {
     #[test]
     fn test_checkmethod_roundtrip() {
         let mut count_valid = 0;
-        for input in 0..std::u8::MAX {
+        for input in 0..u8::MAX {
             if let Ok(check) = CheckMethod::try_from(input) {
                 let output: u8 = check.into();
                 assert_eq!(input, output);
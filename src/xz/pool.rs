@@ -0,0 +1,211 @@
+//! Pool of threads processing XZ blocks in parallel.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+
+/// State shared between a [`Pool`] and its threads.
+struct State<J, T> {
+    /// Jobs waiting for a thread, with their indices.
+    jobs: VecDeque<(usize, J)>,
+    /// Results not returned yet, by index.
+    results: BTreeMap<usize, thread::Result<T>>,
+    /// Whether the pool is dropped, which stops the threads.
+    closed: bool,
+}
+
+struct Shared<J, T> {
+    state: Mutex<State<J, T>>,
+    /// Notified when a job or a result is added, and when the pool closes.
+    changed: Condvar,
+}
+
+impl<J, T> Shared<J, T> {
+    fn lock(&self) -> MutexGuard<'_, State<J, T>> {
+        // The lock isn't held while processing a job, so the state is
+        // consistent even if a thread panicked.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn wait<'a>(&self, state: MutexGuard<'a, State<J, T>>) -> MutexGuard<'a, State<J, T>> {
+        self.changed.wait(state).unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Threads processing jobs, whose results are returned in the order the jobs
+/// were submitted.
+pub(crate) struct Pool<J, T> {
+    shared: Arc<Shared<J, T>>,
+    /// The threads, joined when the pool is dropped.
+    handles: Vec<thread::JoinHandle<()>>,
+    threads: usize,
+    /// Index of the next job submitted.
+    next_submitted: usize,
+    /// Index of the next result returned.
+    next_returned: usize,
+}
+
+impl<J, T> Pool<J, T>
+where
+    J: Send + 'static,
+    T: Send + 'static,
+{
+    /// Start `threads` threads processing the jobs with `work`.
+    pub(crate) fn new<F>(threads: usize, work: F) -> Self
+    where
+        F: Fn(J) -> T + Send + Sync + 'static,
+    {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                jobs: VecDeque::new(),
+                results: BTreeMap::new(),
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let work = Arc::new(work);
+        let handles = (0..threads)
+            .map(|_| {
+                let shared = shared.clone();
+                let work = work.clone();
+                thread::spawn(move || run(&shared, &*work))
+            })
+            .collect();
+        Self {
+            shared,
+            handles,
+            threads,
+            next_submitted: 0,
+            next_returned: 0,
+        }
+    }
+
+    /// Whether as many jobs as threads are submitted and not returned yet,
+    /// in which case the next result should be returned before submitting
+    /// another job to bound the memory used.
+    pub(crate) fn is_full(&self) -> bool {
+        self.next_submitted - self.next_returned >= self.threads
+    }
+
+    pub(crate) fn submit(&mut self, job: J) {
+        self.shared
+            .lock()
+            .jobs
+            .push_back((self.next_submitted, job));
+        self.next_submitted += 1;
+        self.shared.changed.notify_all();
+    }
+
+    /// Wait for the next result in order, or return [`None`] if all the
+    /// results were returned. A panic while processing the job is resumed
+    /// here.
+    pub(crate) fn next(&mut self) -> Option<T> {
+        if self.next_returned == self.next_submitted {
+            return None;
+        }
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(result) = state.results.remove(&self.next_returned) {
+                self.next_returned += 1;
+                return match result {
+                    Ok(result) => Some(result),
+                    Err(payload) => panic::resume_unwind(payload),
+                };
+            }
+            state = self.shared.wait(state);
+        }
+    }
+}
+
+impl<J, T> Drop for Pool<J, T> {
+    /// Drop the jobs waiting for a thread, and wait for the threads to finish
+    /// their current job, so that no work outlives the pool.
+    fn drop(&mut self) {
+        let jobs = {
+            let mut state = self.shared.lock();
+            state.closed = true;
+            std::mem::take(&mut state.jobs)
+        };
+        drop(jobs);
+        self.shared.changed.notify_all();
+        for handle in self.handles.drain(..) {
+            // The jobs are run catching their panics.
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Process the jobs submitted to the pool until it is dropped.
+fn run<J, T, F>(shared: &Shared<J, T>, work: &F)
+where
+    F: Fn(J) -> T,
+{
+    loop {
+        let (index, job) = {
+            let mut state = shared.lock();
+            loop {
+                if state.closed {
+                    return;
+                }
+                if let Some(job) = state.jobs.pop_front() {
+                    break job;
+                }
+                state = shared.wait(state);
+            }
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| work(job)));
+        shared.lock().results.insert(index, result);
+        shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_order() {
+        let mut pool = Pool::new(4, |n: u64| {
+            // Later jobs finish first.
+            thread::sleep(std::time::Duration::from_millis(20 - n));
+            n * n
+        });
+        let mut results = Vec::new();
+        for n in 0..20 {
+            if pool.is_full() {
+                results.push(pool.next().unwrap());
+            }
+            pool.submit(n);
+        }
+        while let Some(result) = pool.next() {
+            results.push(result);
+        }
+        assert_eq!(results, (0..20).map(|n| n * n).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_drop() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let done = Arc::new(AtomicUsize::new(0));
+        let mut pool = {
+            let done = done.clone();
+            Pool::new(2, move |n: u64| {
+                thread::sleep(std::time::Duration::from_millis(50));
+                done.fetch_add(1, Ordering::SeqCst);
+                n
+            })
+        };
+        for n in 0..10 {
+            pool.submit(n);
+        }
+        thread::sleep(std::time::Duration::from_millis(10));
+        drop(pool);
+        // The jobs being processed are finished, and the others dropped.
+        let count = done.load(Ordering::SeqCst);
+        assert!(count <= 2, "{}", count);
+        thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(done.load(Ordering::SeqCst), count);
+    }
+}
@@ -19,6 +19,7 @@ fn round_trip(x: &[u8]) {
     // Do another round trip, but this time also write it to the header
     let encode_options = lzma_rs::compress::Options {
         unpacked_size: lzma_rs::compress::UnpackedSize::WriteToHeader(Some(x.len() as u64)),
+        ..Default::default()
     };
     let decode_options = lzma_rs::decompress::Options {
         unpacked_size: lzma_rs::decompress::UnpackedSize::ReadFromHeader,
@@ -167,6 +168,238 @@ fn round_trip_files() {
     round_trip_file("tests/files/range-coder-edge-case");
 }
 
+/// Generate pseudo-random data made of literals and copies of previous data,
+/// with a bias towards reusing recent distances.
+fn generate_lz_data(len: usize) -> Vec<u8> {
+    let mut seed: u32 = 0x1234_5678;
+    let mut next = move || {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (seed >> 8) as usize
+    };
+
+    let mut data: Vec<u8> = Vec::with_capacity(len);
+    let mut dists = [1usize; 4];
+    while data.len() < len {
+        let choice = next() % 8;
+        if data.is_empty() || choice < 3 {
+            data.push(next() as u8);
+            continue;
+        }
+
+        let dist = if choice < 6 {
+            dists[next() % 4]
+        } else {
+            1 + next() % data.len()
+        };
+        let dist = std::cmp::min(dist, data.len());
+        dists.rotate_right(1);
+        dists[0] = dist;
+
+        let copy_len = if next() % 4 == 0 {
+            1 + next() % 300
+        } else {
+            2 + next() % 8
+        };
+        for _ in 0..copy_len {
+            data.push(data[data.len() - dist]);
+        }
+    }
+    data
+}
+
+#[test]
+fn round_trip_lz_data() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    round_trip(&generate_lz_data(1 << 10));
+    round_trip(&generate_lz_data(1 << 18));
+}
+
+fn round_trip_with_encode_options(
+    x: &[u8],
+    options: &lzma_rs::compress::Options,
+    compare_to_liblzma: bool,
+) {
+    let mut compressed: Vec<u8> = Vec::new();
+    lzma_rs::lzma_compress_with_options(&mut std::io::BufReader::new(x), &mut compressed, options)
+        .unwrap();
+    #[cfg(feature = "enable_logging")]
+    info!("Compressed {} -> {} bytes", x.len(), compressed.len());
+
+    assert_decomp_eq(&compressed, x, compare_to_liblzma);
+}
+
+#[test]
+fn round_trip_properties() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let data = generate_lz_data(1 << 14);
+    for lc in 0..=8 {
+        for lp in 0..=4 {
+            for pb in [0, 2, 4] {
+                let options = lzma_rs::compress::Options {
+                    lc,
+                    lp,
+                    pb,
+                    ..Default::default()
+                };
+                // liblzma only supports lc + lp <= 4.
+                round_trip_with_encode_options(&data, &options, lc + lp <= 4);
+            }
+        }
+    }
+}
+
+#[test]
+fn round_trip_small_dict() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let options = lzma_rs::compress::Options {
+        dict_size: 0x1000,
+        ..Default::default()
+    };
+    round_trip_with_encode_options(&generate_lz_data(1 << 18), &options, true);
+    round_trip_with_encode_options(
+        &read_all_file("tests/files/foo.txt").unwrap(),
+        &options,
+        true,
+    );
+}
+
+#[test]
+fn round_trip_presets() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let data = generate_lz_data(1 << 16);
+    for level in 0..=9 {
+        for extreme in [false, true] {
+            let options = lzma_rs::compress::Options::preset(level, extreme).unwrap();
+            round_trip_with_encode_options(&data, &options, true);
+        }
+    }
+}
+
+#[test]
+fn round_trip_modes() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::Mode;
+    let data = generate_lz_data(1 << 16);
+    for mode in [Mode::Fast, Mode::Normal] {
+        for x in [b"" as &[u8], b"a", b"aa", b"abababab", &data] {
+            let options = lzma_rs::compress::Options {
+                mode,
+                ..Default::default()
+            };
+            round_trip_with_encode_options(x, &options, true);
+        }
+    }
+
+    // The optimal parser should not do worse than the greedy one.
+    let input = include_bytes!("files/foo.txt");
+    let mut sizes = Vec::new();
+    for mode in [Mode::Fast, Mode::Normal] {
+        let options = lzma_rs::compress::Options {
+            mode,
+            ..Default::default()
+        };
+        let mut compressed = Vec::new();
+        lzma_rs::lzma_compress_with_options(&mut &input[..], &mut compressed, &options).unwrap();
+        assert_decomp_eq(&compressed, input, true);
+        sizes.push(compressed.len());
+    }
+    assert!(sizes[1] < sizes[0], "{:?}", sizes);
+}
+
+#[test]
+fn round_trip_match_finders() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::MatchFinder;
+    let data = generate_lz_data(1 << 16);
+    for &match_finder in &[
+        MatchFinder::Hc3,
+        MatchFinder::Hc4,
+        MatchFinder::Bt2,
+        MatchFinder::Bt3,
+        MatchFinder::Bt4,
+    ] {
+        for (nice_len, depth) in [(2, 0), (16, 1), (64, 0), (273, 1000)] {
+            let options = lzma_rs::compress::Options {
+                match_finder,
+                nice_len,
+                depth,
+                dict_size: 0x1_0000,
+                ..Default::default()
+            };
+            round_trip_with_encode_options(&data, &options, true);
+        }
+    }
+}
+
+#[test]
+fn compress_header_properties() {
+    let options = lzma_rs::compress::Options {
+        lc: 0,
+        lp: 2,
+        pb: 2,
+        dict_size: 0x1_0000,
+        ..Default::default()
+    };
+    let mut compressed: Vec<u8> = Vec::new();
+    lzma_rs::lzma_compress_with_options(&mut (b"" as &[u8]), &mut compressed, &options).unwrap();
+    assert_eq!(&compressed[..5], b"\x6c\x00\x00\x01\x00");
+}
+
+#[test]
+fn compress_invalid_options() {
+    let invalid = [
+        lzma_rs::compress::Options {
+            lc: 9,
+            ..Default::default()
+        },
+        lzma_rs::compress::Options {
+            lp: 5,
+            ..Default::default()
+        },
+        lzma_rs::compress::Options {
+            pb: 5,
+            ..Default::default()
+        },
+        lzma_rs::compress::Options {
+            dict_size: 0xFFF,
+            ..Default::default()
+        },
+        lzma_rs::compress::Options {
+            nice_len: 274,
+            ..Default::default()
+        },
+    ];
+    for options in &invalid {
+        let mut compressed: Vec<u8> = Vec::new();
+        let error =
+            lzma_rs::lzma_compress_with_options(&mut (b"" as &[u8]), &mut compressed, options)
+                .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(compressed.is_empty());
+    }
+}
+
+#[test]
+fn compress_big_file() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let x = read_all_file("tests/files/foo.txt").unwrap();
+    let mut compressed: Vec<u8> = Vec::new();
+    lzma_rs::lzma_compress(&mut std::io::BufReader::new(x.as_slice()), &mut compressed).unwrap();
+    assert!(
+        compressed.len() < x.len() / 2,
+        "compressed {} bytes to {} bytes",
+        x.len(),
+        compressed.len()
+    );
+}
+
 #[test]
 fn decompress_big_file() {
     #[cfg(feature = "enable_logging")]
@@ -238,6 +471,7 @@ fn unpacked_size_write_to_header() {
     let data = b"Some data";
     let encode_options = lzma_rs::compress::Options {
         unpacked_size: lzma_rs::compress::UnpackedSize::WriteToHeader(Some(data.len() as u64)),
+        ..Default::default()
     };
     let decode_options = lzma_rs::decompress::Options {
         unpacked_size: lzma_rs::decompress::UnpackedSize::ReadFromHeader,
@@ -251,6 +485,7 @@ fn unpacked_size_provided_outside() {
     let data = b"Some data";
     let encode_options = lzma_rs::compress::Options {
         unpacked_size: lzma_rs::compress::UnpackedSize::SkipWritingToHeader,
+        ..Default::default()
     };
     let decode_options = lzma_rs::decompress::Options {
         unpacked_size: lzma_rs::decompress::UnpackedSize::UseProvided(Some(data.len() as u64)),
@@ -264,6 +499,7 @@ fn unpacked_size_write_some_to_header_but_use_provided_on_read() {
     let data = b"Some data";
     let encode_options = lzma_rs::compress::Options {
         unpacked_size: lzma_rs::compress::UnpackedSize::WriteToHeader(Some(data.len() as u64)),
+        ..Default::default()
     };
     let decode_options = lzma_rs::decompress::Options {
         unpacked_size: lzma_rs::decompress::UnpackedSize::ReadHeaderButUseProvided(Some(
@@ -279,6 +515,7 @@ fn unpacked_size_write_none_to_header_and_use_provided_on_read() {
     let data = b"Some data";
     let encode_options = lzma_rs::compress::Options {
         unpacked_size: lzma_rs::compress::UnpackedSize::WriteToHeader(None),
+        ..Default::default()
     };
     let decode_options = lzma_rs::decompress::Options {
         unpacked_size: lzma_rs::decompress::UnpackedSize::ReadHeaderButUseProvided(Some(
@@ -294,6 +531,7 @@ fn unpacked_size_write_none_to_header_and_use_provided_none_on_read() {
     let data = b"Some data";
     let encode_options = lzma_rs::compress::Options {
         unpacked_size: lzma_rs::compress::UnpackedSize::WriteToHeader(None),
+        ..Default::default()
     };
     let decode_options = lzma_rs::decompress::Options {
         unpacked_size: lzma_rs::decompress::UnpackedSize::ReadHeaderButUseProvided(None),
@@ -307,6 +545,7 @@ fn memlimit() {
     let data = b"Some data";
     let encode_options = lzma_rs::compress::Options {
         unpacked_size: lzma_rs::compress::UnpackedSize::WriteToHeader(None),
+        ..Default::default()
     };
     let decode_options = lzma_rs::decompress::Options {
         unpacked_size: lzma_rs::decompress::UnpackedSize::ReadHeaderButUseProvided(None),
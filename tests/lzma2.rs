@@ -1,6 +1,8 @@
 #[cfg(feature = "enable_logging")]
 use log::{debug, info};
 use std::io::Read;
+#[cfg(feature = "stream")]
+use std::io::Write;
 
 /// Utility function to read a file into memory
 fn read_all_file(filename: &str) -> std::io::Result<Vec<u8>> {
@@ -19,7 +21,16 @@ fn round_trip(x: &[u8]) {
     let mut bf = std::io::BufReader::new(compressed.as_slice());
     let mut decomp: Vec<u8> = Vec::new();
     lzma_rs::lzma2_decompress(&mut bf, &mut decomp).unwrap();
-    assert_eq!(decomp, x)
+    assert_eq!(decomp, x);
+
+    #[cfg(feature = "stream")]
+    for &chunk_size in &[1, 7, 4096, usize::MAX] {
+        let mut stream = lzma_rs::decompress::Lzma2Stream::new(Vec::new());
+        for chunk in compressed.chunks(chunk_size) {
+            stream.write_all(chunk).unwrap();
+        }
+        assert_eq!(stream.finish().unwrap(), x);
+    }
 }
 
 fn round_trip_file(filename: &str) {
@@ -50,3 +61,160 @@ fn round_trip_files() {
     let _ = env_logger::try_init();
     round_trip_file("tests/files/foo.txt");
 }
+
+/// Generate data alternating between incompressible runs of pseudo-random
+/// bytes and runs of highly compressible text.
+fn generate_mixed_data(len: usize) -> Vec<u8> {
+    let mut seed: u32 = 0x1234_5678;
+    let mut data = Vec::with_capacity(len);
+    let text = read_all_file("tests/files/foo.txt").unwrap();
+    while data.len() < len {
+        for _ in 0..100_000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            data.push((seed >> 16) as u8);
+        }
+        data.extend_from_slice(&text);
+    }
+    data.truncate(len);
+    data
+}
+
+/// Kinds of the chunks of an LZMA2 stream, as (control byte, unpacked size,
+/// packed size).
+fn parse_chunks(mut compressed: &[u8]) -> Vec<(u8, usize, usize)> {
+    let mut chunks = Vec::new();
+    loop {
+        let control = compressed[0];
+        let (unpacked_size, packed_size, header_size) = match control {
+            0 => break,
+            1 | 2 => {
+                let size = u16::from_be_bytes([compressed[1], compressed[2]]) as usize + 1;
+                (size, size, 3)
+            }
+            0x80..=0xFF => {
+                let unpacked_size = (((control & 0x1F) as usize) << 16)
+                    + u16::from_be_bytes([compressed[1], compressed[2]]) as usize
+                    + 1;
+                let packed_size = u16::from_be_bytes([compressed[3], compressed[4]]) as usize + 1;
+                (
+                    unpacked_size,
+                    packed_size,
+                    if control >= 0xC0 { 6 } else { 5 },
+                )
+            }
+            _ => panic!("Invalid control byte {:#x}", control),
+        };
+        chunks.push((control & 0xE0, unpacked_size, packed_size));
+        compressed = &compressed[header_size + packed_size..];
+    }
+    assert_eq!(compressed, &[0]);
+    chunks
+}
+
+#[test]
+fn round_trip_chunks() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::Mode;
+    let data = generate_mixed_data(3 << 20);
+    for mode in [Mode::Fast, Mode::Normal] {
+        let options = lzma_rs::compress::Options {
+            mode,
+            dict_size: 0x1000,
+            ..lzma_rs::compress::Options::preset(0, false).unwrap()
+        };
+        let mut compressed = Vec::new();
+        lzma_rs::lzma2_compress_with_options(&mut data.as_slice(), &mut compressed, &options)
+            .unwrap();
+        let mut decomp = Vec::new();
+        lzma_rs::lzma2_decompress(&mut compressed.as_slice(), &mut decomp).unwrap();
+        assert_eq!(decomp, data);
+
+        // The first chunk is uncompressed, with a dictionary reset. The first
+        // LZMA chunk then sets the properties, and the following ones reset
+        // the state after an uncompressed chunk.
+        let chunks = parse_chunks(&compressed);
+        let mut props_set = false;
+        let mut prev = 0;
+        for &(control, unpacked_size, packed_size) in &chunks {
+            assert!(unpacked_size <= 1 << 21);
+            assert!(packed_size <= 1 << 16);
+            if control >= 0x80 {
+                let expected = if !props_set {
+                    0xC0
+                } else if prev < 0x80 {
+                    0xA0
+                } else {
+                    0x80
+                };
+                assert_eq!(control, expected, "{:?}", chunks);
+                props_set = true;
+            }
+            prev = control;
+        }
+        assert_eq!(chunks[0].0, 0);
+        assert!(chunks.iter().any(|c| c.0 == 0xA0));
+        assert!(chunks.iter().any(|c| c.0 == 0x80));
+    }
+}
+
+#[test]
+fn compress_ratio() {
+    let x = read_all_file("tests/files/foo.txt").unwrap();
+    let mut compressed = Vec::new();
+    lzma_rs::lzma2_compress(&mut x.as_slice(), &mut compressed).unwrap();
+    assert!(compressed.len() < x.len() / 3, "{}", compressed.len());
+    assert_eq!(parse_chunks(&compressed)[0].0, 0xE0);
+}
+
+#[test]
+fn compress_invalid_options() {
+    let options = lzma_rs::compress::Options {
+        lc: 4,
+        lp: 1,
+        ..Default::default()
+    };
+    let mut compressed = Vec::new();
+    let err = lzma_rs::lzma2_compress_with_options(&mut &b"abc"[..], &mut compressed, &options)
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn decompress_memlimit() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+
+    // Compressible data is encoded in LZMA chunks, the mixed data also in
+    // uncompressed chunks.
+    for x in [vec![0x00; 100_000], generate_mixed_data(100_000)] {
+        let mut compressed: Vec<u8> = Vec::new();
+        lzma_rs::lzma2_compress(&mut x.as_slice(), &mut compressed).unwrap();
+
+        let options = lzma_rs::decompress::Options {
+            memlimit: Some(1000),
+            ..Default::default()
+        };
+        let mut decomp: Vec<u8> = Vec::new();
+        let error = lzma_rs::lzma2_decompress_with_options(
+            &mut compressed.as_slice(),
+            &mut decomp,
+            &options,
+        )
+        .unwrap_err();
+        assert!(
+            matches!(error, lzma_rs::error::Error::MemLimitExceeded(1000)),
+            "{}",
+            error
+        );
+
+        let options = lzma_rs::decompress::Options {
+            memlimit: Some(0x100_0000),
+            ..Default::default()
+        };
+        let mut decomp: Vec<u8> = Vec::new();
+        lzma_rs::lzma2_decompress_with_options(&mut compressed.as_slice(), &mut decomp, &options)
+            .unwrap();
+        assert_eq!(decomp, x);
+    }
+}
@@ -1,6 +1,6 @@
 #[cfg(feature = "enable_logging")]
 use log::{debug, info};
-use std::io::{BufReader, Cursor, Read};
+use std::io::{BufReader, Cursor, Read, Write};
 
 /// Utility function to read a file into memory
 fn read_all_file(filename: &str) -> std::io::Result<Vec<u8>> {
@@ -51,12 +51,378 @@ fn round_trip_files() {
     round_trip_file("tests/files/foo.txt");
 }
 
+#[test]
+fn round_trip_presets() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let x = read_all_file("tests/files/foo.txt").unwrap();
+    for (level, dict_size_property) in [(0, 12), (6, 22), (9, 28)] {
+        let options = lzma_rs::compress::XzOptions {
+            lzma2: lzma_rs::compress::Options::preset(level, false).unwrap(),
+            ..Default::default()
+        };
+        let mut compressed: Vec<u8> = Vec::new();
+        lzma_rs::xz_compress_with_options(&mut x.as_slice(), &mut compressed, &options).unwrap();
+        // The LZMA2 filter properties in the block header encode the
+        // dictionary size.
+        assert_eq!(compressed[12 + 4], dict_size_property);
+        let mut decomp: Vec<u8> = Vec::new();
+        lzma_rs::xz_decompress(&mut compressed.as_slice(), &mut decomp).unwrap();
+        assert_eq!(decomp, x);
+    }
+}
+
+#[test]
+fn round_trip_liblzma() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let x = read_all_file("tests/files/foo.txt").unwrap();
+    let mut compressed: Vec<u8> = Vec::new();
+    lzma_rs::xz_compress(&mut x.as_slice(), &mut compressed).unwrap();
+    assert!(compressed.len() < x.len() / 3, "{}", compressed.len());
+    let decomp = lzma::decompress(&compressed).unwrap();
+    assert_eq!(decomp, x);
+}
+
+#[test]
+fn round_trip_encoder_writer_reader() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let x = read_all_file("tests/files/foo.txt").unwrap();
+
+    let mut encoder = lzma_rs::compress::XzEncoder::new(Vec::new()).unwrap();
+    std::io::copy(&mut x.as_slice(), &mut encoder).unwrap();
+    let compressed = encoder.finish().unwrap();
+    assert_eq!(lzma::decompress(&compressed).unwrap(), x);
+
+    let mut compressed = Vec::new();
+    lzma_rs::compress::XzEncoderReader::new(x.as_slice())
+        .unwrap()
+        .read_to_end(&mut compressed)
+        .unwrap();
+    assert_eq!(lzma::decompress(&compressed).unwrap(), x);
+}
+
+#[test]
+fn round_trip_checks() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::CheckMethod;
+    let x = read_all_file("tests/files/foo.txt").unwrap();
+    for (check, check_size) in [
+        (CheckMethod::None, 0),
+        (CheckMethod::Crc32, 4),
+        (CheckMethod::Crc64, 8),
+        (CheckMethod::Sha256, 32),
+    ] {
+        let options = lzma_rs::compress::XzOptions {
+            check,
+            ..Default::default()
+        };
+        let mut compressed: Vec<u8> = Vec::new();
+        lzma_rs::xz_compress_with_options(&mut x.as_slice(), &mut compressed, &options).unwrap();
+        // The Stream Flags of the header and of the footer.
+        assert_eq!(compressed[7], check as u8);
+        assert_eq!(compressed[compressed.len() - 3], check as u8);
+
+        // Both liblzma and lzma-rs verify the Block Check.
+        let decomp = lzma::decompress(&compressed).unwrap();
+        assert_eq!(decomp, x);
+        let mut decomp: Vec<u8> = Vec::new();
+        lzma_rs::xz_decompress(&mut compressed.as_slice(), &mut decomp).unwrap();
+        assert_eq!(decomp, x);
+
+        // Corrupting the Block Check, just before the Index, is detected.
+        if check_size > 0 {
+            // The Backward Size in the footer gives the size of the Index.
+            let n = compressed.len();
+            let backward_size = u32::from_le_bytes([
+                compressed[n - 8],
+                compressed[n - 7],
+                compressed[n - 6],
+                compressed[n - 5],
+            ]);
+            let index_size = 4 * (backward_size as usize + 1);
+            let check_end = compressed.len() - 12 - index_size;
+            compressed[check_end - 1] ^= 1;
+            assert!(lzma::decompress(&compressed).is_err());
+            let mut decomp: Vec<u8> = Vec::new();
+            lzma_rs::xz_decompress(&mut compressed.as_slice(), &mut decomp).unwrap_err();
+        }
+    }
+}
+
+#[test]
+fn round_trip_bcj_filters() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::{BcjOptions, Filter};
+    let x = read_all_file("tests/files/bcj.bin").unwrap();
+    let bcj = BcjOptions::default();
+    let start = BcjOptions {
+        start_offset: 0x1000,
+    };
+    for filters in [
+        vec![Filter::X86(bcj)],
+        vec![Filter::PowerPc(bcj)],
+        vec![Filter::Ia64(bcj)],
+        vec![Filter::Arm(bcj)],
+        vec![Filter::ArmThumb(bcj)],
+        vec![Filter::Sparc(bcj)],
+        vec![Filter::Arm64(bcj)],
+        vec![Filter::RiscV(bcj)],
+        vec![Filter::X86(start)],
+        vec![Filter::Arm64(start)],
+        vec![Filter::Arm64(bcj), Filter::X86(start)],
+    ] {
+        let options = lzma_rs::compress::XzOptions {
+            filters: filters.clone(),
+            ..Default::default()
+        };
+        let mut compressed: Vec<u8> = Vec::new();
+        lzma_rs::xz_compress_with_options(&mut x.as_slice(), &mut compressed, &options).unwrap();
+        // The Block Flags give the number of filters besides LZMA2.
+        assert_eq!(compressed[13] as usize, filters.len(), "{:?}", filters);
+
+        // The RISC-V filter needs liblzma 5.6.
+        if !matches!(filters[0], Filter::RiscV(_)) {
+            let decomp = lzma::decompress(&compressed).unwrap();
+            assert!(decomp == x, "{:?}", filters);
+        }
+        let mut decomp: Vec<u8> = Vec::new();
+        lzma_rs::xz_decompress(&mut compressed.as_slice(), &mut decomp).unwrap();
+        assert!(decomp == x, "{:?}", filters);
+    }
+}
+
+#[test]
+fn round_trip_delta_filter() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::{BcjOptions, DeltaOptions, Filter};
+    let x = read_all_file("tests/files/delta.bin").unwrap();
+    for filters in [
+        vec![Filter::Delta(DeltaOptions { dist: 1 })],
+        vec![Filter::Delta(DeltaOptions { dist: 2 })],
+        vec![Filter::Delta(DeltaOptions { dist: 256 })],
+        vec![
+            Filter::X86(BcjOptions::default()),
+            Filter::Delta(DeltaOptions { dist: 4 }),
+        ],
+    ] {
+        let options = lzma_rs::compress::XzOptions {
+            filters: filters.clone(),
+            ..Default::default()
+        };
+        let mut compressed: Vec<u8> = Vec::new();
+        lzma_rs::xz_compress_with_options(&mut x.as_slice(), &mut compressed, &options).unwrap();
+
+        let decomp = lzma::decompress(&compressed).unwrap();
+        assert!(decomp == x, "{:?}", filters);
+        let mut decomp: Vec<u8> = Vec::new();
+        lzma_rs::xz_decompress(&mut compressed.as_slice(), &mut decomp).unwrap();
+        assert!(decomp == x, "{:?}", filters);
+    }
+}
+
+/// Read the Index Records of a single-stream file, as pairs of unpadded and
+/// uncompressed sizes.
+fn index_records(compressed: &[u8]) -> Vec<(u64, u64)> {
+    fn read_multibyte(input: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for i in 0.. {
+            let byte = input[0];
+            *input = &input[1..];
+            value |= u64::from(byte & 0x7F) << (7 * i);
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        value
+    }
+
+    use std::convert::TryInto;
+
+    let footer = &compressed[compressed.len() - 12..];
+    let backward_size = u32::from_le_bytes(footer[4..8].try_into().unwrap());
+    let index_size = (backward_size as usize + 1) * 4;
+    let mut index = &compressed[compressed.len() - 12 - index_size..];
+    assert_eq!(index[0], 0);
+    index = &index[1..];
+    let num_records = read_multibyte(&mut index);
+    (0..num_records)
+        .map(|_| (read_multibyte(&mut index), read_multibyte(&mut index)))
+        .collect()
+}
+
+#[test]
+fn round_trip_blocks() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::{BcjOptions, Filter};
+    let x = read_all_file("tests/files/foo.txt").unwrap();
+    let len = x.len() as u64;
+    // The expected sizes of the first blocks.
+    for (block_size, block_list, filters, expected) in [
+        (Some(10000), vec![], vec![], vec![10000, 10000]),
+        (None, vec![1000, 12000], vec![], vec![1000, 12000, 12000]),
+        (None, vec![1000, 0], vec![], vec![1000, len - 1000]),
+        (None, vec![0], vec![], vec![len]),
+        (
+            Some(4000),
+            vec![10000, 1000],
+            vec![],
+            vec![4000, 4000, 2000, 1000, 1000],
+        ),
+        (
+            Some(len),
+            vec![],
+            vec![Filter::X86(BcjOptions::default())],
+            vec![len],
+        ),
+    ] {
+        let options = lzma_rs::compress::XzOptions {
+            block_size,
+            block_list: block_list.clone(),
+            filters: filters.clone(),
+            ..Default::default()
+        };
+        let mut compressed: Vec<u8> = Vec::new();
+        lzma_rs::xz_compress_with_options(&mut x.as_slice(), &mut compressed, &options).unwrap();
+        // The Block Flags record both sizes of a block of a bounded size,
+        // but not of a block extending to the end of the data.
+        let sizes_flags = if block_list == [0] { 0 } else { 0xC0 };
+        assert_eq!(compressed[13], sizes_flags | filters.len() as u8);
+        let sizes: Vec<u64> = index_records(&compressed)
+            .into_iter()
+            .map(|(_, unpacked_size)| unpacked_size)
+            .collect();
+        assert!(sizes.starts_with(&expected), "{:?}", sizes);
+        assert_eq!(sizes.iter().sum::<u64>(), len);
+
+        let decomp = lzma::decompress(&compressed).unwrap();
+        assert!(decomp == x, "{:?} {:?}", block_size, block_list);
+        let mut decomp: Vec<u8> = Vec::new();
+        lzma_rs::xz_decompress(&mut compressed.as_slice(), &mut decomp).unwrap();
+        assert!(decomp == x, "{:?} {:?}", block_size, block_list);
+    }
+}
+
+#[test]
+fn round_trip_blocks_empty() {
+    let options = lzma_rs::compress::XzOptions {
+        block_size: Some(1000),
+        ..Default::default()
+    };
+    let mut compressed: Vec<u8> = Vec::new();
+    lzma_rs::xz_compress_with_options(&mut &b""[..], &mut compressed, &options).unwrap();
+    // An empty stream has no block.
+    assert_eq!(index_records(&compressed), vec![]);
+    assert_eq!(lzma::decompress(&compressed).unwrap(), b"");
+    let mut decomp: Vec<u8> = Vec::new();
+    lzma_rs::xz_decompress(&mut compressed.as_slice(), &mut decomp).unwrap();
+    assert_eq!(decomp, b"");
+}
+
+#[test]
+fn round_trip_threads() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    use lzma_rs::compress::{BcjOptions, Filter};
+    let x = read_all_file("tests/files/foo.txt").unwrap();
+    for (block_size, block_list, filters) in [
+        (Some(10000), vec![], vec![]),
+        (None, vec![5000, 20000, 0], vec![]),
+        (
+            Some(30000),
+            vec![],
+            vec![Filter::X86(BcjOptions::default())],
+        ),
+        (None, vec![], vec![]),
+    ] {
+        let mut outputs: Vec<Vec<u8>> = Vec::new();
+        for threads in [1, 2, 3, 8, 0] {
+            let options = lzma_rs::compress::XzOptions {
+                block_size,
+                block_list: block_list.clone(),
+                filters: filters.clone(),
+                threads,
+                ..Default::default()
+            };
+            let mut compressed: Vec<u8> = Vec::new();
+            lzma_rs::xz_compress_with_options(&mut x.as_slice(), &mut compressed, &options)
+                .unwrap();
+            outputs.push(compressed);
+        }
+        // The output doesn't depend on the number of threads.
+        for compressed in &outputs[1..] {
+            assert!(compressed == &outputs[0], "{:?}", block_size);
+        }
+
+        let decomp = lzma::decompress(&outputs[0]).unwrap();
+        assert!(decomp == x, "{:?} {:?}", block_size, block_list);
+        let mut decomp: Vec<u8> = Vec::new();
+        lzma_rs::xz_decompress(&mut outputs[0].as_slice(), &mut decomp).unwrap();
+        assert!(decomp == x, "{:?} {:?}", block_size, block_list);
+    }
+}
+
+#[test]
+fn delta_encode_decode() {
+    let x = read_all_file("tests/files/delta.bin").unwrap();
+    let options = lzma_rs::compress::DeltaOptions { dist: 2 };
+    let mut buf = x.clone();
+    lzma_rs::delta_encode(&mut buf, &options).unwrap();
+    assert!(buf != x);
+
+    // The Delta filter on its own gives the same bytes as a writer.
+    let mut encoder = lzma_rs::compress::DeltaEncoder::new(&options, Vec::new()).unwrap();
+    encoder.write_all(&x).unwrap();
+    assert!(encoder.into_inner() == buf);
+
+    lzma_rs::delta_decode(&mut buf, &options).unwrap();
+    assert!(buf == x);
+
+    let options = lzma_rs::compress::DeltaOptions { dist: 0 };
+    let err = lzma_rs::delta_encode(&mut buf, &options).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn round_trip_default_check() {
+    let mut compressed: Vec<u8> = Vec::new();
+    lzma_rs::xz_compress(&mut &b"Hello world"[..], &mut compressed).unwrap();
+    assert_eq!(compressed[7], lzma_rs::compress::CheckMethod::Crc64 as u8);
+    let mut decomp: Vec<u8> = Vec::new();
+    lzma_rs::xz_decompress(&mut compressed.as_slice(), &mut decomp).unwrap();
+    assert_eq!(decomp, b"Hello world");
+}
+
 fn decomp_big_file(compfile: &str, plainfile: &str) {
     let expected = read_all_file(plainfile).unwrap();
     let mut f = BufReader::new(std::fs::File::open(compfile).unwrap());
     let mut decomp: Vec<u8> = Vec::new();
     lzma_rs::xz_decompress(&mut f, &mut decomp).unwrap();
-    assert!(decomp == expected)
+    assert!(decomp == expected);
+
+    #[cfg(feature = "stream")]
+    {
+        let compressed = read_all_file(compfile).unwrap();
+        const CHUNK_SIZES: &[usize] = &[1, 7, 64, 4096, usize::MAX];
+        for &chunk_size in CHUNK_SIZES {
+            let mut stream = lzma_rs::decompress::XzStream::new(Vec::new());
+            for chunk in compressed.chunks(chunk_size) {
+                stream.write_all(chunk).unwrap();
+            }
+            let decomp = stream.finish().unwrap();
+            assert!(decomp == expected);
+        }
+
+        let mut reader = lzma_rs::decompress::XzReader::new(compressed.as_slice());
+        let mut decomp = Vec::new();
+        std::io::copy(&mut reader, &mut decomp).unwrap();
+        assert!(decomp == expected);
+    }
 }
 
 #[test]
@@ -82,6 +448,33 @@ fn big_file() {
     );
 }
 
+#[test]
+fn decompress_bcj_filters() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    for arch in &[
+        "x86", "powerpc", "ia64", "arm", "armthumb", "sparc", "arm64", "riscv",
+    ] {
+        decomp_big_file(
+            &format!("tests/files/bcj-{}.bin.xz", arch),
+            "tests/files/bcj.bin",
+        );
+    }
+    // Start offset in the filter properties.
+    decomp_big_file("tests/files/bcj-x86-start.bin.xz", "tests/files/bcj.bin");
+    // The filter restarts with each block.
+    decomp_big_file("tests/files/bcj-x86-blocks.bin.xz", "tests/files/bcj.bin");
+    // Chain of two BCJ filters before LZMA2.
+    decomp_big_file("tests/files/bcj-arm64-x86.bin.xz", "tests/files/bcj.bin");
+}
+
+#[test]
+fn decompress_delta_filter() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    decomp_big_file("tests/files/delta-2.bin.xz", "tests/files/delta.bin");
+}
+
 #[test]
 fn decompress_empty_world() {
     #[cfg(feature = "enable_logging")]
@@ -108,6 +501,52 @@ fn decompress_hello_world() {
     assert_eq!(decomp, b"Hello world\x0a")
 }
 
+/// Writer recording the size of each write.
+#[derive(Default)]
+struct WriteSizes {
+    data: Vec<u8>,
+    sizes: Vec<usize>,
+}
+
+impl Write for WriteSizes {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.data.extend_from_slice(buf);
+        self.sizes.push(buf.len());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn decompress_streams_block_data() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+    let foo = read_all_file("tests/files/foo.txt").unwrap();
+    let x = foo.repeat(8);
+    // A single block of many LZMA2 chunks, with a 256 KiB dictionary.
+    let options = lzma_rs::compress::XzOptions {
+        lzma2: lzma_rs::compress::Options::preset(0, false).unwrap(),
+        ..Default::default()
+    };
+    let mut compressed: Vec<u8> = Vec::new();
+    lzma_rs::xz_compress_with_options(&mut x.as_slice(), &mut compressed, &options).unwrap();
+
+    let mut output = WriteSizes::default();
+    lzma_rs::xz_decompress(&mut compressed.as_slice(), &mut output).unwrap();
+    assert_eq!(output.data, x);
+    // The block data is written as the dictionary fills up, not as a whole
+    // once the block ends.
+    assert!(output.sizes.len() > 1, "{:?}", output.sizes);
+    assert!(
+        output.sizes.iter().all(|&size| size <= 256 << 10),
+        "{:?}",
+        output.sizes
+    );
+}
+
 #[test]
 fn test_xz_block_check_crc32() {
     #[cfg(feature = "enable_logging")]
@@ -144,3 +583,337 @@ fn test_xz_block_check_crc32_invalid() {
         "xz error: Invalid footer CRC32: expected 0x01234567 but got 0x8b0d303e"
     )
 }
+
+#[test]
+fn test_xz_block_check_sha256() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+
+    decomp_big_file(
+        "tests/files/block-check-sha256.txt.xz",
+        "tests/files/block-check-sha256.txt",
+    );
+    decomp_big_file("tests/files/foo-sha256.txt.xz", "tests/files/foo.txt");
+}
+
+#[test]
+fn test_xz_block_check_sha256_invalid() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+
+    let testcase = "tests/files/block-check-sha256.txt.xz";
+    let mut corrupted = {
+        let mut buf = read_all_file(testcase).unwrap();
+        // Mangle the "Block Check" field.
+        buf[0x48] = 0x67;
+        buf[0x49] = 0x45;
+        buf[0x4A] = 0x23;
+        buf[0x4B] = 0x01;
+        BufReader::new(Cursor::new(buf))
+    };
+    let mut decomp = Vec::new();
+
+    let err_msg = lzma_rs::xz_decompress(&mut corrupted, &mut decomp)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        err_msg,
+        "xz error: Invalid block SHA-256, \
+         expected 6745230136334fb180a561d5192849b881d254bced565c47a4fefdb0fb8ae466 \
+         but got 2615902f36334fb180a561d5192849b881d254bced565c47a4fefdb0fb8ae466"
+    )
+}
+
+fn xz_compress(x: &[u8]) -> Vec<u8> {
+    let mut compressed: Vec<u8> = Vec::new();
+    lzma_rs::xz_compress(&mut &x[..], &mut compressed).unwrap();
+    compressed
+}
+
+#[test]
+fn decompress_concatenated_streams() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+
+    let foo = read_all_file("tests/files/foo.txt").unwrap();
+    let mut input = xz_compress(b"Hello ");
+    input.extend_from_slice(&read_all_file("tests/files/foo.txt.xz").unwrap());
+    input.extend_from_slice(&xz_compress(b""));
+    input.extend_from_slice(&xz_compress(b"world"));
+
+    let mut expected = b"Hello ".to_vec();
+    expected.extend_from_slice(&foo);
+    expected.extend_from_slice(b"world");
+
+    let mut decomp: Vec<u8> = Vec::new();
+    lzma_rs::xz_decompress(&mut input.as_slice(), &mut decomp).unwrap();
+    assert_eq!(decomp, expected);
+
+    #[cfg(feature = "stream")]
+    {
+        let mut stream = lzma_rs::decompress::XzStream::new(Vec::new());
+        stream.write_all(&input).unwrap();
+        assert_eq!(stream.finish().unwrap(), expected);
+    }
+}
+
+#[test]
+fn decompress_stream_padding() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+
+    for (before, after) in [(0, 4), (4, 0), (8, 12), (1024, 4)] {
+        let mut input = xz_compress(b"Hello ");
+        input.resize(input.len() + before, 0);
+        input.extend_from_slice(&xz_compress(b"world"));
+        input.resize(input.len() + after, 0);
+
+        let mut decomp: Vec<u8> = Vec::new();
+        lzma_rs::xz_decompress(&mut input.as_slice(), &mut decomp).unwrap();
+        assert_eq!(decomp, b"Hello world");
+    }
+}
+
+#[test]
+fn decompress_invalid_stream_padding() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+
+    // Between two streams.
+    let mut input = xz_compress(b"Hello ");
+    input.extend_from_slice(&[0; 3]);
+    input.extend_from_slice(&xz_compress(b"world"));
+    let mut decomp: Vec<u8> = Vec::new();
+    let err_msg = lzma_rs::xz_decompress(&mut input.as_slice(), &mut decomp)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        err_msg,
+        "xz error: Invalid stream padding of 3 byte(s), must be a multiple of 4"
+    );
+
+    // At the end of the input.
+    let mut input = xz_compress(b"Hello");
+    input.extend_from_slice(&[0; 5]);
+    let mut decomp: Vec<u8> = Vec::new();
+    let err_msg = lzma_rs::xz_decompress(&mut input.as_slice(), &mut decomp)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        err_msg,
+        "xz error: Invalid stream padding of 5 byte(s), must be a multiple of 4"
+    );
+
+    // Garbage after a stream.
+    let mut input = xz_compress(b"Hello");
+    input.extend_from_slice(b"garbage");
+    let mut decomp: Vec<u8> = Vec::new();
+    lzma_rs::xz_decompress(&mut input.as_slice(), &mut decomp).unwrap_err();
+}
+
+#[test]
+fn decompress_single_stream() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+
+    let mut input = xz_compress(b"Hello world");
+    let stream_size = input.len();
+    input.extend_from_slice(b"trailing data");
+
+    let options = lzma_rs::decompress::Options {
+        single_stream: true,
+        ..Default::default()
+    };
+    let mut reader = input.as_slice();
+    let mut decomp: Vec<u8> = Vec::new();
+    lzma_rs::xz_decompress_with_options(&mut reader, &mut decomp, &options).unwrap();
+    assert_eq!(decomp, b"Hello world");
+    assert_eq!(reader, &input[stream_size..]);
+}
+
+#[test]
+fn decompress_memlimit() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+
+    let x = read_all_file("tests/files/foo.txt").unwrap();
+    let compressed = xz_compress(&x);
+
+    let options = lzma_rs::decompress::Options {
+        memlimit: Some(1000),
+        ..Default::default()
+    };
+    let mut decomp: Vec<u8> = Vec::new();
+    let error =
+        lzma_rs::xz_decompress_with_options(&mut compressed.as_slice(), &mut decomp, &options)
+            .unwrap_err();
+    assert!(
+        matches!(error, lzma_rs::error::Error::MemLimitExceeded(1000)),
+        "{}",
+        error
+    );
+
+    let options = lzma_rs::decompress::Options {
+        memlimit: Some(0x100_0000),
+        ..Default::default()
+    };
+    let mut decomp: Vec<u8> = Vec::new();
+    lzma_rs::xz_decompress_with_options(&mut compressed.as_slice(), &mut decomp, &options).unwrap();
+    assert_eq!(decomp, x);
+}
+
+#[test]
+fn decompress_memlimit_dict_size() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+
+    // Much more data than the dictionary, which is all the decoder needs to
+    // keep in memory.
+    let foo = read_all_file("tests/files/foo.txt").unwrap();
+    let x: Vec<u8> = foo.iter().cycle().take(4 * foo.len()).cloned().collect();
+    let options = lzma_rs::compress::XzOptions {
+        lzma2: lzma_rs::compress::Options {
+            dict_size: 0x1000,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut compressed: Vec<u8> = Vec::new();
+    lzma_rs::xz_compress_with_options(&mut x.as_slice(), &mut compressed, &options).unwrap();
+
+    let options = lzma_rs::decompress::Options {
+        memlimit: Some(0x1000),
+        ..Default::default()
+    };
+    let mut decomp: Vec<u8> = Vec::new();
+    lzma_rs::xz_decompress_with_options(&mut compressed.as_slice(), &mut decomp, &options).unwrap();
+    assert_eq!(decomp, x);
+}
+
+fn xz_compress_blocks(x: &[u8], block_size: u64) -> Vec<u8> {
+    let options = lzma_rs::compress::XzOptions {
+        block_size: Some(block_size),
+        ..Default::default()
+    };
+    let mut compressed: Vec<u8> = Vec::new();
+    lzma_rs::xz_compress_with_options(&mut &x[..], &mut compressed, &options).unwrap();
+    compressed
+}
+
+fn xz_decompress_parallel(input: &[u8], threads: u32) -> lzma_rs::error::Result<Vec<u8>> {
+    let options = lzma_rs::decompress::Options {
+        threads,
+        ..Default::default()
+    };
+    let mut decomp: Vec<u8> = Vec::new();
+    lzma_rs::xz_decompress_parallel(&mut Cursor::new(input), &mut decomp, &options)?;
+    Ok(decomp)
+}
+
+#[test]
+fn decompress_parallel() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+
+    let x = read_all_file("tests/files/foo.txt").unwrap();
+    for compressed in [xz_compress_blocks(&x, 10000), xz_compress(&x)] {
+        for threads in [1, 3, 0] {
+            let decomp = xz_decompress_parallel(&compressed, threads).unwrap();
+            assert!(decomp == x, "{}", threads);
+        }
+    }
+
+    // Blocks written by xz, with a BCJ filter.
+    let compressed = read_all_file("tests/files/bcj-x86-blocks.bin.xz").unwrap();
+    let expected = read_all_file("tests/files/bcj.bin").unwrap();
+    assert!(xz_decompress_parallel(&compressed, 4).unwrap() == expected);
+}
+
+#[test]
+fn decompress_parallel_streams() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+
+    let x = read_all_file("tests/files/foo.txt").unwrap();
+    let mut input = xz_compress_blocks(&x[..50000], 7000);
+    input.extend_from_slice(&[0; 8]);
+    input.extend_from_slice(&xz_compress(b""));
+    input.extend_from_slice(&xz_compress_blocks(&x[50000..], 30000));
+    input.extend_from_slice(&[0; 4]);
+
+    let decomp = xz_decompress_parallel(&input, 4).unwrap();
+    assert!(decomp == x);
+
+    // The Indexes locate the streams from the end of the input.
+    input.extend_from_slice(b"garbage");
+    xz_decompress_parallel(&input, 4).unwrap_err();
+}
+
+#[test]
+fn decompress_parallel_invalid() {
+    #[cfg(feature = "enable_logging")]
+    let _ = env_logger::try_init();
+
+    let x = read_all_file("tests/files/foo.txt").unwrap();
+    let compressed = xz_compress_blocks(&x, 10000);
+
+    // Corrupt the data of a block in the middle.
+    let mut input = compressed.clone();
+    let pos = input.len() / 2;
+    input[pos] ^= 0x01;
+    xz_decompress_parallel(&input, 4).unwrap_err();
+
+    // Corrupt the Index CRC32, just before the Stream Footer.
+    let mut input = compressed;
+    let pos = input.len() - 13;
+    input[pos] ^= 0x01;
+    let error = xz_decompress_parallel(&input, 4).unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .starts_with("xz error: Invalid index CRC32"),
+        "{}",
+        error
+    );
+}
+
+/// Input whose seeking always fails, like a pipe.
+struct Unseekable<'a>(&'a [u8]);
+
+impl<'a> Read for Unseekable<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<'a> std::io::BufRead for Unseekable<'a> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        Ok(self.0)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.0 = &self.0[amt..];
+    }
+}
+
+impl<'a> std::io::Seek for Unseekable<'a> {
+    fn seek(&mut self, _: std::io::SeekFrom) -> std::io::Result<u64> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "not seekable",
+        ))
+    }
+}
+
+#[test]
+fn decompress_parallel_unseekable() {
+    let x = read_all_file("tests/files/foo.txt").unwrap();
+    let compressed = xz_compress_blocks(&x, 10000);
+    let options = lzma_rs::decompress::Options {
+        threads: 4,
+        ..Default::default()
+    };
+    let mut decomp: Vec<u8> = Vec::new();
+    lzma_rs::xz_decompress_parallel(&mut Unseekable(&compressed), &mut decomp, &options).unwrap();
+    assert!(decomp == x);
+}